@@ -0,0 +1,65 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use epicinium_server as epicinium;
+
+use docopt::Docopt;
+
+use serde::Deserialize;
+
+const USAGE: &str = "
+Usage: replay [options] --port=PORT <capture>...
+
+Replays captured client traffic against a server running in dev mode.
+
+Options:
+	--server=IPADDRESS   The IP address to connect to. [default: 127.0.0.1]
+	--port=PORT          The port to connect to.
+	--instant            Send messages as fast as possible instead of
+	                     with the timing in which they were captured.
+";
+
+#[derive(Deserialize)]
+struct Args
+{
+	arg_capture: Vec<String>,
+
+	flag_server: String,
+	flag_port: u16,
+	flag_instant: bool,
+}
+
+fn main() -> std::result::Result<(), anyhow::Error>
+{
+	let args: Args = Docopt::new(USAGE)
+		.unwrap()
+		.deserialize()
+		.unwrap_or_else(|error| error.exit());
+
+	epicinium::replay::run(
+		args.arg_capture,
+		args.flag_server,
+		args.flag_port,
+		args.flag_instant,
+	)
+}
@@ -21,6 +21,7 @@
  * Sander in 't Veld (sander@abunchofhacks.coop)
  */
 
+use epicinium::OneOrMany;
 use epicinium::Settings;
 use epicinium::Version;
 use epicinium::{run_server, setup_server};
@@ -38,8 +39,62 @@ Usage: server [options]
 Options:
 	--logname=NAME               The name used in the filenames of logs.
 	--loglevel=LEVEL             The level to filter on when writing logs.
-	--server=IPADDRESS           The IP address to bind to.
+	--logformat=FORMAT           Either 'text' or 'json' for JSON lines.
+	--log-rotate-size=BYTES      The size at which log files are rotated, or 0
+	                             to not rotate them by size.
+	--log-rotate-age=SECS        The age at which log files are rotated, or 0
+	                             to not rotate them by age.
+	--log-retention=N            The number of rotated log files to keep.
+	--log-compress=BOOL          Whether to compress rotated log files.
+	--server=IPADDRESSES         The IP addresses to bind to, separated by
+	                             commas. IPv6 addresses such as '::' also
+	                             accept IPv4 connections, unless IPv4
+	                             addresses are listed as well.
 	--port=PORT                  The port to bind to.
+	--websocket-port=PORT        An additional port to bind to that accepts
+	                             WebSocket connections.
+	--tls-certificate=FILENAME   The location of the PEM certificate chain used
+	                             to encrypt client connections with TLS.
+	--tls-private-key=FILENAME   The location of the matching PEM private key.
+	--proxy-protocol=BOOL        Whether connections start with a PROXY
+	                             protocol header, sent by a load balancer.
+	--unix-socket=FILENAME       An additional Unix domain socket to listen
+	                             on, for bots and tools on the same host.
+	--admin-socket=FILENAME      A Unix domain socket, only accessible to the
+	                             user running the server, that accepts
+	                             commands from operators.
+	--metrics-port=PORT          A port on the loopback interface on which to
+	                             serve metrics for Prometheus.
+	--max-connections=N          The most client connections open at once.
+	--max-connections-per-ip=N   The most client connections open at once
+	                             from a single IP address, or a single /64
+	                             subnet for IPv6.
+	--max-connection-rate-per-ip=N
+	                             The most client connections a single IP
+	                             address may open per minute.
+	--ban-list=FILENAME          Where to keep the bans issued through the
+	                             admin socket.
+	--allow-capture=BOOL         Whether to capture the traffic of clients that
+	                             ask for it, for debugging purposes.
+	--send-queue-capacity=N      The number of queued messages above which
+	                             general chat is no longer queued for a
+	                             client at all.
+	--send-queue-threshold=N     The number of queued messages above which
+	                             chat and lobby listings are dropped and a
+	                             client is considered to be stuck.
+	--slow-client-timeout=SECS   How long a client may stay stuck before it
+	                             is disconnected.
+	--resume-grace-period=SECS   How long a client that lost its connection
+	                             may take to resume its session, or 0 to
+	                             disable resuming.
+	--drain-deadline=SECS        How long to wait for clients to leave when
+	                             shutting down before unfinished games are
+	                             ended and the remaining clients are
+	                             disconnected.
+	--game-manifest=FILENAME     Where to record the games that were still
+	                             running when the drain deadline passed and
+	                             the lobbies that were still open, so that
+	                             they are restored after a restart.
 	--login-server=URL           The login server to connect to.
 	--allow-discord-login=BOOL   Whether to allow clients to log in using only
 	                             their Discord username as credentials.
@@ -47,19 +102,55 @@ Options:
 	--slackurl=URL               The Slack callback url to post to.
 	--slackname=NAME             The name with which to post to Slack.
 	--discordurl=URL             The Discord callback url to post to.
-	--settings=FILENAME          Filename to load additional settings from.
+	--settings=FILENAME          The TOML or JSON file to load settings from.
+	--check-config               Print the effective settings and exit.
+
+Settings are taken from the settings file, then from environment variables
+such as EPICINIUM_SLOW_CLIENT_TIMEOUT and then from the options above. If no
+settings file is given, EPICINIUM_SETTINGS, settings-server.toml and
+settings-server.json are tried in turn.
+
+Sending SIGTERM closes the server gracefully. Sending SIGUSR2 starts a new
+server process with the same options that takes over the listening sockets,
+after which this process closes gracefully. The drain deadline does not apply
+after such a handoff: games that are still running are played to the end
+rather than recorded in the game manifest.
 ";
 
 #[derive(Deserialize)]
 struct Args
 {
 	flag_settings: Option<String>,
+	flag_check_config: bool,
 
 	flag_logname: Option<String>,
 	flag_loglevel: Option<epicinium::common::log::Level>,
+	flag_logformat: Option<epicinium::common::log::Format>,
+	flag_log_rotate_size: Option<u64>,
+	flag_log_rotate_age: Option<u64>,
+	flag_log_retention: Option<usize>,
+	flag_log_compress: Option<bool>,
 
 	flag_server: Option<String>,
 	flag_port: Option<u16>,
+	flag_websocket_port: Option<u16>,
+	flag_tls_certificate: Option<String>,
+	flag_tls_private_key: Option<String>,
+	flag_proxy_protocol: Option<bool>,
+	flag_unix_socket: Option<String>,
+	flag_admin_socket: Option<String>,
+	flag_metrics_port: Option<u16>,
+	flag_max_connections: Option<usize>,
+	flag_max_connections_per_ip: Option<usize>,
+	flag_max_connection_rate_per_ip: Option<usize>,
+	flag_ban_list: Option<String>,
+	flag_allow_capture: Option<bool>,
+	flag_send_queue_capacity: Option<usize>,
+	flag_send_queue_threshold: Option<usize>,
+	flag_slow_client_timeout: Option<u64>,
+	flag_resume_grace_period: Option<u64>,
+	flag_drain_deadline: Option<u64>,
+	flag_game_manifest: Option<String>,
 
 	flag_login_server: Option<String>,
 	flag_allow_discord_login: Option<bool>,
@@ -78,29 +169,26 @@ fn main() -> std::result::Result<(), anyhow::Error>
 		.deserialize()
 		.unwrap_or_else(|error| error.exit());
 
-	let settings_filename = args
-		.flag_settings
-		.as_deref()
-		.unwrap_or("settings-server.json");
-	let mut settings = Settings::load(settings_filename)?;
-
-	settings.logname = args.flag_logname.or(settings.logname);
-	settings.loglevel = args.flag_loglevel.or(settings.loglevel);
-	settings.server = args.flag_server.or(settings.server);
-	settings.port = args.flag_port.or(settings.port);
-	settings.login_server = args.flag_login_server.or(settings.login_server);
-	settings.allow_discord_login = args
-		.flag_allow_discord_login
-		.or(settings.allow_discord_login);
-	settings.steam_web_key = args.flag_steam_web_key.or(settings.steam_web_key);
-	settings.slackurl = args.flag_slackurl.or(settings.slackurl);
-	settings.slackname = args.flag_slackname.or(settings.slackname);
-	settings.discordurl = args.flag_discordurl.or(settings.discordurl);
+	let settings = load_settings(&args)?;
+
+	if args.flag_check_config
+	{
+		println!("{}", settings.describe()?);
+		return Ok(());
+	}
+
+	let load_settings: epicinium::settings::Loader =
+		Box::new(move || load_settings(&args));
 
 	let logname = settings.logname.as_deref().unwrap_or("rust");
 	let loglevel = settings.loglevel.unwrap_or(epicinium::log::Level::Verbose);
-	epicinium::log::start(logname, loglevel)?;
-	let log_setup = epicinium::logrotate::setup(logname)?;
+	let logformat = settings.logformat.unwrap_or(epicinium::log::Format::Text);
+	let log_files = epicinium::log::start(logname, loglevel, logformat)?;
+	let log_setup = epicinium::logrotate::setup(
+		logname,
+		log_files,
+		settings.log_rotation(),
+	);
 
 	let currentversion = Version::current();
 
@@ -109,7 +197,7 @@ fn main() -> std::result::Result<(), anyhow::Error>
 
 	info!("Server started.");
 
-	let server = match setup_server(&settings, log_setup)
+	let server = match setup_server(&settings, load_settings, log_setup)
 	{
 		Ok(server) => server,
 		Err(error) =>
@@ -129,3 +217,54 @@ fn main() -> std::result::Result<(), anyhow::Error>
 	println!("[ Done ]");
 	Ok(())
 }
+
+fn load_settings(args: &Args) -> std::result::Result<Settings, anyhow::Error>
+{
+	let filename = args
+		.flag_settings
+		.clone()
+		.or_else(|| std::env::var(epicinium::ENV_SETTINGS_FILENAME).ok());
+
+	let overrides = Settings {
+		logname: args.flag_logname.clone(),
+		loglevel: args.flag_loglevel,
+		logformat: args.flag_logformat,
+		log_rotate_size: args.flag_log_rotate_size,
+		log_rotate_age: args.flag_log_rotate_age,
+		log_retention: args.flag_log_retention,
+		log_compress: args.flag_log_compress,
+		server: args
+			.flag_server
+			.as_ref()
+			.map(|x| x.split(',').map(|a| a.trim().to_string()).collect())
+			.map(OneOrMany::Many),
+		port: args.flag_port,
+		websocket_port: args.flag_websocket_port,
+		tls_certificate: args.flag_tls_certificate.clone(),
+		tls_private_key: args.flag_tls_private_key.clone(),
+		proxy_protocol: args.flag_proxy_protocol,
+		unix_socket: args.flag_unix_socket.clone(),
+		admin_socket: args.flag_admin_socket.clone(),
+		metrics_port: args.flag_metrics_port,
+		max_connections: args.flag_max_connections,
+		max_connections_per_ip: args.flag_max_connections_per_ip,
+		max_connection_rate_per_ip: args.flag_max_connection_rate_per_ip,
+		ban_list: args.flag_ban_list.clone(),
+		allow_capture: args.flag_allow_capture,
+		send_queue_capacity: args.flag_send_queue_capacity,
+		send_queue_threshold: args.flag_send_queue_threshold,
+		slow_client_timeout: args.flag_slow_client_timeout,
+		resume_grace_period: args.flag_resume_grace_period,
+		drain_deadline: args.flag_drain_deadline,
+		game_manifest: args.flag_game_manifest.clone(),
+		login_server: args.flag_login_server.clone(),
+		allow_discord_login: args.flag_allow_discord_login,
+		steam_web_key: args.flag_steam_web_key.clone(),
+		slackname: args.flag_slackname.clone(),
+		slackurl: args.flag_slackurl.clone(),
+		discordurl: args.flag_discordurl.clone(),
+		..Settings::default()
+	};
+
+	Settings::load_layered(filename.as_deref(), &overrides)
+}
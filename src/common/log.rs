@@ -21,9 +21,15 @@
  * Sander in 't Veld (sander@abunchofhacks.coop)
  */
 
+use crate::common::keycode::Keycode;
+
+use std::cell::Cell;
+use std::future::Future;
+
 use epicinium_lib;
 
 use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
 
 pub fn trace_filename(logname: &str) -> String
 {
@@ -40,31 +46,53 @@ pub fn error_filename(logname: &str) -> String
 	format!("logs/{}.error.log", logname)
 }
 
-pub fn start(logname: &str, level: Level) -> Result<(), fern::InitError>
+// A log file that can be told to reopen, for instance after it was rotated.
+pub struct LogFile
 {
-	let tracelogfilename: std::path::PathBuf = trace_filename(logname).into();
-	let infologfilename: std::path::PathBuf = info_filename(logname).into();
-	let errorlogfilename: std::path::PathBuf = error_filename(logname).into();
-	let sighup = Some(libc::SIGHUP);
+	pub filename: String,
+	pub reopen: reopen::Handle,
+}
 
-	let levelfilter = match level
-	{
-		Level::Error => log::LevelFilter::Error,
-		Level::Warn => log::LevelFilter::Warn,
-		Level::Info => log::LevelFilter::Info,
-		Level::Debug => log::LevelFilter::Debug,
-		Level::Verbose => log::LevelFilter::Trace,
-	};
+pub fn start(
+	logname: &str,
+	level: Level,
+	format: Format,
+) -> Result<Vec<LogFile>, fern::InitError>
+{
+	let tracelogfilename = trace_filename(logname);
+	let infologfilename = info_filename(logname);
+	let errorlogfilename = error_filename(logname);
+	let sighup = Some(libc::SIGHUP);
+	let tracelog = fern::log_reopen(tracelogfilename.as_ref(), sighup)?;
+	let infolog = fern::log_reopen(infologfilename.as_ref(), sighup)?;
+	let errorlog = fern::log_reopen(errorlogfilename.as_ref(), sighup)?;
+	let files = vec![
+		LogFile {
+			filename: tracelogfilename,
+			reopen: tracelog.handle(),
+		},
+		LogFile {
+			filename: infologfilename,
+			reopen: infolog.handle(),
+		},
+		LogFile {
+			filename: errorlogfilename,
+			reopen: errorlog.handle(),
+		},
+	];
 
+	// The level itself is enforced by the maximum level set below, so that it
+	// can be changed while the server is running.
 	fern::Dispatch::new()
-		.level(levelfilter)
+		.level(log::LevelFilter::Trace)
 		.filter(|metadata| {
 			// Smaller is more severe.
 			metadata.level() <= log::LevelFilter::Info
 				|| !matches_blacklist(metadata.target())
 		})
-		.format(|out, message, record| {
-			out.finish(format_args!(
+		.format(move |out, message, record| match format
+		{
+			Format::Text => out.finish(format_args!(
 				"{time} {lvl:5} [{tid:x}] [{target}.rs:{ln}] {msg}",
 				time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S.%3f"),
 				lvl = record.level(),
@@ -72,21 +100,27 @@ pub fn start(logname: &str, level: Level) -> Result<(), fern::InitError>
 				target = record.target(),
 				ln = record.line().unwrap_or(0),
 				msg = message
-			))
+			)),
+			Format::Json =>
+			{
+				out.finish(format_args!("{}", format_json(message, record)))
+			}
 		})
-		.chain(fern::log_reopen(&tracelogfilename, sighup)?)
+		.chain(tracelog)
 		.chain(
 			fern::Dispatch::new()
 				.level(log::LevelFilter::Debug)
-				.chain(fern::log_reopen(&infologfilename, sighup)?),
+				.chain(infolog),
 		)
 		.chain(
 			fern::Dispatch::new()
 				.level(log::LevelFilter::Warn)
-				.chain(fern::log_reopen(&errorlogfilename, sighup)?),
+				.chain(errorlog),
 		)
 		.apply()?;
 
+	set_level(level);
+
 	let severity = match level
 	{
 		Level::Error => epicinium_lib::log::Severity::Error,
@@ -97,7 +131,104 @@ pub fn start(logname: &str, level: Level) -> Result<(), fern::InitError>
 	};
 	epicinium_lib::log_initialize(severity);
 
-	Ok(())
+	Ok(files)
+}
+
+// The game library keeps the severity it was initialized with.
+pub fn set_level(level: Level)
+{
+	let levelfilter = match level
+	{
+		Level::Error => log::LevelFilter::Error,
+		Level::Warn => log::LevelFilter::Warn,
+		Level::Info => log::LevelFilter::Info,
+		Level::Debug => log::LevelFilter::Debug,
+		Level::Verbose => log::LevelFilter::Trace,
+	};
+	log::set_max_level(levelfilter);
+}
+
+fn format_json(
+	message: &std::fmt::Arguments,
+	record: &log::Record,
+) -> serde_json::Value
+{
+	let mut entry = json!({
+		"time": chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z")
+			.to_string(),
+		"level": record.level().to_string(),
+		"target": record.target(),
+		"line": record.line(),
+		"thread": format!("{:x}", thread_id::get()),
+		"message": message.to_string(),
+	});
+	let _outside_of_task = CONTEXT.try_with(|context| {
+		if let Some(client_id) = context.client_id.get()
+		{
+			entry["client_id"] = json!(client_id.to_string());
+		}
+		if let Some(lobby_id) = context.lobby_id.get()
+		{
+			entry["lobby_id"] = json!(lobby_id.to_string());
+		}
+		if let Some(round) = context.round.get()
+		{
+			entry["round"] = json!(round);
+		}
+		if let Some(user_id) = context.user_id.get()
+		{
+			entry["user_id"] = json!(user_id);
+		}
+	});
+	entry
+}
+
+// What a task is working on, to be included in structured log entries.
+#[derive(Debug, Default)]
+pub struct Context
+{
+	client_id: Cell<Option<Keycode>>,
+	lobby_id: Cell<Option<Keycode>>,
+	round: Cell<Option<u32>>,
+	user_id: Cell<Option<u64>>,
+}
+
+tokio::task_local! {
+	static CONTEXT: Context;
+}
+
+pub async fn for_client<F: Future>(client_id: Keycode, task: F) -> F::Output
+{
+	let context = Context {
+		client_id: Cell::new(Some(client_id)),
+		..Context::default()
+	};
+	CONTEXT.scope(context, task).await
+}
+
+pub async fn for_lobby<F: Future>(lobby_id: Keycode, task: F) -> F::Output
+{
+	let context = Context {
+		lobby_id: Cell::new(Some(lobby_id)),
+		..Context::default()
+	};
+	CONTEXT.scope(context, task).await
+}
+
+// These do nothing outside of a task started with for_client or for_lobby.
+pub fn set_lobby_id(lobby_id: Option<Keycode>)
+{
+	let _outside_of_task = CONTEXT.try_with(|x| x.lobby_id.set(lobby_id));
+}
+
+pub fn set_round(round: Option<u32>)
+{
+	let _outside_of_task = CONTEXT.try_with(|x| x.round.set(round));
+}
+
+pub fn set_user_id(user_id: Option<u64>)
+{
+	let _outside_of_task = CONTEXT.try_with(|x| x.user_id.set(user_id));
 }
 
 fn matches_blacklist(target: &str) -> bool
@@ -117,3 +248,11 @@ pub enum Level
 	Debug,
 	Verbose,
 }
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format
+{
+	Text,
+	Json,
+}
@@ -23,71 +23,195 @@
 
 use crate::common::log;
 
-use std::io::Write;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-use unindent::Unindent;
+#[derive(Debug, Clone, Copy)]
+pub struct Policy
+{
+	pub max_size: Option<u64>,
+	pub max_age: Option<Duration>,
+	pub retention: usize,
+	pub compress: bool,
+}
+
+impl Default for Policy
+{
+	fn default() -> Policy
+	{
+		Policy {
+			max_size: Some(1024 * 1024),
+			max_age: None,
+			retention: 500,
+			compress: true,
+		}
+	}
+}
 
 pub struct Setup
 {
-	pub conffilename: String,
-	pub statusfilename: String,
+	policy: Policy,
+	files: Vec<RotatingFile>,
+}
+
+struct RotatingFile
+{
+	file: log::LogFile,
+	rotated_at: Instant,
+}
+
+pub fn setup(logname: &str, files: Vec<log::LogFile>, policy: Policy) -> Setup
+{
+	// Older versions ran the system logrotate, which needed these files.
+	for filename in &[
+		format!("logs/.{}.logrotate.conf", logname),
+		format!("logs/.{}.logrotate.status", logname),
+	]
+	{
+		let _missing = std::fs::remove_file(filename);
+	}
+
+	let now = Instant::now();
+	let files = files
+		.into_iter()
+		.map(|file| RotatingFile {
+			file,
+			rotated_at: now,
+		})
+		.collect();
+	Setup { policy, files }
 }
 
-impl Drop for Setup
+impl Setup
 {
-	fn drop(&mut self)
+	pub fn rotate_if_due(&mut self) -> Result<(), io::Error>
 	{
-		match std::fs::remove_file(&self.conffilename)
+		for file in self.files.iter_mut()
 		{
-			Ok(()) =>
-			{}
-			Err(error) =>
+			if is_due(file, &self.policy)?
 			{
-				eprintln!(
-					"Failed to remove '{}': {}",
-					self.conffilename, error
-				);
+				rotate(&file.file, &self.policy)?;
+				file.rotated_at = Instant::now();
 			}
 		}
+		Ok(())
 	}
 }
 
-pub fn setup(logname: &str) -> Result<Setup, std::io::Error>
+fn is_due(file: &RotatingFile, policy: &Policy) -> Result<bool, io::Error>
 {
-	let conffilename = format!("logs/.{}.logrotate.conf", logname);
-	let mut file = std::fs::OpenOptions::new()
-		.write(true)
-		.create_new(true)
-		.open(&conffilename)?;
-
-	let conf = format!(
-		"
-		\"{trace}\" \"{info}\" \"{error}\" {{
-			rotate 500
-			size 1M
-			extension .log
-			compress
-			delaycompress
-			sharedscripts
-			missingok
-			postrotate
-				kill -HUP {pid}
-			endscript
-		}}",
-		trace = log::trace_filename(logname),
-		info = log::info_filename(logname),
-		error = log::error_filename(logname),
-		pid = std::process::id(),
-	)
-	.unindent();
-
-	file.write_all(conf.as_bytes())?;
-
-	let statusfilename = format!("logs/.{}.logrotate.status", logname);
-
-	let setup = Setup {
-		conffilename,
-		statusfilename,
+	let size = match std::fs::metadata(&file.file.filename)
+	{
+		Ok(metadata) => metadata.len(),
+		Err(error) if error.kind() == io::ErrorKind::NotFound => 0,
+		Err(error) => return Err(error),
 	};
-	Ok(setup)
+	if size == 0
+	{
+		return Ok(false);
+	}
+
+	let is_too_large = policy.max_size.is_some_and(|max| size >= max);
+	let is_too_old = policy
+		.max_age
+		.is_some_and(|max| file.rotated_at.elapsed() >= max);
+	Ok(is_too_large || is_too_old)
+}
+
+// The most recently rotated file is named "x.1.log", the one before that
+// "x.2.log" and so on. Like the file itself, the most recently rotated file is
+// not compressed, because it may still be written to until it is reopened.
+fn rotate(file: &log::LogFile, policy: &Policy) -> Result<(), io::Error>
+{
+	let filename = &file.filename;
+	for n in (1..=policy.retention).rev()
+	{
+		for &compressed in &[false, true]
+		{
+			let from = rotated_filename(filename, n, compressed);
+			if n == policy.retention
+			{
+				ignore_missing(std::fs::remove_file(&from))?;
+			}
+			else
+			{
+				let to = rotated_filename(filename, n + 1, compressed);
+				ignore_missing(std::fs::rename(&from, &to))?;
+			}
+		}
+	}
+
+	if policy.retention > 0
+	{
+		std::fs::rename(filename, rotated_filename(filename, 1, false))?;
+	}
+	else
+	{
+		std::fs::remove_file(filename)?;
+	}
+	file.reopen.reopen();
+
+	if policy.compress && policy.retention > 1
+	{
+		let previous = rotated_filename(filename, 2, false);
+		if Path::new(&previous).exists()
+		{
+			compress(&previous, &rotated_filename(filename, 2, true))?;
+		}
+	}
+	Ok(())
+}
+
+fn rotated_filename(filename: &str, n: usize, compressed: bool) -> String
+{
+	let stem = filename.strip_suffix(".log").unwrap_or(filename);
+	if compressed
+	{
+		format!("{}.{}.log.gz", stem, n)
+	}
+	else
+	{
+		format!("{}.{}.log", stem, n)
+	}
+}
+
+fn compress(source: &str, target: &str) -> Result<(), io::Error>
+{
+	let mut input = std::fs::File::open(source)?;
+	let output = std::fs::File::create(target)?;
+	let mut encoder =
+		flate2::write::GzEncoder::new(output, flate2::Compression::default());
+	io::copy(&mut input, &mut encoder)?;
+	encoder.finish()?;
+	std::fs::remove_file(source)
+}
+
+fn ignore_missing(result: Result<(), io::Error>) -> Result<(), io::Error>
+{
+	match result
+	{
+		Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+		result => result,
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn test_rotated_filename()
+	{
+		let filename = "logs/rust.info.log";
+		assert_eq!(
+			rotated_filename(filename, 1, false),
+			"logs/rust.info.1.log"
+		);
+		assert_eq!(
+			rotated_filename(filename, 12, true),
+			"logs/rust.info.12.log.gz"
+		);
+	}
 }
@@ -26,6 +26,7 @@ pub use epicinium_lib::ChallengeId;
 
 use crate::logic::difficulty::Difficulty;
 
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 
 #[derive(Debug)]
@@ -36,8 +37,9 @@ pub struct Challenge
 	pub metadata: Metadata,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
+#[schemars(rename = "ChallengeMetadata")]
 pub struct Metadata
 {
 	display_name: String,
@@ -116,7 +118,7 @@ pub fn ruleset_name(id: ChallengeId) -> Option<String>
 	epicinium_lib::challenge_ruleset_name(id)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct MissionBriefing(serde_json::Value);
 
 pub fn load_briefing(id: ChallengeId)
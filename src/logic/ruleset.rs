@@ -27,6 +27,7 @@ use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use tokio::io::BufReader;
 
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 use serde_json;
 
@@ -45,7 +46,8 @@ pub fn exists(name: &str) -> bool
 	epicinium_lib::ruleset_exists(name)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "RulesetData")]
 pub struct Data(serde_json::Value);
 
 pub async fn load_data(name: &str) -> Result<Data, std::io::Error>
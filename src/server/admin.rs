@@ -0,0 +1,519 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use crate::common::keycode::*;
+use crate::server::bans;
+use crate::server::chat;
+use crate::server::game;
+use crate::server::handoff;
+use crate::server::lobby;
+use crate::server::message::*;
+use crate::server::tokio::State as ServerState;
+
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+use log::*;
+
+use futures::{FutureExt, StreamExt};
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+#[derive(Debug, Serialize)]
+pub struct ClientInfo
+{
+	pub client_id: Keycode,
+	pub username: String,
+	pub lobby_id: Option<Keycode>,
+	pub role: Option<Role>,
+	pub in_game: bool,
+	pub hidden: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LobbyInfo
+{
+	pub lobby_id: Keycode,
+	pub lobby_name: String,
+	pub metadata: LobbyMetadata,
+	pub in_game: bool,
+	pub members: Vec<ClientInfo>,
+}
+
+// Operators send one command per line, such as {"command": "list_clients"},
+// and receive one JSON object per line in return.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command
+{
+	ListClients,
+	ListLobbies,
+	ListGames,
+	Kick
+	{
+		client_id: Keycode,
+	},
+	DisbandLobby
+	{
+		lobby_id: Keycode,
+	},
+	EndGame
+	{
+		lobby_id: Keycode,
+	},
+	Announce
+	{
+		message: String,
+	},
+	ListBans,
+	Ban
+	{
+		#[serde(flatten)]
+		target: bans::Target,
+		#[serde(default)]
+		reason: Option<String>,
+		#[serde(default)]
+		duration_in_seconds: Option<u64>,
+	},
+	Unban
+	{
+		#[serde(flatten)]
+		target: bans::Target,
+	},
+}
+
+pub async fn run(
+	path: String,
+	general_chat: mpsc::Sender<chat::Update>,
+	bans: bans::BanList,
+	server_state: watch::Receiver<ServerState>,
+)
+{
+	let key = format!("admin/{}", path);
+	let bound = match handoff::take_unix(&key)
+	{
+		Some(listener) => UnixListener::from_std(listener).map_err(Error::from),
+		None => bind(&path),
+	};
+	let mut listener = match bound
+	{
+		Ok(listener) => listener,
+		Err(error) =>
+		{
+			error!("Failed to open admin socket '{}': {}", path, error);
+			return;
+		}
+	};
+	handoff::register(key, &listener);
+	info!("Listening for admin commands on {}...", path);
+
+	let stopped = wait_until_stopped(server_state.clone()).boxed();
+	let mut connections = listener.incoming().take_until(stopped);
+
+	while let Some(connection) = connections.next().await
+	{
+		match connection
+		{
+			Ok(socket) =>
+			{
+				let task = serve(
+					socket,
+					general_chat.clone(),
+					bans.clone(),
+					server_state.clone(),
+				);
+				tokio::spawn(task);
+			}
+			Err(error) =>
+			{
+				warn!("Failed to accept admin connection: {:?}", error);
+			}
+		}
+	}
+
+	// After a handoff, the socket belongs to the new server process.
+	if handoff::is_handed_off()
+	{
+		return;
+	}
+
+	match std::fs::remove_file(&path)
+	{
+		Ok(()) => (),
+		Err(error) => warn!("Failed to remove '{}': {}", path, error),
+	}
+}
+
+fn bind(path: &str) -> Result<UnixListener, Error>
+{
+	// A socket left behind by a previous run would make binding fail.
+	match std::fs::symlink_metadata(path)
+	{
+		Ok(metadata) if metadata.file_type().is_socket() =>
+		{
+			std::fs::remove_file(path)?;
+		}
+		_ => (),
+	}
+
+	// Whoever can connect has full control over the server, so only the user
+	// running the server (and root) may do so. The socket is bound inside a
+	// directory that only we can enter and moved into place once its
+	// permissions are restricted, so that nobody can connect before that.
+	let staging = format!("{}.staging", path);
+	match std::fs::remove_dir_all(&staging)
+	{
+		Ok(()) => (),
+		Err(error) if error.kind() == std::io::ErrorKind::NotFound => (),
+		Err(error) => return Err(error.into()),
+	}
+	std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+	let staged = std::path::Path::new(&staging).join("admin.sock");
+	let result = bind_staged(&staged, path);
+	std::fs::remove_dir_all(&staging)?;
+	result
+}
+
+fn bind_staged(
+	staged: &std::path::Path,
+	path: &str,
+) -> Result<UnixListener, Error>
+{
+	let listener = UnixListener::bind(staged)?;
+	let permissions = std::fs::Permissions::from_mode(0o600);
+	std::fs::set_permissions(staged, permissions)?;
+	std::fs::rename(staged, path)?;
+	Ok(listener)
+}
+
+// Operators need the admin socket most while the server is closing, so we
+// keep accepting connections until it has closed, unless a new server process
+// has taken over the socket.
+async fn wait_until_stopped(mut server_state: watch::Receiver<ServerState>)
+{
+	while let Some(state) = server_state.recv().await
+	{
+		match state
+		{
+			ServerState::Open => (),
+			ServerState::Closing if handoff::is_handed_off() => break,
+			ServerState::Closing => (),
+			ServerState::Closed => break,
+		}
+	}
+}
+
+async fn wait_until_closed(mut server_state: watch::Receiver<ServerState>)
+{
+	while let Some(state) = server_state.recv().await
+	{
+		match state
+		{
+			ServerState::Open => (),
+			ServerState::Closing => (),
+			ServerState::Closed => break,
+		}
+	}
+}
+
+async fn serve(
+	socket: UnixStream,
+	general_chat: mpsc::Sender<chat::Update>,
+	bans: bans::BanList,
+	server_state: watch::Receiver<ServerState>,
+)
+{
+	debug!("Admin connected.");
+	match handle_connection(socket, general_chat, bans, server_state).await
+	{
+		Ok(()) => debug!("Admin disconnected."),
+		Err(error) => warn!("Error in admin connection: {}", error),
+	}
+}
+
+async fn handle_connection(
+	mut socket: UnixStream,
+	mut general_chat: mpsc::Sender<chat::Update>,
+	bans: bans::BanList,
+	server_state: watch::Receiver<ServerState>,
+) -> Result<(), Error>
+{
+	let (reader, mut writer) = socket.split();
+	let closed = wait_until_closed(server_state).boxed();
+	let mut lines = BufReader::new(reader).lines().take_until(closed);
+
+	while let Some(line) = lines.next().await
+	{
+		let line = line?;
+		if line.trim().is_empty()
+		{
+			continue;
+		}
+
+		let response = match serde_json::from_str(&line)
+		{
+			Ok(command) =>
+			{
+				info!("Executing admin command {:?}...", command);
+				execute(command, &mut general_chat, &bans).await?
+			}
+			Err(error) => failure(&error.to_string()),
+		};
+
+		let mut buffer = serde_json::to_vec(&response)?;
+		buffer.push(b'\n');
+		writer.write_all(&buffer).await?;
+	}
+
+	Ok(())
+}
+
+async fn execute(
+	command: Command,
+	general_chat: &mut mpsc::Sender<chat::Update>,
+	bans: &bans::BanList,
+) -> Result<serde_json::Value, Error>
+{
+	match command
+	{
+		Command::ListClients =>
+		{
+			let (callback, reply) = oneshot::channel();
+			general_chat
+				.send(chat::Update::ListClients { callback })
+				.await?;
+			let clients = reply.await?;
+			Ok(json!({"ok": true, "clients": clients}))
+		}
+		Command::ListLobbies =>
+		{
+			let lobbies = list_lobbies(general_chat).await?;
+			Ok(json!({"ok": true, "lobbies": lobbies}))
+		}
+		Command::ListGames =>
+		{
+			let games: Vec<LobbyInfo> = list_lobbies(general_chat)
+				.await?
+				.into_iter()
+				.filter(|x| x.in_game)
+				.collect();
+			Ok(json!({"ok": true, "games": games}))
+		}
+		Command::Kick { client_id } =>
+		{
+			let (callback, reply) = oneshot::channel();
+			let update = chat::Update::Kick {
+				client_id,
+				callback,
+			};
+			general_chat.send(update).await?;
+			if reply.await?
+			{
+				Ok(success())
+			}
+			else
+			{
+				Ok(failure("no such client"))
+			}
+		}
+		Command::DisbandLobby { lobby_id } =>
+		{
+			let update = lobby::Update::ForSetup(lobby::Sub::Disband {
+				general_chat: general_chat.clone(),
+			});
+			notify_lobby(general_chat, lobby_id, false, update).await
+		}
+		Command::EndGame { lobby_id } =>
+		{
+			let update = lobby::Update::ForGame(game::Sub::Abort);
+			notify_lobby(general_chat, lobby_id, true, update).await
+		}
+		Command::Announce { message } =>
+		{
+			let message = Message::Chat {
+				content: message,
+				sender: Some("server".to_string()),
+				target: ChatTarget::General,
+			};
+			general_chat.send(chat::Update::Msg(message)).await?;
+			Ok(success())
+		}
+		Command::ListBans =>
+		{
+			let bans = bans.list();
+			Ok(json!({"ok": true, "bans": bans}))
+		}
+		Command::Ban {
+			target,
+			reason,
+			duration_in_seconds,
+		} =>
+		{
+			let duration = duration_in_seconds.map(Duration::from_secs);
+			match bans.add(target, reason, duration)
+			{
+				Ok(()) => (),
+				Err(error) => return Ok(failure(&error.to_string())),
+			}
+
+			// Clients that have already logged in are kicked if they are banned.
+			general_chat.send(chat::Update::EnforceBans).await?;
+			Ok(success())
+		}
+		Command::Unban { target } => match bans.remove(&target)
+		{
+			Ok(true) => Ok(success()),
+			Ok(false) => Ok(failure("no such ban")),
+			Err(error) => Ok(failure(&error.to_string())),
+		},
+	}
+}
+
+async fn list_lobbies(
+	general_chat: &mut mpsc::Sender<chat::Update>,
+) -> Result<Vec<LobbyInfo>, Error>
+{
+	let (callback, reply) = oneshot::channel();
+	general_chat
+		.send(chat::Update::ListLobbies { callback })
+		.await?;
+	let lobbies = reply.await?;
+	Ok(lobbies)
+}
+
+async fn notify_lobby(
+	general_chat: &mut mpsc::Sender<chat::Update>,
+	lobby_id: Keycode,
+	in_game: bool,
+	update: lobby::Update,
+) -> Result<serde_json::Value, Error>
+{
+	// Lobbies ignore setup commands once the game has started and vice versa,
+	// so we check beforehand to avoid silently doing nothing.
+	let lobbies = list_lobbies(general_chat).await?;
+	match lobbies.iter().find(|x| x.lobby_id == lobby_id)
+	{
+		Some(lobby) if lobby.in_game == in_game => (),
+		Some(_) if in_game => return Ok(failure("lobby is not in game")),
+		Some(_) => return Ok(failure("lobby is in game")),
+		None => return Ok(failure("no such lobby")),
+	}
+
+	let (callback, reply) = oneshot::channel();
+	let update = chat::Update::NotifyLobby {
+		lobby_id,
+		update,
+		callback,
+	};
+	general_chat.send(update).await?;
+	if reply.await?
+	{
+		Ok(success())
+	}
+	else
+	{
+		Ok(failure("no such lobby"))
+	}
+}
+
+fn success() -> serde_json::Value
+{
+	json!({"ok": true})
+}
+
+fn failure(error: &str) -> serde_json::Value
+{
+	json!({"ok": false, "error": error})
+}
+
+#[derive(Debug)]
+enum Error
+{
+	Io(std::io::Error),
+	Json(serde_json::Error),
+	Chat
+	{
+		error: Box<mpsc::error::SendError<chat::Update>>,
+	},
+	Recv
+	{
+		error: oneshot::error::RecvError,
+	},
+}
+
+impl From<std::io::Error> for Error
+{
+	fn from(error: std::io::Error) -> Self
+	{
+		Error::Io(error)
+	}
+}
+
+impl From<serde_json::Error> for Error
+{
+	fn from(error: serde_json::Error) -> Self
+	{
+		Error::Json(error)
+	}
+}
+
+impl From<mpsc::error::SendError<chat::Update>> for Error
+{
+	fn from(error: mpsc::error::SendError<chat::Update>) -> Self
+	{
+		Error::Chat {
+			error: Box::new(error),
+		}
+	}
+}
+
+impl From<oneshot::error::RecvError> for Error
+{
+	fn from(error: oneshot::error::RecvError) -> Self
+	{
+		Error::Recv { error }
+	}
+}
+
+impl std::fmt::Display for Error
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		match self
+		{
+			Error::Io(error) => error.fmt(f),
+			Error::Json(error) => error.fmt(f),
+			Error::Chat { error } => error.fmt(f),
+			Error::Recv { error } => error.fmt(f),
+		}
+	}
+}
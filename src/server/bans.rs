@@ -0,0 +1,457 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use crate::server::login::UserId;
+use crate::server::settings::Settings;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync;
+use std::time::{Duration, SystemTime};
+
+use log::*;
+
+use serde_derive::{Deserialize, Serialize};
+
+// The bans that moderators have issued through the admin socket. They are
+// written to the ban list file, if any, whenever they change.
+#[derive(Debug, Clone)]
+pub struct BanList
+{
+	filename: Option<String>,
+	bans: sync::Arc<sync::Mutex<Vec<Ban>>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Contents
+{
+	#[serde(default)]
+	bans: Vec<Ban>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban
+{
+	#[serde(flatten)]
+	pub target: Target,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub reason: Option<String>,
+	// In seconds since the Unix epoch; bans without expiry are permanent.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub expires: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target
+{
+	Ip(IpRange),
+	UserId(UserId),
+	Username(String),
+}
+
+// A single IP address such as "203.0.113.7" or a range of addresses written
+// in CIDR notation such as "203.0.113.0/24" or "2001:db8::/32".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpRange
+{
+	network: IpAddr,
+	prefix_length: u8,
+}
+
+impl BanList
+{
+	pub fn load(settings: &Settings) -> Result<BanList, Error>
+	{
+		let filename = settings.ban_list.clone();
+		let contents = match &filename
+		{
+			Some(filename) if Path::new(filename).exists() =>
+			{
+				let raw = std::fs::read_to_string(filename)?;
+				serde_json::from_str(&raw)?
+			}
+			_ => Contents::default(),
+		};
+		info!("Loaded {} bans.", contents.bans.len());
+
+		Ok(BanList {
+			filename,
+			bans: sync::Arc::new(sync::Mutex::new(contents.bans)),
+		})
+	}
+
+	pub fn find_ip(&self, ip: IpAddr) -> Option<Ban>
+	{
+		self.find(|target| match target
+		{
+			Target::Ip(range) => range.contains(ip),
+			_ => false,
+		})
+	}
+
+	pub fn find_account(&self, user_id: UserId, username: &str) -> Option<Ban>
+	{
+		self.find(|target| match target
+		{
+			Target::UserId(x) => *x == user_id,
+			Target::Username(x) => x.eq_ignore_ascii_case(username),
+			_ => false,
+		})
+	}
+
+	fn find(&self, is_match: impl Fn(&Target) -> bool) -> Option<Ban>
+	{
+		let now = now();
+		self.lock()
+			.iter()
+			.find(|ban| !ban.has_expired(now) && is_match(&ban.target))
+			.cloned()
+	}
+
+	pub fn list(&self) -> Vec<Ban>
+	{
+		let now = now();
+		self.lock()
+			.iter()
+			.filter(|ban| !ban.has_expired(now))
+			.cloned()
+			.collect()
+	}
+
+	// Replaces any earlier ban with the same target.
+	pub fn add(
+		&self,
+		target: Target,
+		reason: Option<String>,
+		duration: Option<Duration>,
+	) -> Result<(), Error>
+	{
+		let expires = duration.map(|duration| now() + duration.as_secs());
+		let ban = Ban {
+			target,
+			reason,
+			expires,
+		};
+		info!("Adding ban {:?}.", ban);
+
+		let mut bans = self.lock();
+		bans.retain(|x| !x.target.is_same(&ban.target));
+		bans.push(ban);
+		self.save(&mut bans)
+	}
+
+	// Returns whether there was a ban to lift.
+	pub fn remove(&self, target: &Target) -> Result<bool, Error>
+	{
+		let mut bans = self.lock();
+		let count = bans.len();
+		bans.retain(|x| !x.target.is_same(target));
+		if bans.len() == count
+		{
+			return Ok(false);
+		}
+
+		info!("Lifted ban on {:?}.", target);
+		self.save(&mut bans)?;
+		Ok(true)
+	}
+
+	// We also use this opportunity to forget expired bans.
+	fn save(&self, bans: &mut Vec<Ban>) -> Result<(), Error>
+	{
+		let now = now();
+		bans.retain(|ban| !ban.has_expired(now));
+
+		let filename = match &self.filename
+		{
+			Some(filename) => filename,
+			None => return Ok(()),
+		};
+		let contents = Contents { bans: bans.clone() };
+		let raw = serde_json::to_string_pretty(&contents)?;
+		let tmpfilename = format!("{}.tmp", filename);
+		std::fs::write(&tmpfilename, raw)?;
+		std::fs::rename(&tmpfilename, filename)?;
+		Ok(())
+	}
+
+	fn lock(&self) -> sync::MutexGuard<'_, Vec<Ban>>
+	{
+		match self.bans.lock()
+		{
+			Ok(guard) => guard,
+			Err(poisoned) => poisoned.into_inner(),
+		}
+	}
+}
+
+impl Target
+{
+	// Usernames are not case sensitive.
+	fn is_same(&self, other: &Target) -> bool
+	{
+		match (self, other)
+		{
+			(Target::Username(a), Target::Username(b)) =>
+			{
+				a.eq_ignore_ascii_case(b)
+			}
+			(a, b) => a == b,
+		}
+	}
+}
+
+impl Ban
+{
+	fn has_expired(&self, now: u64) -> bool
+	{
+		self.expires.is_some_and(|expires| now >= expires)
+	}
+}
+
+fn now() -> u64
+{
+	SystemTime::now()
+		.duration_since(SystemTime::UNIX_EPOCH)
+		.map(|x| x.as_secs())
+		.unwrap_or(0)
+}
+
+impl IpRange
+{
+	pub fn contains(&self, ip: IpAddr) -> bool
+	{
+		// Dual stack listeners report IPv4 clients as mapped IPv6 addresses.
+		let ip = match ip
+		{
+			IpAddr::V6(ip) =>
+			{
+				ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4)
+			}
+			IpAddr::V4(_) => ip,
+		};
+		match (self.network, ip)
+		{
+			(IpAddr::V4(network), IpAddr::V4(ip)) =>
+			{
+				let mask = mask(32, self.prefix_length) as u32;
+				u32::from(ip) & mask == u32::from(network)
+			}
+			(IpAddr::V6(network), IpAddr::V6(ip)) =>
+			{
+				let mask = mask(128, self.prefix_length);
+				u128::from(ip) & mask == u128::from(network)
+			}
+			_ => false,
+		}
+	}
+}
+
+fn mask(bits: u8, prefix_length: u8) -> u128
+{
+	match bits - prefix_length
+	{
+		128 => 0,
+		host_bits => (u128::MAX >> (128 - bits)) & !((1u128 << host_bits) - 1),
+	}
+}
+
+impl std::str::FromStr for IpRange
+{
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<IpRange, Error>
+	{
+		let invalid = || Error::InvalidIpRange(s.to_string());
+		let (address, prefix_length) = match s.split_once('/')
+		{
+			Some((address, prefix_length)) =>
+			{
+				(address, Some(prefix_length.parse().map_err(|_| invalid())?))
+			}
+			None => (s, None),
+		};
+		let address: IpAddr = address.parse().map_err(|_| invalid())?;
+		let bits = if address.is_ipv4() { 32 } else { 128 };
+		let prefix_length = prefix_length.unwrap_or(bits);
+		if prefix_length > bits
+		{
+			return Err(invalid());
+		}
+
+		// The address must not have any bits set outside of the prefix,
+		// so that it is clear which addresses are banned.
+		let network = match address
+		{
+			IpAddr::V4(ip) =>
+			{
+				let mask = mask(32, prefix_length) as u32;
+				IpAddr::V4((u32::from(ip) & mask).into())
+			}
+			IpAddr::V6(ip) =>
+			{
+				let mask = mask(128, prefix_length);
+				IpAddr::V6((u128::from(ip) & mask).into())
+			}
+		};
+		if network != address
+		{
+			return Err(invalid());
+		}
+
+		Ok(IpRange {
+			network,
+			prefix_length,
+		})
+	}
+}
+
+impl fmt::Display for IpRange
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		let bits = if self.network.is_ipv4() { 32 } else { 128 };
+		if self.prefix_length == bits
+		{
+			self.network.fmt(f)
+		}
+		else
+		{
+			write!(f, "{}/{}", self.network, self.prefix_length)
+		}
+	}
+}
+
+impl TryFrom<String> for IpRange
+{
+	type Error = Error;
+
+	fn try_from(s: String) -> Result<IpRange, Error>
+	{
+		s.parse()
+	}
+}
+
+impl From<IpRange> for String
+{
+	fn from(range: IpRange) -> String
+	{
+		range.to_string()
+	}
+}
+
+#[derive(Debug)]
+pub enum Error
+{
+	InvalidIpRange(String),
+	Io(std::io::Error),
+	Json(serde_json::Error),
+}
+
+impl From<std::io::Error> for Error
+{
+	fn from(error: std::io::Error) -> Self
+	{
+		Error::Io(error)
+	}
+}
+
+impl From<serde_json::Error> for Error
+{
+	fn from(error: serde_json::Error) -> Self
+	{
+		Error::Json(error)
+	}
+}
+
+impl fmt::Display for Error
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		match self
+		{
+			Error::InvalidIpRange(s) => write!(f, "invalid IP range '{}'", s),
+			Error::Io(error) => error.fmt(f),
+			Error::Json(error) => error.fmt(f),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn range(s: &str) -> IpRange
+	{
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn test_ip_range()
+	{
+		let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+		assert!(range("203.0.113.0/24").contains(ip("203.0.113.7")));
+		assert!(range("203.0.113.0/24").contains(ip("::ffff:203.0.113.7")));
+		assert!(!range("203.0.113.0/24").contains(ip("203.0.114.7")));
+		assert!(range("203.0.113.7").contains(ip("203.0.113.7")));
+		assert!(!range("203.0.113.7").contains(ip("203.0.113.8")));
+		assert!(range("0.0.0.0/0").contains(ip("198.51.100.1")));
+		assert!(range("2001:db8::/32").contains(ip("2001:db8:1::1")));
+		assert!(!range("2001:db8::/32").contains(ip("2001:db9::1")));
+		assert!(!range("2001:db8::/32").contains(ip("203.0.113.7")));
+		assert_eq!(range("2001:db8::/32").to_string(), "2001:db8::/32");
+		assert_eq!(range("203.0.113.7/32").to_string(), "203.0.113.7");
+		assert!("203.0.113.7/24".parse::<IpRange>().is_err());
+		assert!("203.0.113.0/33".parse::<IpRange>().is_err());
+		assert!("localhost".parse::<IpRange>().is_err());
+	}
+
+	#[test]
+	fn test_ban_format()
+	{
+		let raw = r#"{"bans": [
+			{"ip": "203.0.113.0/24", "reason": "spam"},
+			{"user_id": 12, "expires": 1},
+			{"username": "Troll"}
+		]}"#;
+		let contents: Contents = serde_json::from_str(raw).unwrap();
+		let list = BanList {
+			filename: None,
+			bans: sync::Arc::new(sync::Mutex::new(contents.bans)),
+		};
+		let ip = "203.0.113.9".parse().unwrap();
+		assert_eq!(list.find_ip(ip).unwrap().reason.unwrap(), "spam");
+		let user_id: UserId = serde_json::from_str("12").unwrap();
+		assert!(list.find_account(user_id, "Someone").is_none());
+		assert!(list.find_account(user_id, "troll").is_some());
+		assert_eq!(list.list().len(), 2);
+	}
+}
@@ -23,17 +23,24 @@
 
 use crate::common::keycode::*;
 use crate::logic::challenge;
+use crate::server::admin;
 use crate::server::client;
 use crate::server::lobby;
 use crate::server::login::Unlock;
 use crate::server::message::*;
+use crate::server::metrics;
 use crate::server::rating;
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 
 use log::*;
 
+use futures::stream;
+use futures::StreamExt;
+
 use tokio::sync::mpsc;
+use tokio::sync::oneshot;
 use tokio::sync::watch;
 
 use enumset::*;
@@ -88,6 +95,10 @@ pub enum Update
 	{
 		lobby_id: Keycode,
 	},
+	GameStarted
+	{
+		lobby_id: Keycode,
+	},
 
 	FindLobby
 	{
@@ -115,10 +126,34 @@ pub enum Update
 	},
 
 	Msg(Message),
+
+	ListClients
+	{
+		callback: oneshot::Sender<Vec<admin::ClientInfo>>,
+	},
+	ListLobbies
+	{
+		callback: oneshot::Sender<Vec<admin::LobbyInfo>>,
+	},
+	Kick
+	{
+		client_id: Keycode,
+		callback: oneshot::Sender<bool>,
+	},
+	EnforceBans,
+	NotifyLobby
+	{
+		lobby_id: Keycode,
+		update: lobby::Update,
+		callback: oneshot::Sender<bool>,
+	},
 }
 
+// The general chat disbands once everyone that sends it updates has let go,
+// except for the admin socket, which may keep sending updates until then.
 pub async fn run(
-	mut updates: mpsc::Receiver<Update>,
+	updates: mpsc::Receiver<Update>,
+	admin_updates: mpsc::Receiver<Update>,
 	canary: mpsc::Sender<()>,
 	challenge_pool: &[challenge::Challenge],
 )
@@ -128,7 +163,10 @@ pub async fn run(
 	let mut lobbies: Vec<Lobby> = Vec::new();
 	let mut bots = Vec::new();
 
-	while let Some(update) = updates.recv().await
+	let updates = updates.map(Some).chain(stream::iter(std::iter::once(None)));
+	let mut updates = stream::select(updates, admin_updates.map(Some));
+
+	while let Some(Some(update)) = updates.next().await
 	{
 		handle_update(
 			update,
@@ -143,6 +181,8 @@ pub async fn run(
 			.e_drain_where(|client| client.handle.is_disconnected())
 			.collect();
 		handle_removed(removed, &mut clients, &mut ghostbusters, &mut bots);
+
+		update_metrics(&clients, &lobbies);
 	}
 
 	info!("General chat has disbanded.");
@@ -223,6 +263,7 @@ fn handle_update(
 				name,
 				metadata,
 				sendbuffer,
+				in_game: false,
 			};
 			handle_list_lobby(lobby, clients, lobbies, listed_bots)
 		}
@@ -268,6 +309,13 @@ fn handle_update(
 		{
 			handle_in_game(clients, lobby_id, client_id, role);
 		}
+		Update::GameStarted { lobby_id } =>
+		{
+			if let Some(lobby) = lobbies.iter_mut().find(|x| x.id == lobby_id)
+			{
+				lobby.in_game = true;
+			}
+		}
 
 		Update::Msg(message) =>
 		{
@@ -276,7 +324,61 @@ fn handle_update(
 				client.handle.send(message.clone());
 			}
 		}
+
+		Update::ListClients { callback } =>
+		{
+			let list = clients.iter().map(admin_client_info).collect();
+			let _discarded = callback.send(list);
+		}
+		Update::ListLobbies { callback } =>
+		{
+			let list = lobbies
+				.iter()
+				.map(|lobby| admin_lobby_info(lobby, clients))
+				.collect();
+			let _discarded = callback.send(list);
+		}
+		Update::Kick {
+			client_id,
+			callback,
+		} =>
+		{
+			let found = handle_kick(client_id, clients);
+			let _discarded = callback.send(found);
+		}
+		Update::EnforceBans =>
+		{
+			for client in clients.iter_mut()
+			{
+				client.handle.notify(client::Update::BansChanged);
+			}
+		}
+		Update::NotifyLobby {
+			lobby_id,
+			update,
+			callback,
+		} =>
+		{
+			let found = lobbies.iter().any(|x| x.id == lobby_id);
+			notify_lobby_or_disband(lobby_id, clients, lobbies, update);
+			let _discarded = callback.send(found);
+		}
+	}
+}
+
+fn update_metrics(clients: &[Client], lobbies: &[Lobby])
+{
+	metrics::LOGGED_IN_USERS.set(clients.len() as i64);
+
+	let mut lobbies_per_type = BTreeMap::new();
+	for lobby in lobbies
+	{
+		let lobby_type = lobby.metadata.borrow().lobby_type;
+		let label = serde_plain::to_string(&lobby_type)
+			.unwrap_or_else(|_| format!("{:?}", lobby_type));
+		*lobbies_per_type.entry(label).or_insert(0) += 1;
 	}
+	metrics::OPEN_LOBBIES.set_all(lobbies_per_type);
 }
 
 struct Client
@@ -350,6 +452,7 @@ struct Lobby
 	name: watch::Receiver<String>,
 	metadata: watch::Receiver<LobbyMetadata>,
 	sendbuffer: mpsc::Sender<lobby::Update>,
+	in_game: bool,
 }
 
 fn handle_join(
@@ -806,6 +909,60 @@ fn notify_lobby_or_disband(
 	handle_disband_lobby(lobby_id, clients, lobbies);
 }
 
+fn handle_kick(client_id: Keycode, clients: &mut [Client]) -> bool
+{
+	match clients.iter_mut().find(|x| x.id == client_id)
+	{
+		Some(client) =>
+		{
+			info!("Kicking client {} ({}).", client.id, client.username);
+			client.handle.notify(client::Update::Kicked);
+			true
+		}
+		None => false,
+	}
+}
+
+fn admin_client_info(client: &Client) -> admin::ClientInfo
+{
+	let (lobby_id, role, in_game) = match client.availability_status
+	{
+		AvailabilityStatus::Available => (None, None, false),
+		AvailabilityStatus::InLobby { lobby_id } =>
+		{
+			(Some(lobby_id), None, false)
+		}
+		AvailabilityStatus::InGame { lobby_id, role } =>
+		{
+			(Some(lobby_id), Some(role), true)
+		}
+	};
+	admin::ClientInfo {
+		client_id: client.id,
+		username: client.username.clone(),
+		lobby_id,
+		role,
+		in_game,
+		hidden: client.hidden,
+	}
+}
+
+fn admin_lobby_info(lobby: &Lobby, clients: &[Client]) -> admin::LobbyInfo
+{
+	let members: Vec<admin::ClientInfo> = clients
+		.iter()
+		.map(admin_client_info)
+		.filter(|x| x.lobby_id == Some(lobby.id))
+		.collect();
+	admin::LobbyInfo {
+		lobby_id: lobby.id,
+		lobby_name: lobby.name.borrow().clone(),
+		metadata: *lobby.metadata.borrow(),
+		in_game: lobby.in_game,
+		members,
+	}
+}
+
 fn handle_find_lobby(
 	lobbies: &mut Vec<Lobby>,
 	lobby_id: Keycode,
@@ -21,19 +21,36 @@
  * Sander in 't Veld (sander@abunchofhacks.coop)
  */
 
+mod admission;
+mod codec;
+mod compression;
+mod connection;
 mod limit;
 mod login;
 mod ping;
+mod proxy;
 mod pulse;
 mod receive;
+mod resume;
 mod send;
+mod sendbuffer;
 
+pub mod capture;
+pub mod drain;
 pub mod handle;
 
+pub use admission::Admissions;
+pub use admission::Ticket;
+pub use connection::establish;
+pub use connection::Address;
+pub use connection::Connection;
 pub use handle::Handle;
+pub use resume::Sessions;
+pub use send::Outgoing;
 
 use crate::common::keycode::Keycode;
 use crate::common::version::*;
+use crate::server::bans;
 use crate::server::chat;
 use crate::server::discord_api;
 use crate::server::game;
@@ -41,7 +58,9 @@ use crate::server::lobby;
 use crate::server::login::Unlock;
 use crate::server::login::UserId;
 use crate::server::message::*;
+use crate::server::metrics;
 use crate::server::rating;
+use crate::server::settings::Settings;
 use crate::server::slack_api;
 use crate::server::tokio::State as ServerState;
 
@@ -57,8 +76,6 @@ use futures::pin_mut;
 use futures::stream;
 use futures::{FutureExt, StreamExt, TryFutureExt};
 
-use tokio::io::ReadHalf;
-use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 use tokio::sync::watch;
@@ -68,7 +85,7 @@ use enumset::EnumSet;
 
 struct Client
 {
-	sendbuffer: mpsc::Sender<Message>,
+	sendbuffer: sendbuffer::Sender,
 	last_receive_time: watch::Sender<()>,
 	pong_receive_time: Option<oneshot::Sender<()>>,
 	ping_tolerance: watch::Sender<Duration>,
@@ -83,15 +100,35 @@ struct Client
 		Option<(rating::Data, watch::Sender<rating::RatingAndStars>)>,
 	canary_for_lobbies: mpsc::Sender<()>,
 	lobby_authority: sync::Arc<atomic::AtomicU64>,
+	server_state_for_lobbies: watch::Receiver<ServerState>,
+	drain_deadline: Option<Duration>,
 	lobby: Option<mpsc::Sender<lobby::Update>>,
 	bot_lobbies:
 		std::collections::HashMap<Keycode, mpsc::Sender<lobby::Update>>,
 	has_proper_version: bool,
 	has_proper_version_a: sync::Arc<atomic::AtomicBool>,
+	allows_compression: bool,
+	codec: watch::Sender<codec::Codec>,
+	allows_capture: bool,
+	capture: watch::Sender<Option<capture::Recorder>>,
 	appears_active_according_to_notifications: bool,
 	has_gracefully_disconnected: bool,
+	num_rejected_messages: usize,
+	sessions: Sessions,
+	resume_token: Option<String>,
+	resume_grace: Option<Duration>,
+	is_resumable: bool,
+	reattach_callback: mpsc::Sender<resume::Reattachment>,
+	readers: mpsc::Sender<receive::Socket>,
+	writers: mpsc::Sender<send::Reattachment>,
+	expirations: mpsc::Sender<u64>,
+	num_disconnections: u64,
+	is_parked: bool,
+	bans: bans::BanList,
+	ticket: Ticket,
 
 	pub id: Keycode,
+	pub address: Address,
 	pub user_id: Option<UserId>,
 	pub username: String,
 	pub version: Version,
@@ -112,6 +149,11 @@ impl Drop for Client
 {
 	fn drop(&mut self)
 	{
+		if let Some(token) = self.resume_token.take()
+		{
+			self.sessions.unregister(&token);
+		}
+
 		if let Some(user_id) = self.user_id
 		{
 			let update = rating::Update::Left { user_id };
@@ -219,9 +261,240 @@ impl Drop for Client
 	}
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct Options
+{
+	allow_capture: bool,
+	sendbuffer: sendbuffer::Policy,
+	resume_grace: Option<Duration>,
+	drain_deadline: Option<Duration>,
+}
+
+impl Options
+{
+	pub fn from_settings(settings: &Settings)
+		-> Result<Options, anyhow::Error>
+	{
+		let defaults = sendbuffer::Policy::default();
+		let policy = sendbuffer::Policy {
+			capacity: settings.send_queue_capacity.unwrap_or(defaults.capacity),
+			threshold: settings
+				.send_queue_threshold
+				.unwrap_or(defaults.threshold),
+			timeout: settings
+				.slow_client_timeout
+				.map(Duration::from_secs)
+				.unwrap_or(defaults.timeout),
+		};
+		if policy.threshold >= policy.capacity
+		{
+			return Err(anyhow::anyhow!(
+				"'send-queue-threshold' must be less than 'send-queue-capacity'"
+			));
+		}
+
+		// A grace period of zero disables resuming altogether.
+		let resume_grace = match settings.resume_grace_period
+		{
+			Some(0) => None,
+			Some(seconds) => Some(Duration::from_secs(seconds)),
+			None => Some(Duration::from_secs(30)),
+		};
+
+		Ok(Options {
+			allow_capture: settings.allow_capture.unwrap_or(false),
+			sendbuffer: policy,
+			resume_grace,
+			drain_deadline: settings.drain_deadline.map(Duration::from_secs),
+		})
+	}
+}
+
 pub fn accept(
-	socket: TcpStream,
+	connection: Connection,
+	id: Keycode,
+	address: Address,
+	options: Options,
+	sessions: Sessions,
+	login_server: sync::Arc<login::Server>,
+	chat_server: mpsc::Sender<chat::Update>,
+	rating_database: mpsc::Sender<rating::Update>,
+	slack_api: mpsc::Sender<slack_api::Post>,
+	discord_api: mpsc::Sender<discord_api::Post>,
+	server_state: watch::Receiver<ServerState>,
+	canary: mpsc::Sender<()>,
+	lobby_authority: sync::Arc<atomic::AtomicU64>,
+	ticket: Ticket,
+	bans: bans::BanList,
+)
+{
+	let (mut reader, writer, allows_compression) = match connection
+	{
+		Connection::Raw(stream) =>
+		{
+			let (reader, writer) = tokio::io::split(stream);
+			(
+				receive::Socket::Raw(reader),
+				send::Socket::Raw(writer),
+				true,
+			)
+		}
+		Connection::WebSocket(stream) =>
+		{
+			let (writer, reader) = (*stream).split();
+			(
+				receive::Socket::WebSocket(reader),
+				send::Socket::WebSocket(writer),
+				false,
+			)
+		}
+	};
+
+	// Until we have received the version handshake, we cannot tell whether
+	// this connection starts a new session or resumes an existing one.
+	let task = async move {
+		let greeting = receive::receive_greeting(&mut reader, id);
+		let timeout = Duration::from_secs(120);
+		let greeting = match tokio::time::timeout(timeout, greeting).await
+		{
+			Ok(Ok(message)) => message,
+			Ok(Err(error)) =>
+			{
+				warn!("Failed to receive greeting from {}: {}", id, error);
+				return;
+			}
+			Err(_elapsed) =>
+			{
+				warn!("Client {} did not greet us in time.", id);
+				return;
+			}
+		};
+
+		let (reader, writer, greeting, ticket) = match greeting
+		{
+			Message::Version {
+				version,
+				mut metadata,
+			} => match metadata.resume_token.take()
+			{
+				Some(token) =>
+				{
+					let reattachment = resume::Reattachment {
+						reader,
+						writer,
+						allows_compression,
+						address,
+						version,
+						metadata,
+						ticket,
+					};
+					match sessions.hand_over(&token, reattachment)
+					{
+						Ok(()) =>
+						{
+							debug!("Client {} is resuming a session.", id);
+							return;
+						}
+						Err(reattachment) =>
+						{
+							debug!("Client {} cannot resume its session.", id);
+							let greeting = Message::Version {
+								version: reattachment.version,
+								metadata: reattachment.metadata,
+							};
+							(
+								reattachment.reader,
+								reattachment.writer,
+								greeting,
+								reattachment.ticket,
+							)
+						}
+					}
+				}
+				None =>
+				{
+					let greeting = Message::Version { version, metadata };
+					(reader, writer, greeting, ticket)
+				}
+			},
+			greeting => (reader, writer, greeting, ticket),
+		};
+
+		start(
+			reader,
+			writer,
+			allows_compression,
+			greeting,
+			id,
+			address,
+			options,
+			sessions,
+			login_server,
+			chat_server,
+			rating_database,
+			slack_api,
+			discord_api,
+			server_state,
+			canary,
+			lobby_authority,
+			ticket,
+			bans,
+		);
+	};
+
+	tokio::spawn(crate::common::log::for_client(id, task));
+}
+
+// Tells a client that we cannot serve it before closing the connection.
+pub fn refuse(connection: Connection, id: Keycode, reason: RefusalReason)
+{
+	let writer = match connection
+	{
+		Connection::Raw(stream) =>
+		{
+			let (_reader, writer) = tokio::io::split(stream);
+			send::Socket::Raw(writer)
+		}
+		Connection::WebSocket(stream) =>
+		{
+			let (writer, _reader) = (*stream).split();
+			send::Socket::WebSocket(writer)
+		}
+	};
+	send_refusal(writer, id, reason);
+}
+
+fn send_refusal(writer: send::Socket, id: Keycode, reason: RefusalReason)
+{
+	let task = async move {
+		let message = Message::Closing {
+			reason: Some(reason),
+		};
+		let sending = send::send_once(writer, message);
+		let timeout = Duration::from_secs(10);
+		match tokio::time::timeout(timeout, sending).await
+		{
+			Ok(Ok(())) => info!("Rejected client {}: {:?}.", id, reason),
+			Ok(Err(error)) =>
+			{
+				warn!("Failed to reject client {}: {:?}", id, error);
+			}
+			Err(_elapsed) => warn!("Rejecting client {} timed out.", id),
+		}
+	};
+
+	tokio::spawn(crate::common::log::for_client(id, task));
+}
+
+fn start(
+	reader: receive::Socket,
+	writer: send::Socket,
+	allows_compression: bool,
+	greeting: Message,
 	id: Keycode,
+	address: Address,
+	options: Options,
+	sessions: Sessions,
 	login_server: sync::Arc<login::Server>,
 	chat_server: mpsc::Sender<chat::Update>,
 	rating_database: mpsc::Sender<rating::Update>,
@@ -230,9 +503,12 @@ pub fn accept(
 	server_state: watch::Receiver<ServerState>,
 	canary: mpsc::Sender<()>,
 	lobby_authority: sync::Arc<atomic::AtomicU64>,
+	ticket: Ticket,
+	bans: bans::BanList,
 )
 {
-	let (sendbuffer_in, sendbuffer_out) = mpsc::channel::<Message>(10000);
+	let (sendbuffer_in, sendbuffer_out) =
+		sendbuffer::channel(options.sendbuffer);
 	let sendbuffer_pulse = sendbuffer_in.clone();
 	let sendbuffer_login = sendbuffer_in.clone();
 	let sendbuffer_handle = sendbuffer_in.clone();
@@ -244,8 +520,16 @@ pub fn accept(
 	let (timebuffer_in, timebuffer_out) = watch::channel(());
 	let (logindata_in, logindata_out) = mpsc::channel::<login::LoginData>(1);
 	let (login_in, login_out) = mpsc::channel::<login::Request>(1);
-	let (reader, writer) = tokio::io::split(socket);
+	let has_proper_version = sync::Arc::new(atomic::AtomicBool::new(false));
+	let (codec_in, codec_out) = watch::channel(codec::Codec::default());
+	let (capture_in, capture_out) = watch::channel(None);
+	let capture_send = capture_out.clone();
+	let (reattach_in, reattach_out) = mpsc::channel::<resume::Reattachment>(1);
+	let (readers_in, readers_out) = mpsc::channel::<receive::Socket>(10);
+	let (writers_in, writers_out) = mpsc::channel::<send::Reattachment>(10);
+	let (expirations_in, expirations_out) = mpsc::channel::<u64>(10);
 	let canary_for_lobbies = canary.clone();
+	let server_state_for_lobbies = server_state.clone();
 
 	let handle = Handle::Connected {
 		id,
@@ -270,14 +554,34 @@ pub fn accept(
 		data_for_rating: None,
 		lobby_authority,
 		canary_for_lobbies,
+		server_state_for_lobbies,
+		drain_deadline: options.drain_deadline,
 		lobby: None,
 		bot_lobbies: std::collections::HashMap::new(),
 		has_proper_version: false,
-		has_proper_version_a: sync::Arc::new(atomic::AtomicBool::new(false)),
+		has_proper_version_a: has_proper_version.clone(),
+		allows_compression,
+		codec: codec_in,
+		allows_capture: options.allow_capture,
+		capture: capture_in,
 		appears_active_according_to_notifications: false,
 		has_gracefully_disconnected: false,
+		num_rejected_messages: 0,
+		sessions,
+		resume_token: None,
+		resume_grace: options.resume_grace,
+		is_resumable: false,
+		reattach_callback: reattach_in,
+		readers: readers_in,
+		writers: writers_in,
+		expirations: expirations_in,
+		num_disconnections: 0,
+		is_parked: false,
+		bans,
+		ticket,
 
 		id,
+		address,
 		user_id: None,
 		username: String::new(),
 		version: Version::undefined(),
@@ -286,6 +590,14 @@ pub fn accept(
 		closing: false,
 	};
 
+	let receiver = receive::Client {
+		socket: reader,
+		client_id: id,
+		has_proper_version,
+		codec: codec_out,
+		capture: capture_out,
+	};
+
 	let receive_task = start_receive_task(
 		client,
 		pingbuffer_out,
@@ -293,9 +605,16 @@ pub fn accept(
 		updatebuffer_out,
 		poison_out,
 		server_state,
-		reader,
+		reattach_out,
+		expirations_out,
+		greeting,
+		receiver,
+		readers_out,
+		options.drain_deadline,
 	);
-	let send_task = send::run(id, sendbuffer_out, writer).map_err(|e| e.into());
+	let send_task =
+		send::run(id, sendbuffer_out, writer, writers_out, capture_send)
+			.map_err(|e| e.into());
 	let ping_task =
 		ping::run(id, pingbuffer_in, timebuffer_out, pingtolerance_out)
 			.map_err(|error| error.into());
@@ -332,10 +651,12 @@ pub fn accept(
 		.map_err(move |e| error!("Error in client {}: {:?}", id, e))
 		.map(move |_result| {
 			info!("Client {} has disconnected.", id);
+			metrics::CONNECTED_CLIENTS.decrement();
 			let _discarded = canary;
 		});
 
-	tokio::spawn(task);
+	metrics::CONNECTED_CLIENTS.increment();
+	tokio::spawn(crate::common::log::for_client(id, task));
 }
 
 async fn start_receive_task(
@@ -345,7 +666,12 @@ async fn start_receive_task(
 	server_updates: mpsc::Receiver<Update>,
 	poison: mpsc::Receiver<handle::Poison>,
 	server_state: watch::Receiver<ServerState>,
-	socket: ReadHalf<TcpStream>,
+	reattachments: mpsc::Receiver<resume::Reattachment>,
+	expirations: mpsc::Receiver<u64>,
+	greeting: Message,
+	receiver: receive::Client,
+	readers: mpsc::Receiver<receive::Socket>,
+	drain_deadline: Option<Duration>,
 ) -> Result<(), Error>
 {
 	let ping_updates =
@@ -358,6 +684,19 @@ async fn start_receive_task(
 		unlocks: data.unlocks,
 		rating_data: data.rating_data,
 	});
+	let drain_state = server_state.clone();
+	let drain_updates = stream::iter(drain_deadline)
+		.flat_map(move |deadline| {
+			drain::countdown(drain_state.clone(), deadline)
+		})
+		.map(|event| match event
+		{
+			drain::Event::Countdown { remaining } =>
+			{
+				Update::DrainCountdown { remaining }
+			}
+			drain::Event::DeadlinePassed => Update::DrainDeadlinePassed,
+		});
 	let state_updates = server_state.filter_map(|x| match x
 	{
 		ServerState::Open => future::ready(None),
@@ -365,26 +704,36 @@ async fn start_receive_task(
 		ServerState::Closed => future::ready(Some(Update::Closed)),
 	});
 	let poison_updates = poison.map(|x| x.into());
+	let resume_updates = stream::select(
+		reattachments.map(Update::Reattached),
+		expirations
+			.map(|disconnection| Update::ResumeWindowExpired { disconnection }),
+	);
 
 	let other_updates = stream::select(
 		stream::select(
 			stream::select(server_updates, ping_updates),
 			stream::select(login_updates, state_updates),
 		),
-		poison_updates,
+		stream::select(
+			poison_updates,
+			stream::select(resume_updates, drain_updates),
+		),
 	)
 	.map(|x| Ok(x))
 	.chain(stream::once(async { Err(Error::Unexpected) }));
 
-	let receiver = receive::Client {
-		socket,
-		client_id: client.id,
-		has_proper_version: client.has_proper_version_a.clone(),
-	};
-	let message_updates = stream::try_unfold(receiver, |mut x| async {
-		let message = x.receive().await?;
-		Ok(Some((Update::Msg(message), x)))
+	// The greeting was already received before this session started.
+	let greeting = stream::once(async { Update::Msg(greeting) });
+	let message_updates = receive::run(receiver, readers).map(|x| match x
+	{
+		Ok(message) => Update::Msg(message),
+		Err(error) if error.is_malformed() => Update::Malformed {
+			error: Box::new(error),
+		},
+		Err(error) => Update::ConnectionLost { error },
 	});
+	let message_updates = greeting.chain(message_updates).map(Ok);
 
 	let updates = stream::select(message_updates, other_updates);
 	pin_mut!(updates);
@@ -438,15 +787,40 @@ pub enum Update
 	RatingAndStars,
 	Closing,
 	Closed,
-	Poison,
+	DrainCountdown
+	{
+		remaining: Duration,
+	},
+	DrainDeadlinePassed,
+	Poison
+	{
+		cause: Option<sendbuffer::Error>,
+	},
 	Msg(Message),
+	Malformed
+	{
+		error: Box<receive::Error>,
+	},
+	ConnectionLost
+	{
+		error: receive::Error,
+	},
+	Reattached(resume::Reattachment),
+	ResumeWindowExpired
+	{
+		disconnection: u64,
+	},
+	Kicked,
+	BansChanged,
 }
 
 impl From<handle::Poison> for Update
 {
-	fn from(_poison: handle::Poison) -> Update
+	fn from(poison: handle::Poison) -> Update
 	{
-		Update::Poison
+		Update::Poison {
+			cause: poison.cause,
+		}
 	}
 }
 
@@ -457,14 +831,14 @@ enum Error
 	Unexpected,
 	Poisoned,
 	Io(std::io::Error),
-	Send
-	{
-		error: mpsc::error::SendError<Message>,
-	},
-	TrySend
+	SlowConsumer
 	{
-		error: mpsc::error::TrySendError<Message>,
+		queued: usize,
+		bytes_in_flight: usize,
+		stuck_for: Duration,
 	},
+	SendBufferClosed,
+	ResumeWindowExpired,
 	Update
 	{
 		error: mpsc::error::SendError<Update>,
@@ -497,6 +871,14 @@ enum Error
 	{
 		error: watch::error::SendError<Duration>,
 	},
+	Codec
+	{
+		error: watch::error::SendError<codec::Codec>,
+	},
+	Capture
+	{
+		error: watch::error::SendError<Option<capture::Recorder>>,
+	},
 	Watch
 	{
 		error: watch::error::SendError<()>,
@@ -512,7 +894,6 @@ enum Error
 	ReceiveTask(receive::Error),
 	SendTask(send::Error),
 	PingTask(ping::Error),
-	PulseTask(pulse::Error),
 	LoginTask(login::Error),
 }
 
@@ -524,19 +905,23 @@ impl From<std::io::Error> for Error
 	}
 }
 
-impl From<mpsc::error::SendError<Message>> for Error
-{
-	fn from(error: mpsc::error::SendError<Message>) -> Self
-	{
-		Error::Send { error }
-	}
-}
-
-impl From<mpsc::error::TrySendError<Message>> for Error
+impl From<sendbuffer::Error> for Error
 {
-	fn from(error: mpsc::error::TrySendError<Message>) -> Self
+	fn from(error: sendbuffer::Error) -> Self
 	{
-		Error::TrySend { error }
+		match error
+		{
+			sendbuffer::Error::SlowConsumer {
+				queued,
+				bytes_in_flight,
+				stuck_for,
+			} => Error::SlowConsumer {
+				queued,
+				bytes_in_flight,
+				stuck_for,
+			},
+			sendbuffer::Error::Closed => Error::SendBufferClosed,
+		}
 	}
 }
 
@@ -604,6 +989,22 @@ impl From<watch::error::SendError<Duration>> for Error
 	}
 }
 
+impl From<watch::error::SendError<codec::Codec>> for Error
+{
+	fn from(error: watch::error::SendError<codec::Codec>) -> Self
+	{
+		Error::Codec { error }
+	}
+}
+
+impl From<watch::error::SendError<Option<capture::Recorder>>> for Error
+{
+	fn from(error: watch::error::SendError<Option<capture::Recorder>>) -> Self
+	{
+		Error::Capture { error }
+	}
+}
+
 impl From<watch::error::SendError<()>> for Error
 {
 	fn from(error: watch::error::SendError<()>) -> Self
@@ -656,7 +1057,10 @@ impl From<pulse::Error> for Error
 {
 	fn from(error: pulse::Error) -> Self
 	{
-		Error::PulseTask(error)
+		match error
+		{
+			pulse::Error::SendMessage(error) => error.into(),
+		}
 	}
 }
 
@@ -678,8 +1082,23 @@ impl fmt::Display for Error
 			Error::Unexpected => write!(f, "Something unexpected happened"),
 			Error::Poisoned => write!(f, "Poisoned by chat or lobby"),
 			Error::Io(error) => error.fmt(f),
-			Error::Send { error } => error.fmt(f),
-			Error::TrySend { error } => error.fmt(f),
+			Error::SlowConsumer {
+				queued,
+				bytes_in_flight,
+				stuck_for,
+			} => write!(
+				f,
+				"Client was too slow; stuck for {}s with {} messages queued \
+				 and {} bytes in flight",
+				stuck_for.as_secs(),
+				queued,
+				bytes_in_flight
+			),
+			Error::SendBufferClosed => write!(f, "Send buffer closed"),
+			Error::ResumeWindowExpired =>
+			{
+				write!(f, "Connection lost and not resumed in time")
+			}
 			Error::Update { error } => error.fmt(f),
 			Error::Chat { error } => error.fmt(f),
 			Error::Lobby { error } => error.fmt(f),
@@ -688,13 +1107,14 @@ impl fmt::Display for Error
 			Error::DiscordApi { error } => error.fmt(f),
 			Error::Rating { error } => error.fmt(f),
 			Error::Tolerance { error } => error.fmt(f),
+			Error::Codec { error } => error.fmt(f),
+			Error::Capture { error } => error.fmt(f),
 			Error::Watch { error } => error.fmt(f),
 			Error::Recv { error } => error.fmt(f),
 			Error::OneshotRecv { error } => error.fmt(f),
 			Error::ReceiveTask(e) => write!(f, "Error in receive task: {}", e),
 			Error::SendTask(e) => write!(f, "Error in send task: {}", e),
 			Error::PingTask(e) => write!(f, "Error in ping task: {}", e),
-			Error::PulseTask(e) => write!(f, "Error in pulse task: {}", e),
 			Error::LoginTask(e) => write!(f, "Error in login task: {}", e),
 		}
 	}
@@ -729,7 +1149,22 @@ async fn handle_update(
 			rating_data,
 		} =>
 		{
+			if let Some(ban) = client.bans.find_account(user_id, &username)
+			{
+				info!("User '{}' is banned: {:?}", username, ban);
+				metrics::LOGIN_FAILURES.increment("AccountDisabled");
+				let message = Message::JoinServer {
+					status: Some(ResponseStatus::AccountDisabled),
+					content: None,
+					sender: None,
+					metadata: Default::default(),
+				};
+				client.sendbuffer.try_send(message)?;
+				return Ok(None);
+			}
+
 			client.user_id = Some(user_id);
+			crate::common::log::set_user_id(Some(user_id.as_u64()));
 			client.username = username;
 			client.unlocks = unlocks;
 
@@ -773,7 +1208,9 @@ async fn handle_update(
 				}
 				None =>
 				{
-					client.sendbuffer.try_send(Message::Closing)?;
+					client
+						.sendbuffer
+						.try_send(Message::Closing { reason: None })?;
 					Ok(None)
 				}
 			}
@@ -809,11 +1246,14 @@ async fn handle_update(
 				client.appears_active_according_to_notifications = true;
 
 				client.general_chat = Some(chat);
+				issue_resume_token(client)?;
 				Ok(None)
 			}
 			None =>
 			{
-				client.sendbuffer.try_send(Message::Closing)?;
+				client
+					.sendbuffer
+					.try_send(Message::Closing { reason: None })?;
 				Ok(None)
 			}
 		},
@@ -821,39 +1261,21 @@ async fn handle_update(
 		Update::LobbyFound {
 			lobby_id: _,
 			invite,
-			mut lobby_sendbuffer,
+			lobby_sendbuffer,
 			general_chat,
 		} =>
 		{
-			let client_user_id = match client.user_id
-			{
-				Some(user_id) => user_id,
-				None =>
-				{
-					error!("Expected user_id");
-					return Err(Error::Unexpected);
-				}
-			};
-			let update = lobby::Update::Join {
-				client_id: client.id,
-				client_user_id,
-				client_username: client.username.clone(),
-				client_handle: client.handle.clone(),
-				lobby_sendbuffer: lobby_sendbuffer.clone(),
-				general_chat,
-				desired_metadata: None,
-				invite,
-			};
-			lobby_sendbuffer.send(update).await?;
+			join_lobby(client, lobby_sendbuffer, general_chat, invite).await?;
 			Ok(None)
 		}
 		Update::LobbyNotFound { lobby_id: _ } =>
 		{
-			client.sendbuffer.try_send(Message::JoinLobby {
+			let message = Message::JoinLobby {
 				lobby_id: None,
 				username: None,
 				invite: None,
-			})?;
+			};
+			client.sendbuffer.try_send(message)?;
 			Ok(None)
 		}
 		Update::JoinedLobby { lobby_id, lobby } =>
@@ -865,6 +1287,7 @@ async fn handle_update(
 			else
 			{
 				client.lobby = Some(lobby);
+				crate::common::log::set_lobby_id(Some(lobby_id));
 			}
 			Ok(None)
 		}
@@ -885,9 +1308,16 @@ async fn handle_update(
 		{
 			client.closing = true;
 			client.general_chat_reserve.take();
-			client.sendbuffer.try_send(Message::Closing)?;
+			client
+				.sendbuffer
+				.try_send(Message::Closing { reason: None })?;
 			Ok(None)
 		}
+		Update::Closed if client.is_parked =>
+		{
+			// There is nothing left to come back for.
+			Err(Error::ResumeWindowExpired)
+		}
 		Update::Closed =>
 		{
 			client.closing = true;
@@ -895,10 +1325,137 @@ async fn handle_update(
 			client.sendbuffer.try_send(Message::Closed)?;
 			Ok(None)
 		}
+		Update::DrainCountdown { remaining } =>
+		{
+			announce_shutdown(client, remaining)?;
+			Ok(None)
+		}
+		Update::DrainDeadlinePassed =>
+		{
+			info!(
+				"Client {} is disconnected by the drain deadline.",
+				client.id
+			);
+			// Interrupt unfinished games first, if their lobbies have not
+			// disbanded yet, so that leaving them is not seen as abandoning.
+			if let Some(ref mut lobby) = client.lobby
+			{
+				let update = lobby::Update::ForGame(game::Sub::Hibernate);
+				let _ = lobby.send(update).await;
+			}
+			for lobby in client.bot_lobbies.values_mut()
+			{
+				let update = lobby::Update::ForGame(game::Sub::Hibernate);
+				let _ = lobby.send(update).await;
+			}
+			client.sendbuffer.try_send(Message::Closed)?;
+			Ok(Some(HasQuit))
+		}
+
+		Update::Kicked =>
+		{
+			info!("Client {} was kicked.", client.id);
+			let message = Message::Error {
+				code: ErrorCode::Kicked,
+				metadata: ErrorMetadata::default(),
+			};
+			client.sendbuffer.try_send(message)?;
+			Ok(Some(HasQuit))
+		}
+		Update::BansChanged =>
+		{
+			let ip_ban = match client.address
+			{
+				Address::Ip(address) => client.bans.find_ip(address.ip()),
+				Address::Local => None,
+			};
+			let account_ban = match client.user_id
+			{
+				Some(user_id) =>
+				{
+					client.bans.find_account(user_id, &client.username)
+				}
+				None => None,
+			};
+			match ip_ban.or(account_ban)
+			{
+				Some(ban) =>
+				{
+					info!("Client {} is banned: {:?}", client.id, ban);
+					let message = Message::Error {
+						code: ErrorCode::Kicked,
+						metadata: ErrorMetadata::default(),
+					};
+					client.sendbuffer.try_send(message)?;
+					Ok(Some(HasQuit))
+				}
+				None => Ok(None),
+			}
+		}
+
+		Update::Poison { cause: Some(error) } => Err(error.into()),
+		Update::Poison { cause: None } => Err(Error::Poisoned),
+
+		Update::Msg(message) =>
+		{
+			let label = metrics::message_type(&message);
+			metrics::MESSAGES_RECEIVED.increment(label);
+			handle_message(client, message).await
+		}
+		Update::Malformed { error } if client.has_proper_version =>
+		{
+			client.last_receive_time.broadcast(())?;
+
+			warn!("Malformed message from client {}: {}", client.id, error);
+			let detail = error.to_string();
+			reject(client, ErrorCode::MalformedMessage, None, Some(&detail))?;
+			Ok(None)
+		}
+		Update::Malformed { error } =>
+		{
+			// Clients that have not told us their version yet might not
+			// speak this protocol at all.
+			Err((*error).into())
+		}
 
-		Update::Poison => Err(Error::Poisoned),
+		Update::ConnectionLost { error } => match client.resume_grace
+		{
+			Some(grace)
+				if client.resume_token.is_some()
+					&& !client.closing
+					&& error.is_connection_lost() =>
+			{
+				warn!("Client {} lost its connection: {}", client.id, error);
+				client.num_disconnections += 1;
+				client.is_parked = true;
+				client.sendbuffer.set_parked(true);
 
-		Update::Msg(message) => handle_message(client, message).await,
+				let disconnection = client.num_disconnections;
+				let mut expirations = client.expirations.clone();
+				tokio::spawn(async move {
+					tokio::time::delay_for(grace).await;
+					match expirations.send(disconnection).await
+					{
+						Ok(()) => (),
+						Err(_error) => (),
+					}
+				});
+				Ok(None)
+			}
+			_ => Err(error.into()),
+		},
+		Update::Reattached(reattachment) =>
+		{
+			resume_session(client, reattachment)?;
+			Ok(None)
+		}
+		Update::ResumeWindowExpired { disconnection }
+			if client.is_parked
+				&& disconnection == client.num_disconnections =>
+		{
+			Err(Error::ResumeWindowExpired)
+		}
+		Update::ResumeWindowExpired { .. } => Ok(None),
 	}
 }
 
@@ -935,9 +1492,9 @@ async fn handle_message(
 				chat.send(update).await?
 			}
 		}
-		Message::Version { version } =>
+		Message::Version { version, metadata } =>
 		{
-			greet_client(client, version)?;
+			greet_client(client, version, metadata)?;
 
 			// We want to know if someone is peeking.
 			client.appears_active_according_to_notifications = true;
@@ -954,7 +1511,9 @@ async fn handle_message(
 		}
 		Message::JoinServer { .. } if client.closing =>
 		{
-			client.sendbuffer.try_send(Message::Closing)?;
+			client
+				.sendbuffer
+				.try_send(Message::Closing { reason: None })?;
 		}
 		Message::JoinServer {
 			status: None,
@@ -977,6 +1536,7 @@ async fn handle_message(
 					token,
 					account_identifier,
 					metadata,
+					is_local: client.address == Address::Local,
 				};
 				joining_server(client, request)?;
 			}
@@ -984,12 +1544,13 @@ async fn handle_message(
 		Message::JoinServer { .. } =>
 		{
 			warn!("Invalid message from client: {:?}", message);
-			return Err(Error::Invalid);
+			reject_invalid(client, &message)?;
 		}
 		Message::LeaveServer { content: _ } => match client.general_chat.take()
 		{
 			Some(mut general_chat) =>
 			{
+				crate::common::log::set_lobby_id(None);
 				match client.lobby.take()
 				{
 					Some(ref mut lobby) =>
@@ -1027,6 +1588,11 @@ async fn handle_message(
 				info!("User '{}' left. (v{})", client.username, client.version,);
 				client.appears_active_according_to_notifications = false;
 
+				if let Some(token) = client.resume_token.take()
+				{
+					client.sessions.unregister(&token);
+				}
+
 				if !client.closing
 				{
 					client.general_chat_reserve = Some(general_chat);
@@ -1058,11 +1624,18 @@ async fn handle_message(
 		Message::JoinLobby { .. } if client.is_bot() =>
 		{
 			debug!("Invalid message from bot: {:?}", message);
-			return Err(Error::Invalid);
+			reject(
+				client,
+				ErrorCode::NotAllowedForBots,
+				Some(message.type_name()),
+				None,
+			)?;
 		}
 		Message::JoinLobby { .. } if client.closing =>
 		{
-			client.sendbuffer.try_send(Message::Closing)?;
+			client
+				.sendbuffer
+				.try_send(Message::Closing { reason: None })?;
 		}
 		Message::JoinLobby {
 			lobby_id: Some(lobby_id),
@@ -1094,13 +1667,14 @@ async fn handle_message(
 		Message::JoinLobby { .. } =>
 		{
 			warn!("Invalid message from client: {:?}", message);
-			return Err(Error::Invalid);
+			reject_invalid(client, &message)?;
 		}
 		Message::LeaveLobby {
 			lobby_id: None,
 			username: None,
 		} =>
 		{
+			crate::common::log::set_lobby_id(None);
 			if let Some(ref mut lobby) = client.lobby.take()
 			{
 				let general_chat = match &client.general_chat
@@ -1127,16 +1701,23 @@ async fn handle_message(
 		Message::LeaveLobby { .. } =>
 		{
 			warn!("Invalid message from client: {:?}", message);
-			return Err(Error::Invalid);
+			reject_invalid(client, &message)?;
 		}
 		Message::MakeLobby { .. } if client.is_bot() =>
 		{
 			debug!("Invalid message from bot: {:?}", message);
-			return Err(Error::Invalid);
+			reject(
+				client,
+				ErrorCode::NotAllowedForBots,
+				Some(message.type_name()),
+				None,
+			)?;
 		}
 		Message::MakeLobby { .. } if client.closing =>
 		{
-			client.sendbuffer.try_send(Message::Closing)?;
+			client
+				.sendbuffer
+				.try_send(Message::Closing { reason: None })?;
 		}
 		Message::MakeLobby { .. } if client.lobby.is_some() =>
 		{
@@ -1167,6 +1748,8 @@ async fn handle_message(
 					client.rating_database.clone(),
 					client.discord_api.clone(),
 					client.canary_for_lobbies.clone(),
+					client.server_state_for_lobbies.clone(),
+					client.drain_deadline,
 				);
 
 				let client_user_id = match client.user_id
@@ -1198,7 +1781,9 @@ async fn handle_message(
 		},
 		Message::SaveLobby {} if client.closing =>
 		{
-			client.sendbuffer.try_send(Message::Closing)?;
+			client
+				.sendbuffer
+				.try_send(Message::Closing { reason: None })?;
 		}
 		Message::SaveLobby {} => match client.lobby
 		{
@@ -1325,7 +1910,7 @@ async fn handle_message(
 		Message::ClaimHost { .. } =>
 		{
 			warn!("Invalid message from client: {:?}", message);
-			return Err(Error::Invalid);
+			reject_invalid(client, &message)?;
 		}
 		Message::ClaimRole { username, role } => match client.lobby
 		{
@@ -1605,7 +2190,7 @@ async fn handle_message(
 		Message::ListRuleset { .. } =>
 		{
 			warn!("Invalid message from client: {:?}", message);
-			return Err(Error::Invalid);
+			reject_invalid(client, &message)?;
 		}
 		Message::ListAi {
 			ai_name,
@@ -1623,14 +2208,33 @@ async fn handle_message(
 					})
 				{
 					error!("Invalid AI name from bot");
-					return Err(Error::Invalid);
+					let detail = "AI names must be 3 to 16 characters long \
+					              and only contain letters, digits, '_', '.' \
+					              or '-'";
+					let message_type = Some("list_ai");
+					reject(
+						client,
+						ErrorCode::InvalidBotMetadata,
+						message_type,
+						Some(detail),
+					)?;
+					return Ok(None);
 				}
 				else if authors.len() < 3
 					|| authors.len() > 30
 					|| authors.chars().any(|x| x.is_control())
 				{
 					error!("Invalid 'authors' metadata from bot");
-					return Err(Error::Invalid);
+					let detail = "Authors must be 3 to 30 characters long \
+					              and not contain control characters";
+					let message_type = Some("list_ai");
+					reject(
+						client,
+						ErrorCode::InvalidBotMetadata,
+						message_type,
+						Some(detail),
+					)?;
+					return Ok(None);
 				}
 
 				let user_id = match client.user_id
@@ -1656,7 +2260,8 @@ async fn handle_message(
 			None =>
 			{
 				error!("Invalid message from offline bot");
-				return Err(Error::Invalid);
+				let message_type = Some("list_ai");
+				reject(client, ErrorCode::NotOnline, message_type, None)?;
 			}
 		},
 		Message::ListAi {
@@ -1674,7 +2279,8 @@ async fn handle_message(
 			None =>
 			{
 				error!("Invalid message from offline bot");
-				return Err(Error::Invalid);
+				let message_type = Some("list_ai");
+				reject(client, ErrorCode::NotOnline, message_type, None)?;
 			}
 		},
 		Message::ListAi {
@@ -1698,7 +2304,7 @@ async fn handle_message(
 		Message::ListAi { .. } =>
 		{
 			warn!("Invalid message from client: {:?}", message);
-			return Err(Error::Invalid);
+			reject_invalid(client, &message)?;
 		}
 		Message::AddBot { slot: None } => match client.lobby
 		{
@@ -1727,7 +2333,7 @@ async fn handle_message(
 		Message::AddBot { .. } =>
 		{
 			warn!("Invalid message from client: {:?}", message);
-			return Err(Error::Invalid);
+			reject_invalid(client, &message)?;
 		}
 		Message::RemoveBot { slot } => match client.lobby
 		{
@@ -1870,7 +2476,7 @@ async fn handle_message(
 		Message::Resign { .. } =>
 		{
 			warn!("Invalid message from client: {:?}", message);
-			return Err(Error::Invalid);
+			reject_invalid(client, &message)?;
 		}
 		Message::HostSync { metadata } => match client.lobby
 		{
@@ -1930,7 +2536,7 @@ async fn handle_message(
 		Message::Changes { .. } =>
 		{
 			warn!("Invalid message from client: {:?}", message);
-			return Err(Error::Invalid);
+			reject_invalid(client, &message)?;
 		}
 		Message::Orders {
 			orders,
@@ -1973,7 +2579,7 @@ async fn handle_message(
 		Message::Orders { .. } =>
 		{
 			warn!("Invalid message from client: {:?}", message);
-			return Err(Error::Invalid);
+			reject_invalid(client, &message)?;
 		}
 		Message::Sync {
 			time_remaining_in_seconds: None,
@@ -1994,7 +2600,7 @@ async fn handle_message(
 		Message::Sync { .. } =>
 		{
 			warn!("Invalid message from client: {:?}", message);
-			return Err(Error::Invalid);
+			reject_invalid(client, &message)?;
 		}
 		Message::Briefing { briefing } => match client.lobby
 		{
@@ -2076,7 +2682,7 @@ async fn handle_message(
 		Message::Chat { .. } =>
 		{
 			warn!("Invalid message from client: {:?}", message);
-			return Err(Error::Invalid);
+			reject_invalid(client, &message)?;
 		}
 		Message::Debug { content } =>
 		{
@@ -2098,25 +2704,167 @@ async fn handle_message(
 		| Message::RatingAndStars { .. }
 		| Message::UpdatedRating { .. }
 		| Message::RecentStars { .. }
-		| Message::Closing
-		| Message::Closed =>
+		| Message::Closing { .. }
+		| Message::Closed
+		| Message::Error { .. }
+		| Message::ResumeToken { .. } =>
 		{
 			warn!("Invalid message from client: {:?}", message);
-			return Err(Error::Invalid);
+			reject(
+				client,
+				ErrorCode::UnexpectedMessage,
+				Some(message.type_name()),
+				None,
+			)?;
 		}
 	}
 
 	Ok(None)
 }
 
-fn greet_client(client: &mut Client, version: Version) -> Result<(), Error>
+// A client that keeps sending invalid messages is probably stuck in a loop,
+// so at some point we stop explaining ourselves and disconnect it.
+const MAX_REJECTED_MESSAGES: usize = 50;
+
+async fn join_lobby(
+	client: &mut Client,
+	mut lobby_sendbuffer: mpsc::Sender<lobby::Update>,
+	general_chat: mpsc::Sender<chat::Update>,
+	invite: Option<lobby::Invite>,
+) -> Result<(), Error>
+{
+	let client_user_id = match client.user_id
+	{
+		Some(user_id) => user_id,
+		None =>
+		{
+			error!("Expected user_id");
+			return Err(Error::Unexpected);
+		}
+	};
+	let update = lobby::Update::Join {
+		client_id: client.id,
+		client_user_id,
+		client_username: client.username.clone(),
+		client_handle: client.handle.clone(),
+		lobby_sendbuffer: lobby_sendbuffer.clone(),
+		general_chat,
+		desired_metadata: None,
+		invite,
+	};
+	lobby_sendbuffer.send(update).await?;
+	Ok(())
+}
+
+fn announce_shutdown(
+	client: &mut Client,
+	remaining: Duration,
+) -> Result<(), Error>
+{
+	// Clients that have not joined the server cannot receive chat messages.
+	if client.general_chat.is_none() || client.is_bot()
+	{
+		return Ok(());
+	}
+
+	let content = format!(
+		"The server is shutting down. Unfinished games will be ended in {}.",
+		drain::describe(remaining)
+	);
+	client.sendbuffer.try_send(Message::Chat {
+		content: content.clone(),
+		sender: Some("server".to_string()),
+		target: ChatTarget::General,
+	})?;
+	if client.lobby.is_some()
+	{
+		client.sendbuffer.try_send(Message::Chat {
+			content,
+			sender: Some("server".to_string()),
+			target: ChatTarget::Lobby,
+		})?;
+	}
+	Ok(())
+}
+
+fn reject(
+	client: &mut Client,
+	code: ErrorCode,
+	message_type: Option<&str>,
+	detail: Option<&str>,
+) -> Result<(), Error>
+{
+	client.num_rejected_messages += 1;
+	if client.num_rejected_messages > MAX_REJECTED_MESSAGES
+	{
+		warn!("Client {} sent too many invalid messages.", client.id);
+		let reply = Message::Error {
+			code: ErrorCode::TooManyErrors,
+			metadata: ErrorMetadata::default(),
+		};
+		client.sendbuffer.try_send(reply)?;
+		return Err(Error::Invalid);
+	}
+
+	let reply = Message::Error {
+		code,
+		metadata: ErrorMetadata {
+			message_type: message_type.map(|x| x.to_string()),
+			detail: detail.map(|x| x.to_string()),
+		},
+	};
+	client.sendbuffer.try_send(reply)?;
+	Ok(())
+}
+
+fn reject_invalid(client: &mut Client, message: &Message) -> Result<(), Error>
+{
+	let message_type = Some(message.type_name());
+	reject(client, ErrorCode::InvalidMessage, message_type, None)
+}
+
+fn greet_client(
+	client: &mut Client,
+	version: Version,
+	metadata: VersionMetadata,
+) -> Result<(), Error>
 {
 	client.version = version;
 	info!("Client {} has version {}.", client.id, version);
 
+	let mut negotiated = codec::negotiate(&metadata, client.allows_compression);
+	let codec = codec::Codec::from(&negotiated);
+	if codec != codec::Codec::default()
+	{
+		debug!("Client {} will use {:?}.", client.id, codec);
+	}
+
+	// Only clients that ask for resume tokens know what to do with them.
+	if metadata.resumable && client.resume_grace.is_some()
+	{
+		client.is_resumable = true;
+		negotiated.resumable = true;
+	}
+
+	if metadata.capture && client.allows_capture
+	{
+		let mut recorder = capture::start(client.id);
+		// The handshake itself has already been received, but it is redacted
+		// and thus encoded anew, so its original data is not needed.
+		let handshake = Message::Version { version, metadata };
+		let direction = capture::Direction::In;
+		recorder.record(direction, &handshake, &[], codec::Codec::default());
+		client.capture.broadcast(Some(recorder))?;
+		negotiated.capture = true;
+	}
+
 	let myversion = Version::current();
-	let response = Message::Version { version: myversion };
+	let response = Message::Version {
+		version: myversion,
+		metadata: negotiated,
+	};
 	client.sendbuffer.try_send(response)?;
+	client.codec.broadcast(codec)?;
 
 	if version.major != myversion.major || version == Version::undefined()
 	{
@@ -2153,12 +2901,127 @@ fn greet_client(client: &mut Client, version: Version) -> Result<(), Error>
 	Ok(())
 }
 
+fn issue_resume_token(client: &mut Client) -> Result<(), Error>
+{
+	if !client.is_resumable
+	{
+		return Ok(());
+	}
+
+	if let Some(token) = client.resume_token.take()
+	{
+		client.sessions.unregister(&token);
+	}
+
+	let token = resume::generate_token();
+	client
+		.sessions
+		.register(token.clone(), client.reattach_callback.clone());
+	client.resume_token = Some(token.clone());
+	client.sendbuffer.try_send(Message::ResumeToken { token })?;
+	Ok(())
+}
+
+fn resume_session(
+	client: &mut Client,
+	reattachment: resume::Reattachment,
+) -> Result<(), Error>
+{
+	let resume::Reattachment {
+		reader,
+		writer,
+		allows_compression,
+		address,
+		version,
+		metadata,
+		ticket,
+	} = reattachment;
+
+	// Bans may have been issued while the client was away.
+	if let Address::Ip(ip) = address
+	{
+		if let Some(ban) = client.bans.find_ip(ip.ip())
+		{
+			info!("Client {} from {} is banned: {:?}", client.id, ip, ban);
+			metrics::REJECTED_CONNECTIONS.increment("ban");
+			send_refusal(writer, client.id, RefusalReason::IpBlocked);
+			return Ok(());
+		}
+	}
+
+	info!(
+		"Client {} resumed its session from {}. (v{})",
+		client.id, address, version
+	);
+	client.address = address;
+	// The new connection counts towards the limits of its own address.
+	client.ticket = ticket;
+	client.version = version;
+	client.allows_compression = allows_compression;
+	client.is_parked = false;
+	client.sendbuffer.set_parked(false);
+
+	let mut negotiated = codec::negotiate(&metadata, allows_compression);
+	negotiated.resumable = true;
+	negotiated.resumed = true;
+	client.codec.broadcast(codec::Codec::from(&negotiated))?;
+
+	// The send task replies to the handshake on the new connection before it
+	// sends anything else, including the messages that queued up meanwhile.
+	let greeting = Message::Version {
+		version: Version::current(),
+		metadata: negotiated,
+	};
+	let attachment = send::Reattachment {
+		socket: writer,
+		greeting,
+	};
+	if client.writers.try_send(attachment).is_err()
+		|| client.readers.try_send(reader).is_err()
+	{
+		error!("Failed to reattach client {}.", client.id);
+		return Err(Error::Unexpected);
+	}
+
+	client.last_receive_time.broadcast(())?;
+
+	// A ping sent while the client was away might never have arrived.
+	if client.pong_receive_time.is_some()
+	{
+		client.sendbuffer.try_send(Message::Ping)?;
+	}
+
+	// Each token can only be used once.
+	issue_resume_token(client)
+}
+
 fn joining_server(
 	client: &mut Client,
 	request: login::Request,
 ) -> Result<(), Error>
 {
-	info!("Client {} is logging in...", client.id);
+	info!(
+		"Client {} is logging in from {}...",
+		client.id, client.address
+	);
+
+	// Bans may have been issued after this client connected.
+	if let Address::Ip(address) = client.address
+	{
+		if let Some(ban) = client.bans.find_ip(address.ip())
+		{
+			info!("Client {} is banned: {:?}", client.id, ban);
+			metrics::LOGIN_FAILURES.increment("IpBlocked");
+			let message = Message::JoinServer {
+				status: Some(ResponseStatus::IpBlocked),
+				content: None,
+				sender: None,
+				metadata: Default::default(),
+			};
+			client.sendbuffer.try_send(message)?;
+			return Ok(());
+		}
+	}
 
 	match client.login.try_send(request)
 	{
@@ -0,0 +1,398 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use super::connection::Address;
+
+use crate::server::message::RefusalReason;
+use crate::server::metrics;
+use crate::server::settings::Settings;
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync;
+
+use tokio::time::{Duration, Instant};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+// Unset limits do not apply. Connections through the local socket count
+// towards the total, but not towards any limit per IP address.
+#[derive(Debug, Clone, Copy)]
+struct Limits
+{
+	max_connections: Option<usize>,
+	max_connections_per_ip: Option<usize>,
+	// The most connections a single IP address may open per minute.
+	max_connection_rate_per_ip: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection
+{
+	ServerFull,
+	IpConnectionLimit,
+	IpRateLimit,
+}
+
+impl Rejection
+{
+	pub fn label(&self) -> &'static str
+	{
+		match self
+		{
+			Rejection::ServerFull => "max_connections",
+			Rejection::IpConnectionLimit => "max_connections_per_ip",
+			Rejection::IpRateLimit => "max_connection_rate_per_ip",
+		}
+	}
+}
+
+impl From<Rejection> for RefusalReason
+{
+	fn from(rejection: Rejection) -> RefusalReason
+	{
+		match rejection
+		{
+			Rejection::ServerFull => RefusalReason::ServerFull,
+			Rejection::IpConnectionLimit => RefusalReason::IpConnectionLimit,
+			Rejection::IpRateLimit => RefusalReason::IpRateLimit,
+		}
+	}
+}
+
+// The connections that are currently open, by IP address.
+#[derive(Debug, Clone)]
+pub struct Admissions
+{
+	limits: Limits,
+	shared: sync::Arc<sync::Mutex<Tally>>,
+}
+
+#[derive(Debug, Default)]
+struct Tally
+{
+	total: usize,
+	sources: HashMap<IpAddr, Source>,
+	last_pruned: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct Source
+{
+	connections: usize,
+	recent: VecDeque<Instant>,
+}
+
+// Held for as long as an admitted connection is open.
+#[derive(Debug)]
+pub struct Ticket
+{
+	shared: sync::Arc<sync::Mutex<Tally>>,
+	source: Option<IpAddr>,
+}
+
+impl Admissions
+{
+	pub fn from_settings(settings: &Settings) -> Admissions
+	{
+		let limits = Limits {
+			max_connections: settings.max_connections,
+			max_connections_per_ip: settings.max_connections_per_ip,
+			max_connection_rate_per_ip: settings.max_connection_rate_per_ip,
+		};
+		Admissions {
+			limits,
+			shared: Default::default(),
+		}
+	}
+
+	pub fn admit(&self, address: Address) -> Result<Ticket, Rejection>
+	{
+		let source = match address
+		{
+			Address::Ip(address) => Some(source(address.ip())),
+			Address::Local => None,
+		};
+		self.admit_source(source)
+	}
+
+	// Behind a proxy, the address of a client is only known after the
+	// handshake, so until then its connection only counts towards the total.
+	pub fn admit_unattributed(&self) -> Result<Ticket, Rejection>
+	{
+		self.admit_source(None)
+	}
+
+	pub fn attribute(
+		&self,
+		ticket: &mut Ticket,
+		address: Address,
+	) -> Result<(), Rejection>
+	{
+		let ip = match address
+		{
+			Address::Ip(address) => source(address.ip()),
+			Address::Local => return Ok(()),
+		};
+		let result =
+			lock(&self.shared).attribute(&self.limits, ip, Instant::now());
+		match result
+		{
+			Ok(()) =>
+			{
+				ticket.source = Some(ip);
+				Ok(())
+			}
+			Err(rejection) =>
+			{
+				metrics::REJECTED_CONNECTIONS.increment(rejection.label());
+				Err(rejection)
+			}
+		}
+	}
+
+	fn admit_source(&self, source: Option<IpAddr>)
+		-> Result<Ticket, Rejection>
+	{
+		let result =
+			lock(&self.shared).admit(&self.limits, source, Instant::now());
+		match result
+		{
+			Ok(()) => Ok(Ticket {
+				shared: self.shared.clone(),
+				source,
+			}),
+			Err(rejection) =>
+			{
+				metrics::REJECTED_CONNECTIONS.increment(rejection.label());
+				Err(rejection)
+			}
+		}
+	}
+}
+
+impl Tally
+{
+	fn admit(
+		&mut self,
+		limits: &Limits,
+		source: Option<IpAddr>,
+		now: Instant,
+	) -> Result<(), Rejection>
+	{
+		if self.last_pruned.is_none_or(|t| now >= t + RATE_WINDOW)
+		{
+			self.prune(now);
+			self.last_pruned = Some(now);
+		}
+
+		if limits.max_connections.is_some_and(|max| self.total >= max)
+		{
+			return Err(Rejection::ServerFull);
+		}
+
+		if let Some(ip) = source
+		{
+			self.attribute(limits, ip, now)?;
+		}
+
+		self.total += 1;
+		Ok(())
+	}
+
+	fn attribute(
+		&mut self,
+		limits: &Limits,
+		ip: IpAddr,
+		now: Instant,
+	) -> Result<(), Rejection>
+	{
+		let source = self.sources.entry(ip).or_default();
+		while source
+			.recent
+			.front()
+			.is_some_and(|&t| now >= t + RATE_WINDOW)
+		{
+			source.recent.pop_front();
+		}
+
+		if limits
+			.max_connections_per_ip
+			.is_some_and(|max| source.connections >= max)
+		{
+			return Err(Rejection::IpConnectionLimit);
+		}
+		if limits
+			.max_connection_rate_per_ip
+			.is_some_and(|max| source.recent.len() >= max)
+		{
+			return Err(Rejection::IpRateLimit);
+		}
+
+		source.connections += 1;
+		source.recent.push_back(now);
+		Ok(())
+	}
+
+	fn release(&mut self, source: Option<IpAddr>)
+	{
+		self.total = self.total.saturating_sub(1);
+		if let Some(ip) = source
+		{
+			if let Some(source) = self.sources.get_mut(&ip)
+			{
+				source.connections = source.connections.saturating_sub(1);
+			}
+		}
+	}
+
+	// Forgets addresses that have no open connections and no recent ones.
+	fn prune(&mut self, now: Instant)
+	{
+		self.sources.retain(|_, source| {
+			source.connections > 0
+				|| source.recent.back().is_some_and(|&t| now < t + RATE_WINDOW)
+		});
+	}
+}
+
+impl Drop for Ticket
+{
+	fn drop(&mut self)
+	{
+		lock(&self.shared).release(self.source);
+	}
+}
+
+// An IPv6 client can easily use any address in its /64 subnet, so we limit
+// those subnets rather than individual addresses.
+fn source(ip: IpAddr) -> IpAddr
+{
+	match ip
+	{
+		IpAddr::V4(_) => ip,
+		IpAddr::V6(ip) => match ip.to_ipv4_mapped()
+		{
+			Some(ip) => IpAddr::V4(ip),
+			None =>
+			{
+				let [a, b, c, d, ..] = ip.segments();
+				IpAddr::V6(Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0))
+			}
+		},
+	}
+}
+
+fn lock(shared: &sync::Mutex<Tally>) -> sync::MutexGuard<'_, Tally>
+{
+	match shared.lock()
+	{
+		Ok(guard) => guard,
+		Err(poisoned) => poisoned.into_inner(),
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn ip(address: &str) -> Option<IpAddr>
+	{
+		Some(source(address.parse().unwrap()))
+	}
+
+	#[test]
+	fn test_limits_per_ip()
+	{
+		let limits = Limits {
+			max_connections: Some(3),
+			max_connections_per_ip: Some(2),
+			max_connection_rate_per_ip: None,
+		};
+		let now = Instant::now();
+		let mut tally = Tally::default();
+		assert_eq!(tally.admit(&limits, ip("10.0.0.1"), now), Ok(()));
+		assert_eq!(tally.admit(&limits, ip("10.0.0.1"), now), Ok(()));
+		assert_eq!(
+			tally.admit(&limits, ip("10.0.0.1"), now),
+			Err(Rejection::IpConnectionLimit)
+		);
+		assert_eq!(tally.admit(&limits, None, now), Ok(()));
+		assert_eq!(
+			tally.admit(&limits, ip("10.0.0.2"), now),
+			Err(Rejection::ServerFull)
+		);
+		tally.release(ip("10.0.0.1"));
+		assert_eq!(tally.admit(&limits, ip("10.0.0.2"), now), Ok(()));
+	}
+
+	#[test]
+	fn test_rate_per_ip()
+	{
+		let limits = Limits {
+			max_connections: None,
+			max_connections_per_ip: None,
+			max_connection_rate_per_ip: Some(2),
+		};
+		let now = Instant::now();
+		let mut tally = Tally::default();
+		assert_eq!(tally.admit(&limits, ip("2001:db8::1"), now), Ok(()));
+		tally.release(ip("2001:db8::1"));
+		assert_eq!(tally.admit(&limits, ip("2001:db8::2"), now), Ok(()));
+		tally.release(ip("2001:db8::2"));
+		assert_eq!(
+			tally.admit(&limits, ip("2001:db8::3"), now),
+			Err(Rejection::IpRateLimit)
+		);
+		assert_eq!(tally.admit(&limits, ip("2001:db9::1"), now), Ok(()));
+		let later = now + RATE_WINDOW;
+		assert_eq!(tally.admit(&limits, ip("2001:db8::3"), later), Ok(()));
+	}
+
+	#[test]
+	fn test_attribute_after_handshake()
+	{
+		let limits = Limits {
+			max_connections: Some(2),
+			max_connections_per_ip: Some(1),
+			max_connection_rate_per_ip: None,
+		};
+		let now = Instant::now();
+		let mut tally = Tally::default();
+		assert_eq!(tally.admit(&limits, None, now), Ok(()));
+		assert_eq!(
+			tally.attribute(&limits, ip("10.0.0.1").unwrap(), now),
+			Ok(())
+		);
+		assert_eq!(tally.admit(&limits, None, now), Ok(()));
+		assert_eq!(
+			tally.attribute(&limits, ip("10.0.0.1").unwrap(), now),
+			Err(Rejection::IpConnectionLimit)
+		);
+		tally.release(None);
+		assert_eq!(tally.admit(&limits, None, now), Ok(()));
+		assert_eq!(tally.admit(&limits, None, now), Err(Rejection::ServerFull));
+	}
+}
@@ -0,0 +1,331 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use super::codec;
+use super::codec::Codec;
+use super::compression;
+use super::limit::MESSAGE_SIZE_LIMIT;
+
+use crate::common::keycode::Keycode;
+use crate::server::message::*;
+
+use log::*;
+
+use serde_derive::{Deserialize, Serialize};
+
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction
+{
+	In,
+	Out,
+}
+
+// Each line of a capture file contains one entry. The frame holds the data
+// of the message in hexadecimal, exactly as it went over the wire unless it
+// had to be redacted, and the codec tells how to decode it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry
+{
+	pub time: String,
+	pub milliseconds: u64,
+	pub direction: Direction,
+	pub codec: Codec,
+	pub frame: String,
+}
+
+impl Entry
+{
+	pub fn message(&self) -> Result<Message, Error>
+	{
+		let data = from_hex(&self.frame).ok_or(Error::Hex)?;
+		let data = match self.codec.compression
+		{
+			Some(compression) =>
+			{
+				compression::decompress(compression, &data, MESSAGE_SIZE_LIMIT)
+					.map_err(Error::Decompression)?
+			}
+			None => data,
+		};
+		let message = codec::deserialize(&data, self.codec.encoding)?;
+		Ok(message)
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct Recorder
+{
+	start: Instant,
+	sendbuffer: mpsc::Sender<Entry>,
+}
+
+impl Recorder
+{
+	pub fn record(
+		&mut self,
+		direction: Direction,
+		message: &Message,
+		data: &[u8],
+		codec: Codec,
+	)
+	{
+		let (frame, codec) = match redact(direction, message)
+		{
+			Some(redacted) =>
+			{
+				match codec::serialize(&redacted, codec.encoding)
+				{
+					Ok(data) => (
+						to_hex(&data),
+						Codec {
+							encoding: codec.encoding,
+							compression: None,
+						},
+					),
+					Err(error) =>
+					{
+						warn!("Failed to capture message: {}", error);
+						return;
+					}
+				}
+			}
+			None => (to_hex(data), codec),
+		};
+		let entry = Entry {
+			time: chrono::Local::now()
+				.format("%Y-%m-%d %H:%M:%S.%3f")
+				.to_string(),
+			milliseconds: self.start.elapsed().as_millis() as u64,
+			direction,
+			codec,
+			frame,
+		};
+		// Capturing is a debugging aid, so we would rather drop entries
+		// than slow down the client if the disk cannot keep up.
+		match self.sendbuffer.try_send(entry)
+		{
+			Ok(()) => (),
+			Err(error) => warn!("Failed to capture message: {}", error),
+		}
+	}
+}
+
+pub fn filename(client_id: Keycode) -> String
+{
+	let now = chrono::Local::now().format("%Y%m%d-%H%M%S");
+	format!("logs/capture-{}-{}.jsonl", now, client_id)
+}
+
+pub fn start(client_id: Keycode) -> Recorder
+{
+	let filename = filename(client_id);
+	info!(
+		"Capturing traffic of client {} to {}...",
+		client_id, filename
+	);
+
+	let (sendbuffer, receiver) = mpsc::channel::<Entry>(1000);
+	tokio::spawn(async move {
+		match run(&filename, receiver).await
+		{
+			Ok(()) => debug!("Finished writing {}.", filename),
+			Err(error) => error!("Failed to write {}: {}", filename, error),
+		}
+	});
+
+	Recorder {
+		start: Instant::now(),
+		sendbuffer,
+	}
+}
+
+async fn run(
+	filename: &str,
+	mut entries: mpsc::Receiver<Entry>,
+) -> Result<(), std::io::Error>
+{
+	let mut file = File::create(filename).await?;
+
+	while let Some(entry) = entries.recv().await
+	{
+		let mut line = serde_json::to_vec(&entry)?;
+		line.push(b'\n');
+		file.write_all(&line).await?;
+	}
+
+	file.flush().await?;
+	Ok(())
+}
+
+// Login and resume tokens should not end up in a file that is passed around
+// between developers. The replay tool fills in its own token instead.
+fn redact(direction: Direction, message: &Message) -> Option<Message>
+{
+	match (direction, message)
+	{
+		(Direction::In, Message::Version { version, metadata }) =>
+		{
+			Some(Message::Version {
+				version: *version,
+				metadata: VersionMetadata {
+					resume_token: None,
+					..metadata.clone()
+				},
+			})
+		}
+		(Direction::Out, Message::ResumeToken { .. }) =>
+		{
+			Some(Message::ResumeToken {
+				token: String::new(),
+			})
+		}
+		(
+			Direction::In,
+			Message::JoinServer {
+				status,
+				content: _,
+				sender,
+				metadata,
+			},
+		) =>
+		{
+			let metadata = match metadata
+			{
+				JoinMetadataOrTagMetadata::JoinMetadata(metadata) =>
+				{
+					JoinMetadataOrTagMetadata::JoinMetadata(JoinMetadata {
+						desired_username: metadata.desired_username.clone(),
+						merge_token: None,
+					})
+				}
+				JoinMetadataOrTagMetadata::TagMetadata(_) => metadata.clone(),
+			};
+			Some(Message::JoinServer {
+				status: *status,
+				content: None,
+				sender: sender.clone(),
+				metadata,
+			})
+		}
+		_ => None,
+	}
+}
+
+fn to_hex(data: &[u8]) -> String
+{
+	const DIGITS: &[u8; 16] = b"0123456789abcdef";
+	let mut hex = String::with_capacity(2 * data.len());
+	for &x in data
+	{
+		hex.push(DIGITS[(x >> 4) as usize] as char);
+		hex.push(DIGITS[(x & 0xF) as usize] as char);
+	}
+	hex
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>>
+{
+	hex.as_bytes()
+		.chunks(2)
+		.map(|pair| {
+			let pair =
+				std::str::from_utf8(pair).ok().filter(|x| x.len() == 2)?;
+			u8::from_str_radix(pair, 16).ok()
+		})
+		.collect()
+}
+
+#[derive(Debug)]
+pub enum Error
+{
+	Hex,
+	Decompression(std::io::Error),
+	Codec(codec::Error),
+}
+
+impl From<codec::Error> for Error
+{
+	fn from(error: codec::Error) -> Self
+	{
+		Error::Codec(error)
+	}
+}
+
+impl std::fmt::Display for Error
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		match self
+		{
+			Error::Hex => write!(f, "Frame is not hexadecimal."),
+			Error::Decompression(error) =>
+			{
+				write!(f, "Failed to decompress frame: {}", error)
+			}
+			Error::Codec(error) => error.fmt(f),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn test_entry_message() -> Result<(), Error>
+	{
+		let message = Message::Chat {
+			content: "Hello".repeat(100),
+			sender: None,
+			target: ChatTarget::General,
+		};
+		let data = codec::serialize(&message, Encoding::MessagePack)?;
+		let data = compression::compress(Compression::Zstd, &data)
+			.map_err(Error::Decompression)?;
+		let entry = Entry {
+			time: String::new(),
+			milliseconds: 0,
+			direction: Direction::In,
+			codec: Codec {
+				encoding: Encoding::MessagePack,
+				compression: Some(Compression::Zstd),
+			},
+			frame: to_hex(&data),
+		};
+		match entry.message()?
+		{
+			Message::Chat { content, .. } => assert_eq!(content.len(), 500),
+			message => panic!("unexpected {:?}", message),
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,734 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use super::compression;
+
+use crate::server::message::*;
+
+use serde_derive::{Deserialize, Serialize};
+
+pub const SUPPORTED_ENCODINGS: [Encoding; 2] =
+	[Encoding::MessagePack, Encoding::Json];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Codec
+{
+	pub encoding: Encoding,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub compression: Option<Compression>,
+}
+
+impl Default for Codec
+{
+	fn default() -> Codec
+	{
+		Codec {
+			encoding: Encoding::Json,
+			compression: None,
+		}
+	}
+}
+
+impl From<&VersionMetadata> for Codec
+{
+	fn from(metadata: &VersionMetadata) -> Codec
+	{
+		Codec {
+			encoding: metadata.encoding.unwrap_or(Encoding::Json),
+			compression: metadata.compression,
+		}
+	}
+}
+
+pub fn negotiate(
+	offer: &VersionMetadata,
+	allows_compression: bool,
+) -> VersionMetadata
+{
+	let compression = if allows_compression
+	{
+		compression::negotiate(&offer.supported_compressions)
+	}
+	else
+	{
+		None
+	};
+
+	let encoding = offer
+		.supported_encodings
+		.iter()
+		.find(|x| SUPPORTED_ENCODINGS.contains(x))
+		.copied();
+
+	VersionMetadata {
+		compression,
+		encoding,
+		..Default::default()
+	}
+}
+
+pub fn serialize(
+	message: &Message,
+	encoding: Encoding,
+) -> Result<Vec<u8>, Error>
+{
+	match encoding
+	{
+		Encoding::Json => Ok(serde_json::to_vec(message)?),
+		Encoding::MessagePack => Ok(rmp_serde::to_vec_named(message)?),
+		Encoding::Unsupported => Err(Error::Unsupported),
+	}
+}
+
+pub fn deserialize(data: &[u8], encoding: Encoding) -> Result<Message, Error>
+{
+	match encoding
+	{
+		Encoding::Json =>
+		{
+			let jsonstr = std::str::from_utf8(data)?;
+			Ok(serde_json::from_str(jsonstr)?)
+		}
+		Encoding::MessagePack => Ok(rmp_serde::from_slice(data)?),
+		Encoding::Unsupported => Err(Error::Unsupported),
+	}
+}
+
+// Used for trace logging, which should be readable regardless of encoding.
+pub fn preview(data: &[u8], encoding: Encoding) -> String
+{
+	match encoding
+	{
+		Encoding::Json => String::from_utf8_lossy(data).into_owned(),
+		Encoding::MessagePack =>
+		{
+			match rmp_serde::from_slice::<serde_json::Value>(data)
+			{
+				Ok(value) => value.to_string(),
+				Err(error) => format!("<{}>", error),
+			}
+		}
+		Encoding::Unsupported => String::new(),
+	}
+}
+
+#[derive(Debug)]
+pub enum Error
+{
+	Unsupported,
+	Utf8(std::str::Utf8Error),
+	Json(serde_json::Error),
+	MessagePackEncode(rmp_serde::encode::Error),
+	MessagePackDecode(rmp_serde::decode::Error),
+}
+
+impl From<std::str::Utf8Error> for Error
+{
+	fn from(error: std::str::Utf8Error) -> Self
+	{
+		Error::Utf8(error)
+	}
+}
+
+impl From<serde_json::Error> for Error
+{
+	fn from(error: serde_json::Error) -> Self
+	{
+		Error::Json(error)
+	}
+}
+
+impl From<rmp_serde::encode::Error> for Error
+{
+	fn from(error: rmp_serde::encode::Error) -> Self
+	{
+		Error::MessagePackEncode(error)
+	}
+}
+
+impl From<rmp_serde::decode::Error> for Error
+{
+	fn from(error: rmp_serde::decode::Error) -> Self
+	{
+		Error::MessagePackDecode(error)
+	}
+}
+
+impl std::fmt::Display for Error
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		match self
+		{
+			Error::Unsupported => write!(f, "Unsupported encoding."),
+			Error::Utf8(error) => error.fmt(f),
+			Error::Json(error) => error.fmt(f),
+			Error::MessagePackEncode(error) => error.fmt(f),
+			Error::MessagePackDecode(error) => error.fmt(f),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	use crate::common::keycode::keycode;
+	use crate::common::version::Version;
+	use crate::logic::difficulty::Difficulty;
+	use crate::logic::player::PlayerColor;
+	use crate::server::botslot::{Botslot, EmptyBotslot};
+	use crate::server::lobby;
+	use crate::server::lobby::LobbyType;
+
+	use serde_json::json;
+
+	// This match has no wildcard, so it will fail to compile when a variant
+	// is added, and NUM_VARIANTS must be updated alongside the examples.
+	const NUM_VARIANTS: usize = 64;
+
+	fn variant_index(message: &Message) -> usize
+	{
+		match message
+		{
+			Message::Pulse => 0,
+			Message::Ping => 1,
+			Message::Pong => 2,
+			Message::Version { .. } => 3,
+			Message::JoinServer { .. } => 4,
+			Message::LeaveServer { .. } => 5,
+			Message::JoinLobby { .. } => 6,
+			Message::LeaveLobby { .. } => 7,
+			Message::MakeLobby { .. } => 8,
+			Message::SaveLobby => 9,
+			Message::DisbandLobby { .. } => 10,
+			Message::LockLobby => 11,
+			Message::UnlockLobby => 12,
+			Message::NameLobby { .. } => 13,
+			Message::ListLobby { .. } => 14,
+			Message::ClaimHost { .. } => 15,
+			Message::ClaimRole { .. } => 16,
+			Message::ClaimColor { .. } => 17,
+			Message::ClaimVisionType { .. } => 18,
+			Message::ClaimAi { .. } => 19,
+			Message::ClaimDifficulty { .. } => 20,
+			Message::PickMap { .. } => 21,
+			Message::PickTimer { .. } => 22,
+			Message::PickChallenge { .. } => 23,
+			Message::PickRuleset { .. } => 24,
+			Message::AddBot { .. } => 25,
+			Message::RemoveBot { .. } => 26,
+			Message::ListChallenge { .. } => 27,
+			Message::ListAi { .. } => 28,
+			Message::ListMap { .. } => 29,
+			Message::ListRuleset { .. } => 30,
+			Message::EnableCustomMaps => 31,
+			Message::AssignColor { .. } => 32,
+			Message::RulesetRequest { .. } => 33,
+			Message::RulesetData { .. } => 34,
+			Message::RulesetUnknown { .. } => 35,
+			Message::Secrets { .. } => 36,
+			Message::Skins { .. } => 37,
+			Message::InGame { .. } => 38,
+			Message::Start => 39,
+			Message::Game { .. } => 40,
+			Message::Tutorial { .. } => 41,
+			Message::Challenge => 42,
+			Message::Briefing { .. } => 43,
+			Message::ReplayWithAnimations { .. } => 44,
+			Message::Resign { .. } => 45,
+			Message::HostSync { .. } => 46,
+			Message::HostRejoinRequest { .. } => 47,
+			Message::HostRejoinChanges { .. } => 48,
+			Message::Changes { .. } => 49,
+			Message::Orders { .. } => 50,
+			Message::Sync { .. } => 51,
+			Message::Init => 52,
+			Message::RatingAndStars { .. } => 53,
+			Message::UpdatedRating { .. } => 54,
+			Message::RecentStars { .. } => 55,
+			Message::Closing { .. } => 56,
+			Message::Closed => 57,
+			Message::Quit => 58,
+			Message::Chat { .. } => 59,
+			Message::LinkAccounts { .. } => 60,
+			Message::Debug { .. } => 61,
+			Message::Error { .. } => 62,
+			Message::ResumeToken { .. } => 63,
+		}
+	}
+
+	fn examples() -> Result<Vec<Message>, serde_json::Error>
+	{
+		let lobby_id = keycode(123, 456);
+		let client_id = keycode(789, 1011);
+		let slot: Botslot = crate::server::botslot::pool()[1];
+		let secrets = lobby::Secrets::create(
+			lobby_id,
+			client_id,
+			lobby::Salts::generate(),
+		);
+		let map_metadata: crate::logic::map::Metadata =
+			serde_json::from_value(json!({
+				"playercount": 2,
+				"rows": 10,
+				"cols": 12,
+				"pool": "multiplayer",
+				"ruleset": "v1.0.0",
+				"author": "Sander",
+			}))?;
+		let changes = vec![
+			serde_json::from_value(json!({"type": "funds", "value": 20}))?,
+			serde_json::from_value(json!({
+				"type": "move",
+				"subject": {"row": 3, "col": -1},
+				"target": {"row": 4, "col": 1},
+				"unit": {"type": "militia", "stacks": 3},
+			}))?,
+		];
+		let orders = vec![serde_json::from_value(json!({
+			"type": "move",
+			"subject": {"row": 3, "col": 4},
+			"moves": ["e", "s"],
+		}))?];
+
+		let messages = vec![
+			Message::Pulse,
+			Message::Ping,
+			Message::Pong,
+			Message::Version {
+				version: Version::current(),
+				metadata: Default::default(),
+			},
+			Message::Version {
+				version: Version::exact(1, 2, 3, 4),
+				metadata: VersionMetadata {
+					supported_compressions: vec![
+						Compression::Zstd,
+						Compression::Zlib,
+					],
+					compression: Some(Compression::Zlib),
+					supported_encodings: vec![
+						Encoding::MessagePack,
+						Encoding::Json,
+					],
+					encoding: Some(Encoding::MessagePack),
+					capture: true,
+					resumable: true,
+					resume_token: Some("token".to_string()),
+					resumed: true,
+				},
+			},
+			Message::JoinServer {
+				status: None,
+				content: Some("token".to_string()),
+				sender: Some("12345".to_string()),
+				metadata: JoinMetadataOrTagMetadata::JoinMetadata(
+					JoinMetadata {
+						desired_username: Some("alice".to_string()),
+						merge_token: Some("merge".to_string()),
+					},
+				),
+			},
+			Message::JoinServer {
+				status: Some(ResponseStatus::Success),
+				content: Some("alice".to_string()),
+				sender: None,
+				metadata: JoinMetadataOrTagMetadata::TagMetadata(TagMetadata {
+					dev: true,
+					guest: false,
+					bot: true,
+					supporter: true,
+				}),
+			},
+			Message::JoinServer {
+				status: Some(ResponseStatus::IpBlocked),
+				content: None,
+				sender: None,
+				metadata: Default::default(),
+			},
+			Message::LeaveServer {
+				content: Some("alice".to_string()),
+			},
+			Message::JoinLobby {
+				lobby_id: Some(lobby_id),
+				username: Some("alice".to_string()),
+				invite: Some(lobby::Invite::SpectateSecret(
+					secrets.spectate_secret.clone(),
+				)),
+			},
+			Message::JoinLobby {
+				lobby_id: None,
+				username: None,
+				invite: None,
+			},
+			Message::LeaveLobby {
+				lobby_id: Some(lobby_id),
+				username: Some("alice".to_string()),
+			},
+			Message::MakeLobby {
+				metadata: Some(LobbyMetadata {
+					max_players: 4,
+					num_players: 2,
+					num_bot_players: 1,
+					lobby_type: LobbyType::OneVsOne,
+					is_public: true,
+				}),
+			},
+			Message::SaveLobby,
+			Message::DisbandLobby { lobby_id },
+			Message::LockLobby,
+			Message::UnlockLobby,
+			Message::NameLobby {
+				lobby_name: "Lobby".to_string(),
+			},
+			Message::ListLobby {
+				lobby_id,
+				lobby_name: "Lobby".to_string(),
+				metadata: LobbyMetadata::default(),
+			},
+			Message::ClaimHost {
+				username: Some("alice".to_string()),
+			},
+			Message::ClaimRole {
+				username: "alice".to_string(),
+				role: Role::Observer,
+			},
+			Message::ClaimColor {
+				username_or_slot: UsernameOrSlot::Username("alice".to_string()),
+				color: PlayerColor::Red,
+			},
+			Message::ClaimColor {
+				username_or_slot: UsernameOrSlot::Slot(slot),
+				color: PlayerColor::Teal,
+			},
+			Message::ClaimColor {
+				username_or_slot: UsernameOrSlot::Empty(EmptyBotslot),
+				color: PlayerColor::None,
+			},
+			Message::ClaimVisionType {
+				username_or_slot: UsernameOrSlot::Slot(slot),
+				visiontype: VisionType::Global,
+			},
+			Message::ClaimAi {
+				username_or_slot: UsernameOrSlot::Slot(slot),
+				ai_name: "RampantRhino".to_string(),
+			},
+			Message::ClaimDifficulty {
+				username_or_slot: UsernameOrSlot::Username("bob".to_string()),
+				difficulty: Difficulty::Hard,
+			},
+			Message::PickMap {
+				map_name: "toad".to_string(),
+			},
+			Message::PickTimer { seconds: 60 },
+			Message::PickChallenge {
+				challenge_key: "tutorial".to_string(),
+			},
+			Message::PickRuleset {
+				ruleset_name: "v1.0.0".to_string(),
+			},
+			Message::AddBot { slot: Some(slot) },
+			Message::AddBot { slot: None },
+			Message::RemoveBot { slot },
+			Message::ListChallenge {
+				key: "showcase".to_string(),
+				metadata: serde_json::from_value(json!({
+					"display-name": "Showcase",
+					"panel-picture-name": "panels/showcase",
+					"discord-image-key": "showcase",
+					"max-stars": 3,
+				}))?,
+			},
+			Message::ListAi {
+				ai_name: "RampantRhino".to_string(),
+				metadata: Some(ListAiMetadata::FromHost { self_hosted: true }),
+			},
+			Message::ListAi {
+				ai_name: "HungryHippo".to_string(),
+				metadata: Some(ListAiMetadata::Authors(BotAuthorsMetadata {
+					authors: "Sander".to_string(),
+				})),
+			},
+			Message::ListMap {
+				map_name: "toad".to_string(),
+				metadata: map_metadata.clone(),
+			},
+			Message::ListRuleset {
+				ruleset_name: "v1.0.0".to_string(),
+				metadata: Some(ListRulesetMetadata::FromHost {
+					self_hosted: false,
+				}),
+			},
+			Message::ListRuleset {
+				ruleset_name: "v1.0.0".to_string(),
+				metadata: Some(ListRulesetMetadata::Forwarding { lobby_id }),
+			},
+			Message::EnableCustomMaps,
+			Message::AssignColor {
+				name: "alice".to_string(),
+				color: PlayerColor::Blue,
+			},
+			Message::RulesetRequest {
+				ruleset_name: "v1.0.0".to_string(),
+			},
+			Message::RulesetData {
+				ruleset_name: "v1.0.0".to_string(),
+				data: serde_json::from_value(json!({
+					"units": [{"type": "rifleman", "hitpoints": 2}],
+					"fraction": 0.5,
+					"negative": -7,
+					"nothing": null,
+				}))?,
+			},
+			Message::RulesetUnknown {
+				ruleset_name: "v0.0.0".to_string(),
+			},
+			Message::Secrets { secrets },
+			Message::Skins {
+				metadata: map_metadata,
+			},
+			Message::InGame {
+				lobby_id,
+				username: "alice".to_string(),
+				role: Role::Player,
+			},
+			Message::Start,
+			Message::Game {
+				role: Some(Role::Player),
+				player: Some(PlayerColor::Yellow),
+				ruleset_name: Some("v1.0.0".to_string()),
+				timer_in_seconds: Some(30),
+				difficulty: Some(Difficulty::Easy),
+				forwarding: Some(ForwardingMetadata::ConnectedBot {
+					lobby_id,
+					slot,
+				}),
+			},
+			Message::Game {
+				role: None,
+				player: None,
+				ruleset_name: None,
+				timer_in_seconds: None,
+				difficulty: None,
+				forwarding: Some(ForwardingMetadata::ClientHosted {
+					player: PlayerColor::Black,
+				}),
+			},
+			Message::Tutorial {
+				role: Some(Role::Player),
+				player: Some(PlayerColor::Pink),
+				ruleset_name: Some("v1.0.0".to_string()),
+				timer_in_seconds: None,
+			},
+			Message::Challenge,
+			Message::Briefing {
+				briefing: serde_json::from_value(json!({"0": "Hello."}))?,
+			},
+			Message::ReplayWithAnimations {
+				on_or_off: OnOrOff::On,
+			},
+			Message::Resign {
+				username: Some("alice".to_string()),
+			},
+			Message::HostSync {
+				metadata: Some(HostSyncMetadata {
+					defeated_players: vec![PlayerColor::Red],
+					game_over: true,
+					stars: 2,
+				}),
+			},
+			Message::HostSync { metadata: None },
+			Message::HostRejoinRequest {
+				player: PlayerColor::Indigo,
+				username: "alice".to_string(),
+			},
+			Message::HostRejoinChanges {
+				player: PlayerColor::Purple,
+				username: "alice".to_string(),
+				changes: changes.clone(),
+			},
+			Message::Changes {
+				changes: changes.clone(),
+				forwarding: Some(ForwardingMetadata::ConnectedBot {
+					lobby_id,
+					slot,
+				}),
+			},
+			Message::Changes {
+				changes,
+				forwarding: None,
+			},
+			Message::Orders {
+				orders: orders.clone(),
+				forwarding: Some(ForwardingMetadata::ClientHosted {
+					player: PlayerColor::Red,
+				}),
+			},
+			Message::Orders {
+				orders,
+				forwarding: None,
+			},
+			Message::Sync {
+				time_remaining_in_seconds: Some(12),
+			},
+			Message::Init,
+			Message::RatingAndStars {
+				username: "alice".to_string(),
+				rating: 12.5,
+				stars: 31,
+			},
+			Message::UpdatedRating { rating: -0.25 },
+			Message::RecentStars {
+				challenge_key: "showcase".to_string(),
+				stars: 2,
+			},
+			Message::Closing { reason: None },
+			Message::Closing {
+				reason: Some(RefusalReason::IpBlocked),
+			},
+			Message::Closed,
+			Message::Quit,
+			Message::Chat {
+				content: "Hello \"world\" \u{1F44B}".to_string(),
+				sender: Some("alice".to_string()),
+				target: ChatTarget::General,
+			},
+			Message::Chat {
+				content: String::new(),
+				sender: None,
+				target: ChatTarget::Lobby,
+			},
+			Message::LinkAccounts {
+				metadata: AccountLinkingMetadata {
+					discord_user_id: "1234567890".to_string(),
+				},
+			},
+			Message::Debug {
+				content: "debug".to_string(),
+			},
+			Message::Error {
+				code: ErrorCode::InvalidBotMetadata,
+				metadata: ErrorMetadata {
+					message_type: Some("list_ai".to_string()),
+					detail: Some("Try again".to_string()),
+				},
+			},
+			Message::Error {
+				code: ErrorCode::MalformedMessage,
+				metadata: ErrorMetadata::default(),
+			},
+			Message::ResumeToken {
+				token: "token".to_string(),
+			},
+		];
+		Ok(messages)
+	}
+
+	#[test]
+	fn test_examples_cover_every_variant() -> Result<(), serde_json::Error>
+	{
+		let mut covered = [false; NUM_VARIANTS];
+		for message in examples()?
+		{
+			covered[variant_index(&message)] = true;
+		}
+		assert!(covered.iter().all(|&x| x));
+		Ok(())
+	}
+
+	#[test]
+	fn test_type_name() -> Result<(), serde_json::Error>
+	{
+		for message in examples()?
+		{
+			let value = serde_json::to_value(&message)?;
+			assert_eq!(value["type"], message.type_name());
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_roundtrip() -> Result<(), Error>
+	{
+		for message in examples()?
+		{
+			// Some metadata is only ever sent by the server and does not
+			// survive being parsed, not even as JSON, so compare against
+			// what the original JSON encoding would have produced.
+			let data = serialize(&message, Encoding::Json)?;
+			let reparsed = deserialize(&data, Encoding::Json)?;
+			let expected = serde_json::to_value(&reparsed)?;
+			for &encoding in SUPPORTED_ENCODINGS.iter()
+			{
+				let data = serialize(&message, encoding)?;
+				let decoded = deserialize(&data, encoding)?;
+				assert_eq!(
+					serde_json::to_value(&decoded)?,
+					expected,
+					"{:?}",
+					encoding
+				);
+			}
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_json_unchanged() -> Result<(), Error>
+	{
+		let message = Message::Version {
+			version: Version::exact(1, 2, 3, 0),
+			metadata: Default::default(),
+		};
+		let data = serialize(&message, Encoding::Json)?;
+		let jsonstr = std::str::from_utf8(&data)?;
+		assert_eq!(jsonstr, r#"{"type":"version","version":"1.2.3"}"#);
+		Ok(())
+	}
+
+	#[test]
+	fn test_negotiate() -> Result<(), Error>
+	{
+		let offer: VersionMetadata = serde_json::from_str(
+			r#"{
+				"supported_compressions": ["brotli", "zlib"],
+				"supported_encodings": ["cbor", "msgpack", "json"]
+			}"#,
+		)?;
+		let reply = negotiate(&offer, true);
+		assert_eq!(reply.compression, Some(Compression::Zlib));
+		assert_eq!(reply.encoding, Some(Encoding::MessagePack));
+		assert!(reply.supported_compressions.is_empty());
+		assert!(reply.supported_encodings.is_empty());
+
+		let reply = negotiate(&offer, false);
+		assert_eq!(reply.compression, None);
+		assert_eq!(Codec::from(&reply).encoding, Encoding::MessagePack);
+
+		let reply = negotiate(&VersionMetadata::default(), true);
+		assert_eq!(Codec::from(&reply), Codec::default());
+		Ok(())
+	}
+}
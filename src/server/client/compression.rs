@@ -0,0 +1,134 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use crate::server::message::Compression;
+
+use std::io::Read;
+use std::io::Write;
+
+pub const SUPPORTED: [Compression; 2] = [Compression::Zstd, Compression::Zlib];
+
+pub fn negotiate(offered: &[Compression]) -> Option<Compression>
+{
+	offered.iter().find(|x| SUPPORTED.contains(x)).copied()
+}
+
+pub fn compress(
+	compression: Compression,
+	data: &[u8],
+) -> Result<Vec<u8>, std::io::Error>
+{
+	match compression
+	{
+		Compression::Zlib =>
+		{
+			let mut encoder = flate2::write::ZlibEncoder::new(
+				Vec::new(),
+				flate2::Compression::default(),
+			);
+			encoder.write_all(data)?;
+			encoder.finish()
+		}
+		Compression::Zstd => zstd::encode_all(data, 0),
+		Compression::Unsupported => Err(unsupported()),
+	}
+}
+
+pub fn decompress(
+	compression: Compression,
+	data: &[u8],
+	limit: usize,
+) -> Result<Vec<u8>, std::io::Error>
+{
+	let decoder: Box<dyn Read + '_> = match compression
+	{
+		Compression::Zlib => Box::new(flate2::read::ZlibDecoder::new(data)),
+		Compression::Zstd => Box::new(zstd::Decoder::new(data)?),
+		Compression::Unsupported => return Err(unsupported()),
+	};
+
+	// Read at most one byte more than the limit so we can detect
+	// compressed data that expands beyond it without allocating further.
+	let mut buffer = Vec::new();
+	decoder.take(limit as u64 + 1).read_to_end(&mut buffer)?;
+	if buffer.len() > limit
+	{
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			"decompressed message too large",
+		));
+	}
+
+	Ok(buffer)
+}
+
+fn unsupported() -> std::io::Error
+{
+	std::io::Error::new(
+		std::io::ErrorKind::InvalidInput,
+		"unsupported compression",
+	)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn test_roundtrip() -> Result<(), std::io::Error>
+	{
+		let data = "{\"type\":\"change\",\"changes\":[]}".repeat(100);
+		for &compression in SUPPORTED.iter()
+		{
+			let compressed = compress(compression, data.as_bytes())?;
+			assert!(compressed.len() < data.len());
+			let decompressed = decompress(compression, &compressed, 10000)?;
+			assert_eq!(decompressed, data.as_bytes());
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_limit() -> Result<(), std::io::Error>
+	{
+		let data = vec![0u8; 10001];
+		for &compression in SUPPORTED.iter()
+		{
+			let compressed = compress(compression, &data)?;
+			assert!(decompress(compression, &compressed, 10000).is_err());
+			assert!(decompress(compression, &compressed, 10001).is_ok());
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_negotiate() -> Result<(), std::io::Error>
+	{
+		let offered = [Compression::Unsupported, Compression::Zlib];
+		assert_eq!(negotiate(&offered), Some(Compression::Zlib));
+		assert_eq!(negotiate(&[Compression::Unsupported]), None);
+		assert_eq!(negotiate(&[]), None);
+		Ok(())
+	}
+}
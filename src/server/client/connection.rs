@@ -0,0 +1,164 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use super::limit::*;
+use super::proxy;
+
+use std::net::SocketAddr;
+use std::sync;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::WebSocketStream;
+
+use openssl::ssl::SslAcceptor;
+
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> Stream for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+pub type BoxedStream = Box<dyn Stream>;
+
+// Where a client connected from. Clients that connect through the local
+// socket are trusted as much as the file permissions of that socket allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Address
+{
+	Ip(SocketAddr),
+	Local,
+}
+
+impl std::fmt::Display for Address
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		match self
+		{
+			Address::Ip(address) => address.fmt(f),
+			Address::Local => write!(f, "local socket"),
+		}
+	}
+}
+
+pub enum Connection
+{
+	Raw(BoxedStream),
+	WebSocket(Box<WebSocketStream<BoxedStream>>),
+}
+
+// Returns the connection along with the address of the client, which is the
+// address of the peer unless a proxy tells us otherwise.
+pub async fn establish(
+	mut socket: TcpStream,
+	peer_address: SocketAddr,
+	proxy_protocol: bool,
+	tls: Option<sync::Arc<SslAcceptor>>,
+	websocket: bool,
+) -> Result<(Connection, SocketAddr), Error>
+{
+	let address = if proxy_protocol
+	{
+		proxy::read_header(&mut socket)
+			.await?
+			.unwrap_or(peer_address)
+	}
+	else
+	{
+		peer_address
+	};
+
+	let stream: BoxedStream = match tls
+	{
+		Some(acceptor) =>
+		{
+			let stream = tokio_openssl::accept(&acceptor, socket).await?;
+			Box::new(stream)
+		}
+		None => Box::new(socket),
+	};
+
+	if websocket
+	{
+		let config = WebSocketConfig {
+			max_send_queue: None,
+			max_message_size: Some(MESSAGE_SIZE_LIMIT),
+			max_frame_size: Some(MESSAGE_SIZE_LIMIT),
+		};
+		let stream =
+			tokio_tungstenite::accept_async_with_config(stream, Some(config))
+				.await?;
+		Ok((Connection::WebSocket(Box::new(stream)), address))
+	}
+	else
+	{
+		Ok((Connection::Raw(stream), address))
+	}
+}
+
+#[derive(Debug)]
+pub enum Error
+{
+	Proxy(proxy::Error),
+	Tls(tokio_openssl::HandshakeError<TcpStream>),
+	WebSocket(tungstenite::Error),
+}
+
+impl From<proxy::Error> for Error
+{
+	fn from(error: proxy::Error) -> Self
+	{
+		Error::Proxy(error)
+	}
+}
+
+impl From<tokio_openssl::HandshakeError<TcpStream>> for Error
+{
+	fn from(error: tokio_openssl::HandshakeError<TcpStream>) -> Self
+	{
+		Error::Tls(error)
+	}
+}
+
+impl From<tungstenite::Error> for Error
+{
+	fn from(error: tungstenite::Error) -> Self
+	{
+		Error::WebSocket(error)
+	}
+}
+
+impl std::fmt::Display for Error
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		match self
+		{
+			Error::Proxy(error) => error.fmt(f),
+			Error::Tls(error) => error.fmt(f),
+			Error::WebSocket(error) => error.fmt(f),
+		}
+	}
+}
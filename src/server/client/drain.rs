@@ -0,0 +1,130 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use crate::server::handoff;
+use crate::server::tokio::State as ServerState;
+
+use futures::stream;
+use futures::{Stream, StreamExt};
+
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+
+// Besides when the server starts closing, clients are warned this many
+// seconds before the drain deadline passes.
+const WARNINGS_IN_SECONDS: [u64; 9] =
+	[3600, 1800, 900, 600, 300, 120, 60, 30, 10];
+
+#[derive(Debug)]
+pub enum Event
+{
+	Countdown
+	{
+		remaining: Duration,
+	},
+	DeadlinePassed,
+}
+
+pub fn countdown(
+	server_state: watch::Receiver<ServerState>,
+	deadline: Duration,
+) -> impl Stream<Item = Event>
+{
+	let closing = server_state
+		.filter(|state| futures::future::ready(*state != ServerState::Open))
+		.take(1);
+
+	closing.flat_map(move |_| {
+		// The new server process has already loaded the game manifest, so
+		// after a handoff we let the games that are running here finish.
+		if handoff::is_handed_off()
+		{
+			return stream::empty().boxed();
+		}
+
+		let start = Instant::now();
+		let warnings = std::iter::once(deadline).chain(
+			WARNINGS_IN_SECONDS
+				.iter()
+				.map(|&seconds| Duration::from_secs(seconds))
+				.filter(move |&remaining| remaining < deadline),
+		);
+		let countdown =
+			stream::iter(warnings).then(move |remaining| async move {
+				tokio::time::delay_until(start + deadline - remaining).await;
+				Event::Countdown { remaining }
+			});
+		let deadline_passed = stream::once(async move {
+			tokio::time::delay_until(start + deadline).await;
+			Event::DeadlinePassed
+		});
+		countdown.chain(deadline_passed).boxed()
+	})
+}
+
+pub fn describe(remaining: Duration) -> String
+{
+	let seconds = remaining.as_secs();
+	if seconds >= 3600 && seconds.is_multiple_of(3600)
+	{
+		plural(seconds / 3600, "hour")
+	}
+	else if seconds >= 60
+	{
+		plural(seconds.div_ceil(60), "minute")
+	}
+	else
+	{
+		plural(seconds, "second")
+	}
+}
+
+fn plural(amount: u64, unit: &str) -> String
+{
+	if amount == 1
+	{
+		format!("{} {}", amount, unit)
+	}
+	else
+	{
+		format!("{} {}s", amount, unit)
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn test_describe()
+	{
+		assert_eq!(describe(Duration::from_secs(7200)), "2 hours");
+		assert_eq!(describe(Duration::from_secs(3600)), "1 hour");
+		assert_eq!(describe(Duration::from_secs(5400)), "90 minutes");
+		assert_eq!(describe(Duration::from_secs(90)), "2 minutes");
+		assert_eq!(describe(Duration::from_secs(60)), "1 minute");
+		assert_eq!(describe(Duration::from_secs(10)), "10 seconds");
+		assert_eq!(describe(Duration::from_secs(1)), "1 second");
+	}
+}
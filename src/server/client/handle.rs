@@ -21,6 +21,8 @@
  * Sander in 't Veld (sander@abunchofhacks.coop)
  */
 
+use super::sendbuffer;
+use super::Outgoing;
 use super::Update;
 
 use crate::common::keycode::Keycode;
@@ -32,7 +34,10 @@ use log::*;
 use tokio::sync::mpsc;
 
 #[derive(Debug)]
-pub struct Poison {}
+pub struct Poison
+{
+	pub cause: Option<sendbuffer::Error>,
+}
 
 #[derive(Debug, Clone)]
 pub enum Handle
@@ -40,7 +45,7 @@ pub enum Handle
 	Connected
 	{
 		id: Keycode,
-		sendbuffer: mpsc::Sender<Message>,
+		sendbuffer: sendbuffer::Sender,
 		update_callback: mpsc::Sender<Update>,
 		poison_callback: mpsc::Sender<Poison>,
 		salts: Option<lobby::Salts>,
@@ -62,7 +67,7 @@ impl Handle
 		}
 	}
 
-	pub fn send(&mut self, message: Message)
+	pub fn send(&mut self, message: impl Into<Outgoing>)
 	{
 		match self
 		{
@@ -79,7 +84,7 @@ impl Handle
 				Err(error) =>
 				{
 					error!("Error sending to client {}: {:?}", id, error);
-					self.poison();
+					self.poison(Some(error));
 				}
 			},
 			Handle::Disconnected { .. } =>
@@ -104,7 +109,7 @@ impl Handle
 				Err(error) =>
 				{
 					error!("Error notifying client {}: {:?}", id, error);
-					self.poison();
+					self.poison(None);
 				}
 			},
 			Handle::Disconnected { .. } =>
@@ -140,24 +145,21 @@ impl Handle
 				id,
 				salts: Some(salts),
 				..
-			} => match invite
-			{
-				lobby::Invite::JoinSecret(secret) =>
-				{
-					secret.client_id == *id
-						&& secret.salt == salts.join_secret_salt
-				}
-				lobby::Invite::SpectateSecret(secret) =>
-				{
-					secret.client_id == *id
-						&& secret.salt == salts.spectate_secret_salt
-				}
-			},
+			} => salts.verify(*id, invite),
 			Handle::Connected { salts: None, .. } => false,
 			Handle::Disconnected { .. } => false,
 		}
 	}
 
+	pub fn salts(&self) -> Option<&lobby::Salts>
+	{
+		match self
+		{
+			Handle::Connected { salts, .. } => salts.as_ref(),
+			Handle::Disconnected { .. } => None,
+		}
+	}
+
 	pub fn take(&mut self) -> Handle
 	{
 		let id: Keycode = match self
@@ -170,7 +172,7 @@ impl Handle
 		result
 	}
 
-	fn poison(&mut self)
+	fn poison(&mut self, cause: Option<sendbuffer::Error>)
 	{
 		match self
 		{
@@ -182,7 +184,7 @@ impl Handle
 				salts: _,
 			} =>
 			{
-				match poison_callback.try_send(Poison {})
+				match poison_callback.try_send(Poison { cause })
 				{
 					Ok(()) =>
 					{}
@@ -24,3 +24,8 @@
 pub const MESSAGE_SIZE_LIMIT: usize = 524288;
 pub const MESSAGE_SIZE_UNVERSIONED_LIMIT: usize = 201;
 pub const MESSAGE_SIZE_WARNING_LIMIT: usize = 65537;
+pub const MESSAGE_SIZE_COMPRESSION_THRESHOLD: usize = 1024;
+
+// Lengths never exceed MESSAGE_SIZE_LIMIT, so the highest bit is free to
+// mark the message data as compressed.
+pub const MESSAGE_LENGTH_COMPRESSED_FLAG: u32 = 0x8000_0000;
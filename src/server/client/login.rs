@@ -21,7 +21,10 @@
  * Sander in 't Veld (sander@abunchofhacks.coop)
  */
 
+use super::sendbuffer;
+
 use crate::server::message::*;
+use crate::server::metrics;
 
 pub use crate::server::login::LoginData;
 pub use crate::server::login::Request;
@@ -32,9 +35,10 @@ use std::sync;
 use log::*;
 
 use tokio::sync::mpsc;
+use tokio::time::Instant;
 
 pub async fn run(
-	mut sendbuffer: mpsc::Sender<Message>,
+	mut sendbuffer: sendbuffer::Sender,
 	mut joinedbuffer: mpsc::Sender<LoginData>,
 	mut requestbuffer: mpsc::Receiver<Request>,
 	login_server: sync::Arc<Server>,
@@ -42,7 +46,11 @@ pub async fn run(
 {
 	while let Some(request) = requestbuffer.recv().await
 	{
-		match login_server.login(request).await
+		let start = Instant::now();
+		let result = login_server.login(request).await;
+		metrics::LOGIN_LATENCY.observe(start.elapsed());
+
+		match result
 		{
 			Ok(logindata) =>
 			{
@@ -51,13 +59,15 @@ pub async fn run(
 			Err(responsestatus) =>
 			{
 				debug!("Login failed with {:?}", responsestatus);
+				let label = format!("{:?}", responsestatus);
+				metrics::LOGIN_FAILURES.increment(&label);
 				let message = Message::JoinServer {
 					status: Some(responsestatus),
 					content: None,
 					sender: None,
 					metadata: Default::default(),
 				};
-				sendbuffer.send(message).await?;
+				sendbuffer.try_send(message)?;
 			}
 		}
 	}
@@ -69,7 +79,7 @@ pub async fn run(
 pub enum Error
 {
 	SendLoginData(mpsc::error::SendError<LoginData>),
-	SendMessage(mpsc::error::SendError<Message>),
+	SendMessage(sendbuffer::Error),
 }
 
 impl From<mpsc::error::SendError<LoginData>> for Error
@@ -80,9 +90,9 @@ impl From<mpsc::error::SendError<LoginData>> for Error
 	}
 }
 
-impl From<mpsc::error::SendError<Message>> for Error
+impl From<sendbuffer::Error> for Error
 {
-	fn from(error: mpsc::error::SendError<Message>) -> Error
+	fn from(error: sendbuffer::Error) -> Error
 	{
 		Error::SendMessage(error)
 	}
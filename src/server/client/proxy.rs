@@ -0,0 +1,195 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+// A load balancer that speaks the PROXY protocol starts each connection with
+// a header that tells us who actually connected. We read it one byte at a
+// time where needed, because anything after it belongs to the client.
+// A header without an address (such as a health check) gives None.
+pub async fn read_header(
+	socket: &mut TcpStream,
+) -> Result<Option<SocketAddr>, Error>
+{
+	let mut start = [0u8; V2_SIGNATURE.len()];
+	socket.read_exact(&mut start[0..V1_PREFIX.len()]).await?;
+
+	if start[0..V1_PREFIX.len()] == V1_PREFIX[..]
+	{
+		let mut line = V1_PREFIX.to_vec();
+		while !line.ends_with(b"\r\n")
+		{
+			if line.len() >= V1_MAX_LENGTH
+			{
+				return Err(Error::Malformed);
+			}
+			line.push(socket.read_u8().await?);
+		}
+		let line = std::str::from_utf8(&line).map_err(|_| Error::Malformed)?;
+		parse_v1(line)
+	}
+	else if start[0..V1_PREFIX.len()] == V2_SIGNATURE[0..V1_PREFIX.len()]
+	{
+		socket.read_exact(&mut start[V1_PREFIX.len()..]).await?;
+		if start != V2_SIGNATURE
+		{
+			return Err(Error::Missing);
+		}
+		let command = socket.read_u8().await?;
+		let family = socket.read_u8().await?;
+		let length = socket.read_u16().await?;
+		let mut payload = vec![0u8; length as usize];
+		socket.read_exact(&mut payload).await?;
+		parse_v2(command, family, &payload)
+	}
+	else
+	{
+		Err(Error::Missing)
+	}
+}
+
+const V1_PREFIX: [u8; 5] = *b"PROXY";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>, Error>
+{
+	let fields: Vec<&str> = line.trim_end_matches("\r\n").split(' ').collect();
+	match fields.as_slice()
+	{
+		["PROXY", "UNKNOWN", ..] => Ok(None),
+		["PROXY", "TCP4", source, _destination, port, _]
+		| ["PROXY", "TCP6", source, _destination, port, _] =>
+		{
+			let ip: IpAddr = source.parse().map_err(|_| Error::Malformed)?;
+			let port: u16 = port.parse().map_err(|_| Error::Malformed)?;
+			Ok(Some(SocketAddr::new(ip, port)))
+		}
+		_ => Err(Error::Malformed),
+	}
+}
+
+fn parse_v2(
+	command: u8,
+	family: u8,
+	payload: &[u8],
+) -> Result<Option<SocketAddr>, Error>
+{
+	if command >> 4 != 2
+	{
+		return Err(Error::Malformed);
+	}
+	match command & 0x0F
+	{
+		0x0 => return Ok(None),
+		0x1 => (),
+		_ => return Err(Error::Malformed),
+	}
+
+	match family
+	{
+		0x11 if payload.len() >= 12 =>
+		{
+			let mut ip = [0u8; 4];
+			ip.copy_from_slice(&payload[0..4]);
+			let port = u16::from_be_bytes([payload[8], payload[9]]);
+			Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+		}
+		0x21 if payload.len() >= 36 =>
+		{
+			let mut ip = [0u8; 16];
+			ip.copy_from_slice(&payload[0..16]);
+			let port = u16::from_be_bytes([payload[32], payload[33]]);
+			Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+		}
+		0x11 | 0x21 => Err(Error::Malformed),
+		_ => Ok(None),
+	}
+}
+
+#[derive(Debug)]
+pub enum Error
+{
+	Missing,
+	Malformed,
+	Io(std::io::Error),
+}
+
+impl From<std::io::Error> for Error
+{
+	fn from(error: std::io::Error) -> Self
+	{
+		Error::Io(error)
+	}
+}
+
+impl std::fmt::Display for Error
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		match self
+		{
+			Error::Missing => write!(f, "Missing PROXY protocol header"),
+			Error::Malformed => write!(f, "Malformed PROXY protocol header"),
+			Error::Io(error) => error.fmt(f),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn test_v1() -> Result<(), Error>
+	{
+		let line = "PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n";
+		let address: SocketAddr = "192.168.0.1:56324".parse().unwrap();
+		assert_eq!(parse_v1(line)?, Some(address));
+
+		let line = "PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n";
+		let address: SocketAddr = "[2001:db8::1]:4000".parse().unwrap();
+		assert_eq!(parse_v1(line)?, Some(address));
+
+		assert_eq!(parse_v1("PROXY UNKNOWN\r\n")?, None);
+		assert!(parse_v1("PROXY TCP4 nonsense\r\n").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_v2() -> Result<(), Error>
+	{
+		let payload = [10, 0, 0, 1, 10, 0, 0, 2, 0x1F, 0x90, 0x01, 0xBB];
+		let address: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+		assert_eq!(parse_v2(0x21, 0x11, &payload)?, Some(address));
+
+		assert_eq!(parse_v2(0x20, 0x00, &[])?, None);
+		assert!(parse_v2(0x21, 0x11, &payload[0..6]).is_err());
+		assert!(parse_v2(0x11, 0x11, &payload).is_err());
+		Ok(())
+	}
+}
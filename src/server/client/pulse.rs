@@ -21,13 +21,14 @@
  * Sander in 't Veld (sander@abunchofhacks.coop)
  */
 
+use super::sendbuffer;
+
 use crate::server::message::*;
 
-use tokio::sync::mpsc;
 use tokio::time as timer;
 use tokio::time::{Duration, Instant};
 
-pub async fn run(mut sendbuffer: mpsc::Sender<Message>) -> Result<(), Error>
+pub async fn run(mut sendbuffer: sendbuffer::Sender) -> Result<(), Error>
 {
 	let start = Instant::now() + Duration::from_secs(4);
 	let mut interval = timer::interval_at(start, Duration::from_secs(4));
@@ -35,19 +36,21 @@ pub async fn run(mut sendbuffer: mpsc::Sender<Message>) -> Result<(), Error>
 	loop
 	{
 		interval.tick().await;
-		sendbuffer.send(Message::Pulse).await?;
+		// This also makes sure that a client that is stuck gets disconnected
+		// eventually, even if nothing else is being sent to it.
+		sendbuffer.try_send(Message::Pulse)?;
 	}
 }
 
 #[derive(Debug)]
 pub enum Error
 {
-	SendMessage(mpsc::error::SendError<Message>),
+	SendMessage(sendbuffer::Error),
 }
 
-impl From<mpsc::error::SendError<Message>> for Error
+impl From<sendbuffer::Error> for Error
 {
-	fn from(error: mpsc::error::SendError<Message>) -> Error
+	fn from(error: sendbuffer::Error) -> Error
 	{
 		Error::SendMessage(error)
 	}
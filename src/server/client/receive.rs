@@ -21,6 +21,11 @@
  * Sander in 't Veld (sander@abunchofhacks.coop)
  */
 
+use super::capture;
+use super::codec;
+use super::codec::Codec;
+use super::compression;
+use super::connection::BoxedStream;
 use super::limit::*;
 
 use crate::common::keycode::Keycode;
@@ -31,47 +36,280 @@ use std::sync::atomic;
 
 use log::*;
 
+use futures::future;
+use futures::future::Either;
+use futures::pin_mut;
+use futures::stream;
+use futures::stream::SplitStream;
+use futures::{Stream, StreamExt};
+
 use tokio::io::ReadHalf;
-use tokio::net::TcpStream;
 use tokio::prelude::*;
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+use tokio_tungstenite::WebSocketStream;
 
 use itertools::Itertools;
 
+pub enum Socket
+{
+	Raw(ReadHalf<BoxedStream>),
+	WebSocket(SplitStream<WebSocketStream<BoxedStream>>),
+}
+
 pub struct Client
 {
-	pub socket: ReadHalf<TcpStream>,
+	pub socket: Socket,
 	pub client_id: Keycode,
 	pub has_proper_version: sync::Arc<atomic::AtomicBool>,
+	pub codec: watch::Receiver<Codec>,
+	pub capture: watch::Receiver<Option<capture::Recorder>>,
 }
 
 impl Client
 {
 	pub async fn receive(&mut self) -> Result<Message, Error>
 	{
-		let socket = &mut self.socket;
 		let id = self.client_id;
 		let versioned = self.has_proper_version.load(atomic::Ordering::Relaxed);
-		let message = receive_message(socket, id, versioned).await?;
+		let codec = *self.codec.borrow();
+		let frame = match &mut self.socket
+		{
+			Socket::Raw(socket) =>
+			{
+				receive_message(socket, id, versioned, codec).await?
+			}
+			Socket::WebSocket(socket) =>
+			{
+				receive_frame(socket, id, versioned, codec.encoding).await?
+			}
+		};
+
+		let message = parse_message(&frame)?;
+		if let Message::Pulse = message
+		{
+			return Ok(message);
+		}
+		let recorder = self.capture.borrow().clone();
+		if let Some(mut recorder) = recorder
+		{
+			let direction = capture::Direction::In;
+			recorder.record(direction, &message, &frame.data, frame.codec);
+		}
+
 		Ok(message)
 	}
 }
 
+// The data of a single message as it was received, along with how it was
+// encoded and, if it was, compressed.
+struct Frame
+{
+	data: Vec<u8>,
+	codec: Codec,
+}
+
+impl Frame
+{
+	fn pulse() -> Frame
+	{
+		Frame {
+			data: Vec::new(),
+			codec: Codec::default(),
+		}
+	}
+}
+
+// Receives messages until the connection drops, and then waits for the
+// client to resume its session on a new connection, if it ever does.
+pub fn run(
+	client: Client,
+	sockets: mpsc::Receiver<Socket>,
+) -> impl Stream<Item = Result<Message, Error>>
+{
+	stream::unfold(
+		(client, sockets, true),
+		|(mut client, mut sockets, mut is_connected)| async move {
+			loop
+			{
+				if !is_connected
+				{
+					client.socket = sockets.recv().await?;
+					is_connected = true;
+				}
+
+				let outcome = {
+					let received = client.receive();
+					let reattached = sockets.recv();
+					pin_mut!(received);
+					pin_mut!(reattached);
+					match future::select(received, reattached).await
+					{
+						Either::Left((result, _)) => Either::Left(result),
+						Either::Right((socket, _)) => Either::Right(socket),
+					}
+				};
+
+				match outcome
+				{
+					Either::Left(Err(error)) if error.is_malformed() =>
+					{
+						let result = Err(error);
+						return Some((result, (client, sockets, is_connected)));
+					}
+					Either::Left(Err(error)) =>
+					{
+						is_connected = false;
+						let result = Err(error);
+						return Some((result, (client, sockets, is_connected)));
+					}
+					Either::Left(Ok(message)) =>
+					{
+						let result = Ok(message);
+						return Some((result, (client, sockets, is_connected)));
+					}
+					Either::Right(Some(socket)) =>
+					{
+						client.socket = socket;
+					}
+					Either::Right(None) => return None,
+				}
+			}
+		},
+	)
+}
+
+// The first message is received before we know whether this connection
+// starts a new session or resumes an existing one.
+pub async fn receive_greeting(
+	socket: &mut Socket,
+	client_id: Keycode,
+) -> Result<Message, Error>
+{
+	let codec = Codec::default();
+	let frame = match socket
+	{
+		Socket::Raw(socket) =>
+		{
+			receive_message(socket, client_id, false, codec).await?
+		}
+		Socket::WebSocket(socket) =>
+		{
+			receive_frame(socket, client_id, false, codec.encoding).await?
+		}
+	};
+	parse_message(&frame)
+}
+
 async fn receive_message(
-	socket: &mut ReadHalf<TcpStream>,
+	socket: &mut ReadHalf<BoxedStream>,
 	client_id: Keycode,
 	versioned: bool,
-) -> Result<Message, Error>
+	codec: Codec,
+) -> Result<Frame, Error>
 {
 	trace!("Starting to receive...");
-	let length = socket.read_u32().await?;
+	let mut length = socket.read_u32().await?;
 
 	if length == 0
 	{
 		trace!("Received pulse.");
 
-		return Ok(Message::Pulse);
+		return Ok(Frame::pulse());
+	}
+
+	// Without negotiated compression, the flag makes the length too large.
+	let compression = match codec.compression
+	{
+		Some(compression) if length & MESSAGE_LENGTH_COMPRESSED_FLAG != 0 =>
+		{
+			length &= !MESSAGE_LENGTH_COMPRESSED_FLAG;
+			Some(compression)
+		}
+		_ => None,
+	};
+
+	check_length(length, client_id, versioned)?;
+
+	trace!("Receiving message of length {}...", length);
+
+	let mut buffer = vec![0; length as usize];
+	socket.read_exact(&mut buffer).await?;
+
+	trace!("Received message of length {}.", buffer.len());
+	Ok(Frame {
+		data: buffer,
+		codec: Codec {
+			encoding: codec.encoding,
+			compression,
+		},
+	})
+}
+
+async fn receive_frame(
+	socket: &mut SplitStream<WebSocketStream<BoxedStream>>,
+	client_id: Keycode,
+	versioned: bool,
+	encoding: Encoding,
+) -> Result<Frame, Error>
+{
+	trace!("Starting to receive...");
+
+	// Each text or binary frame contains exactly one message, so there is
+	// no need for a length prefix. Ping and pong frames are handled by
+	// tungstenite itself and we can simply skip over them.
+	let (buffer, encoding) = loop
+	{
+		let frame = match socket.next().await
+		{
+			Some(frame) => frame?,
+			None => return Err(Error::Closed),
+		};
+
+		match frame
+		{
+			WebSocketMessage::Text(jsonstr) =>
+			{
+				break (jsonstr.into_bytes(), Encoding::Json)
+			}
+			WebSocketMessage::Binary(data) => break (data, encoding),
+			WebSocketMessage::Ping(_) => continue,
+			WebSocketMessage::Pong(_) => continue,
+			WebSocketMessage::Close(_) => return Err(Error::Closed),
+		}
+	};
+
+	if buffer.is_empty()
+	{
+		trace!("Received pulse.");
+
+		return Ok(Frame::pulse());
 	}
-	else if !versioned && length as usize >= MESSAGE_SIZE_UNVERSIONED_LIMIT
+
+	// The WebSocket configuration already caps frames at MESSAGE_SIZE_LIMIT.
+	let length = buffer.len() as u32;
+	check_length(length, client_id, versioned)?;
+
+	trace!("Received message of length {}.", buffer.len());
+	Ok(Frame {
+		data: buffer,
+		codec: Codec {
+			encoding,
+			compression: None,
+		},
+	})
+}
+
+fn check_length(
+	length: u32,
+	client_id: Keycode,
+	versioned: bool,
+) -> Result<(), Error>
+{
+	if !versioned && length as usize >= MESSAGE_SIZE_UNVERSIONED_LIMIT
 	{
 		warn!(
 			"Unversioned client {} tried to send \
@@ -95,26 +333,43 @@ async fn receive_message(
 		warn!("Receiving very large message of length {}...", length);
 	}
 
-	trace!("Receiving message of length {}...", length);
-
-	let mut buffer = vec![0; length as usize];
-	socket.read_exact(&mut buffer).await?;
-
-	trace!("Received message of length {}.", buffer.len());
-	let message = parse_message(buffer)?;
-
-	Ok(message)
+	Ok(())
 }
 
-fn parse_message(buffer: Vec<u8>) -> Result<Message, Error>
+fn parse_message(frame: &Frame) -> Result<Message, Error>
 {
-	let jsonstr = String::from_utf8(buffer)?;
+	if frame.data.is_empty()
+	{
+		return Ok(Message::Pulse);
+	}
+
+	let encoding = frame.codec.encoding;
+	let decompressed;
+	let buffer = match frame.codec.compression
+	{
+		Some(compression) =>
+		{
+			decompressed = compression::decompress(
+				compression,
+				&frame.data,
+				MESSAGE_SIZE_LIMIT,
+			)
+			.map_err(|error| Error::Decompression { error })?;
+			trace!(
+				"Decompressed message of length {} to length {}.",
+				frame.data.len(),
+				decompressed.len()
+			);
+			&decompressed
+		}
+		None => &frame.data,
+	};
 
 	if log_enabled!(log::Level::Trace)
 	{
 		trace!(
 			"Received message: {}",
-			jsonstr
+			codec::preview(buffer, encoding)
 				.chars()
 				.take(500)
 				.map(|x| {
@@ -131,7 +386,7 @@ fn parse_message(buffer: Vec<u8>) -> Result<Message, Error>
 		);
 	}
 
-	let message: Message = serde_json::from_str(&jsonstr)?;
+	let message: Message = codec::deserialize(buffer, encoding)?;
 
 	Ok(message)
 }
@@ -147,9 +402,29 @@ pub enum Error
 	{
 		length: u32,
 	},
+	Closed,
+	Decompression
+	{
+		error: std::io::Error,
+	},
 	Io(std::io::Error),
-	Utf8(std::string::FromUtf8Error),
-	Json(serde_json::Error),
+	WebSocket(tungstenite::Error),
+	Codec(codec::Error),
+}
+
+impl Error
+{
+	// The whole message was received, so the connection is still usable.
+	pub fn is_malformed(&self) -> bool
+	{
+		matches!(self, Error::Codec(_) | Error::Decompression { .. })
+	}
+
+	// Messages that are too large break the protocol, not the connection.
+	pub fn is_connection_lost(&self) -> bool
+	{
+		matches!(self, Error::Closed | Error::Io(_) | Error::WebSocket(_))
+	}
 }
 
 impl From<std::io::Error> for Error
@@ -160,19 +435,19 @@ impl From<std::io::Error> for Error
 	}
 }
 
-impl From<std::string::FromUtf8Error> for Error
+impl From<tungstenite::Error> for Error
 {
-	fn from(error: std::string::FromUtf8Error) -> Error
+	fn from(error: tungstenite::Error) -> Error
 	{
-		Error::Utf8(error)
+		Error::WebSocket(error)
 	}
 }
 
-impl From<serde_json::Error> for Error
+impl From<codec::Error> for Error
 {
-	fn from(error: serde_json::Error) -> Error
+	fn from(error: codec::Error) -> Error
 	{
-		Error::Json(error)
+		Error::Codec(error)
 	}
 }
 
@@ -194,9 +469,14 @@ impl std::fmt::Display for Error
 				 which is more than MESSAGE_SIZE_UNVERSIONED_LIMIT.",
 				length
 			),
+			Error::Closed => write!(f, "Connection closed by peer."),
+			Error::Decompression { error } =>
+			{
+				write!(f, "Failed to decompress message: {}", error)
+			}
 			Error::Io(error) => error.fmt(f),
-			Error::Utf8(error) => error.fmt(f),
-			Error::Json(error) => error.fmt(f),
+			Error::WebSocket(error) => error.fmt(f),
+			Error::Codec(error) => error.fmt(f),
 		}
 	}
 }
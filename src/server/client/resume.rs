@@ -0,0 +1,129 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use super::admission::Ticket;
+use super::connection::Address;
+use super::receive;
+use super::send;
+
+use crate::common::version::Version;
+use crate::server::message::VersionMetadata;
+
+use std::collections::HashMap;
+use std::sync;
+
+use tokio::sync::mpsc;
+
+// A new connection that takes over the session of a client whose connection
+// dropped, bringing along the version handshake it started with.
+pub struct Reattachment
+{
+	pub reader: receive::Socket,
+	pub writer: send::Socket,
+	pub allows_compression: bool,
+	pub address: Address,
+	pub version: Version,
+	pub metadata: VersionMetadata,
+	pub ticket: Ticket,
+}
+
+impl std::fmt::Debug for Reattachment
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		f.debug_struct("Reattachment")
+			.field("address", &self.address)
+			.field("version", &self.version)
+			.field("metadata", &self.metadata)
+			.finish()
+	}
+}
+
+// The sessions that can currently be resumed, by resume token.
+#[derive(Debug, Clone, Default)]
+pub struct Sessions
+{
+	sessions:
+		sync::Arc<sync::Mutex<HashMap<String, mpsc::Sender<Reattachment>>>>,
+}
+
+impl Sessions
+{
+	pub fn register(
+		&self,
+		token: String,
+		sendbuffer: mpsc::Sender<Reattachment>,
+	)
+	{
+		self.lock().insert(token, sendbuffer);
+	}
+
+	pub fn unregister(&self, token: &str)
+	{
+		self.lock().remove(token);
+	}
+
+	// If the session is still around, it takes over the connection.
+	// Otherwise the connection is returned so it can start a new session.
+	pub fn hand_over(
+		&self,
+		token: &str,
+		reattachment: Reattachment,
+	) -> Result<(), Reattachment>
+	{
+		let mut session = match self.lock().remove(token)
+		{
+			Some(session) => session,
+			None => return Err(reattachment),
+		};
+		match session.try_send(reattachment)
+		{
+			Ok(()) => Ok(()),
+			Err(mpsc::error::TrySendError::Full(reattachment)) =>
+			{
+				Err(reattachment)
+			}
+			Err(mpsc::error::TrySendError::Closed(reattachment)) =>
+			{
+				Err(reattachment)
+			}
+		}
+	}
+
+	fn lock(
+		&self,
+	) -> sync::MutexGuard<'_, HashMap<String, mpsc::Sender<Reattachment>>>
+	{
+		match self.sessions.lock()
+		{
+			Ok(sessions) => sessions,
+			Err(poisoned) => poisoned.into_inner(),
+		}
+	}
+}
+
+pub fn generate_token() -> String
+{
+	let bits: u128 = rand::random();
+	format!("{:032x}", bits)
+}
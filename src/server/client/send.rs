@@ -21,55 +21,402 @@
  * Sander in 't Veld (sander@abunchofhacks.coop)
  */
 
+use super::capture;
+use super::codec;
+use super::codec::Codec;
+use super::compression;
+use super::connection::BoxedStream;
 use super::limit::*;
+use super::sendbuffer;
 
 use crate::common::keycode::Keycode;
 use crate::server::message::*;
+use crate::server::metrics;
+
+use std::sync;
 
 use log::*;
 
+use futures::future;
+use futures::future::Either;
 use futures::pin_mut;
-use futures::StreamExt;
+use futures::stream::SplitSink;
+use futures::SinkExt;
 
 use tokio::io::WriteHalf;
-use tokio::net::TcpStream;
 use tokio::prelude::*;
 use tokio::sync::mpsc;
+use tokio::sync::watch;
+
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+use tokio_tungstenite::WebSocketStream;
 
 use itertools::Itertools;
 
+pub enum Socket
+{
+	Raw(WriteHalf<BoxedStream>),
+	WebSocket(SplitSink<WebSocketStream<BoxedStream>, WebSocketMessage>),
+}
+
+// A message that is serialized at most once for each codec, no matter how
+// many clients it is sent to. Cloning only clones the reference.
+#[derive(Debug, Clone)]
+pub struct Outgoing
+{
+	shared: sync::Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared
+{
+	message: Message,
+	buffers: sync::Mutex<Vec<(Codec, sync::Arc<[u8]>)>>,
+	frames: sync::Mutex<Vec<(Codec, WebSocketMessage)>>,
+}
+
+impl From<Message> for Outgoing
+{
+	fn from(message: Message) -> Outgoing
+	{
+		Outgoing {
+			shared: sync::Arc::new(Shared {
+				message,
+				buffers: sync::Mutex::new(Vec::new()),
+				frames: sync::Mutex::new(Vec::new()),
+			}),
+		}
+	}
+}
+
+impl Outgoing
+{
+	pub fn message(&self) -> &Message
+	{
+		&self.shared.message
+	}
+
+	fn prepare(&self, codec: Codec) -> sync::Arc<[u8]>
+	{
+		// Nobody else can clone this message while we are its sole owner,
+		// so there is no point in remembering the result.
+		if sync::Arc::strong_count(&self.shared) == 1
+		{
+			return prepare_message(&self.shared.message, codec).into();
+		}
+
+		let mut buffers = match self.shared.buffers.lock()
+		{
+			Ok(buffers) => buffers,
+			Err(poisoned) => poisoned.into_inner(),
+		};
+		if let Some((_, buffer)) = buffers.iter().find(|(x, _)| *x == codec)
+		{
+			return buffer.clone();
+		}
+		let buffer: sync::Arc<[u8]> =
+			prepare_message(&self.shared.message, codec).into();
+		buffers.push((codec, buffer.clone()));
+		buffer
+	}
+
+	// Each connection takes ownership of the frames it sends, so every client
+	// gets its own copy, but the frame is only prepared once.
+	fn prepare_frame(&self, codec: Codec) -> WebSocketMessage
+	{
+		if sync::Arc::strong_count(&self.shared) == 1
+		{
+			return prepare_frame(&self.shared.message, codec);
+		}
+
+		let mut frames = match self.shared.frames.lock()
+		{
+			Ok(frames) => frames,
+			Err(poisoned) => poisoned.into_inner(),
+		};
+		if let Some((_, frame)) = frames.iter().find(|(x, _)| *x == codec)
+		{
+			return frame.clone();
+		}
+		let frame = prepare_frame(&self.shared.message, codec);
+		frames.push((codec, frame.clone()));
+		frame
+	}
+}
+
+// A new connection for a session that is being resumed, along with the reply
+// to its version handshake, which has to be sent before anything else.
+pub struct Reattachment
+{
+	pub socket: Socket,
+	pub greeting: Message,
+}
+
+enum Event
+{
+	Message(Option<Outgoing>),
+	Reattached(Option<Reattachment>),
+}
+
+enum Outcome
+{
+	Written(Result<(), Error>),
+	Interrupted(Reattachment),
+}
+
+// Messages that were written to a connection that has since dropped are lost,
+// but everything that was still queued is sent once the client reattaches.
 pub async fn run(
 	client_id: Keycode,
-	sendbuffer: mpsc::Receiver<Message>,
-	mut socket: WriteHalf<TcpStream>,
+	mut sendbuffer: sendbuffer::Receiver,
+	socket: Socket,
+	mut reattachments: mpsc::Receiver<Reattachment>,
+	capture: watch::Receiver<Option<capture::Recorder>>,
 ) -> Result<(), Error>
 {
-	let last_messages = futures::stream::once(async { Message::Quit });
-	let messages = sendbuffer.chain(last_messages);
-	pin_mut!(messages);
+	let mut socket = Some(socket);
+	let mut codec = Codec::default();
+	let mut unsent: Option<Outgoing> = None;
+	let mut interruption: Option<Reattachment> = None;
+	let mut can_reattach = true;
+	let mut is_finished = false;
+
+	while !is_finished
+	{
+		let event = if let Some(reattachment) = interruption.take()
+		{
+			Event::Reattached(Some(reattachment))
+		}
+		else if socket.is_none()
+		{
+			Event::Reattached(reattachments.recv().await)
+		}
+		else if let Some(message) = unsent.take()
+		{
+			Event::Message(Some(message))
+		}
+		else if can_reattach
+		{
+			let message = sendbuffer.recv(client_id);
+			let reattachment = reattachments.recv();
+			pin_mut!(message);
+			pin_mut!(reattachment);
+			match future::select(message, reattachment).await
+			{
+				Either::Left((message, _)) => Event::Message(message),
+				Either::Right((reattachment, _)) =>
+				{
+					Event::Reattached(reattachment)
+				}
+			}
+		}
+		else
+		{
+			Event::Message(sendbuffer.recv(client_id).await)
+		};
+
+		let (message, is_greeting) = match event
+		{
+			Event::Message(Some(message)) => (message, false),
+			Event::Message(None) =>
+			{
+				is_finished = true;
+				(Outgoing::from(Message::Quit), false)
+			}
+			Event::Reattached(Some(reattachment)) =>
+			{
+				socket = Some(reattachment.socket);
+				codec = Codec::default();
+				(Outgoing::from(reattachment.greeting), true)
+			}
+			Event::Reattached(None) if socket.is_some() =>
+			{
+				can_reattach = false;
+				continue;
+			}
+			Event::Reattached(None) => break,
+		};
+
+		// The version handshake itself is always sent as plain JSON, but
+		// once the client knows our choice, everything else follows it.
+		let negotiated = match message.message()
+		{
+			Message::Version { metadata, .. } => Some(Codec::from(metadata)),
+			_ => None,
+		};
+
+		let outcome = match socket
+		{
+			Some(ref mut socket) if can_reattach && !is_finished =>
+			{
+				// A connection that silently dropped might block us until it
+				// times out, by which time the client has long moved on.
+				let written = write(socket, &message, codec, &sendbuffer);
+				let reattachment = reattachments.recv();
+				pin_mut!(written);
+				pin_mut!(reattachment);
+				match future::select(written, reattachment).await
+				{
+					Either::Left((result, _)) => Outcome::Written(result),
+					Either::Right((Some(reattachment), _)) =>
+					{
+						Outcome::Interrupted(reattachment)
+					}
+					Either::Right((None, written)) =>
+					{
+						can_reattach = false;
+						Outcome::Written(written.await)
+					}
+				}
+			}
+			Some(ref mut socket) => Outcome::Written(
+				write(socket, &message, codec, &sendbuffer).await,
+			),
+			None => continue,
+		};
+
+		match outcome
+		{
+			Outcome::Written(Ok(())) => (),
+			Outcome::Written(Err(error)) if can_reattach && !is_finished =>
+			{
+				warn!("Client {} lost its connection: {}", client_id, error);
+				socket = None;
+				if !is_greeting
+				{
+					unsent = Some(message);
+				}
+				continue;
+			}
+			Outcome::Written(Err(error)) => return Err(error),
+			Outcome::Interrupted(reattachment) =>
+			{
+				// We cannot tell how much of this message arrived, so we send
+				// it again unless the new connection makes it obsolete.
+				if !is_greeting
+				{
+					unsent = Some(message);
+				}
+				interruption = Some(reattachment);
+				continue;
+			}
+		}
 
-	while let Some(message) = messages.next().await
+		let label = metrics::message_type(message.message());
+		metrics::MESSAGES_SENT.increment(label);
+
+		match message.message()
+		{
+			Message::Pulse => (),
+			_ =>
+			{
+				let recorder = capture.borrow().clone();
+				if let Some(mut recorder) = recorder
+				{
+					record(&mut recorder, &message, codec);
+				}
+			}
+		}
+
+		if let Some(negotiated) = negotiated
+		{
+			codec = negotiated;
+		}
+	}
+
+	if let Some(Socket::WebSocket(ref mut socket)) = socket
 	{
-		let buffer = prepare_message(message);
-		send_bytes(&mut socket, buffer).await?;
+		socket.close().await?;
 	}
 
 	debug!("Client {} stopped sending.", client_id);
 	Ok(())
 }
 
+// Sends a single message to a connection that we are not going to serve.
+pub async fn send_once(
+	mut socket: Socket,
+	message: Message,
+) -> Result<(), Error>
+{
+	let message = Outgoing::from(message);
+	let codec = Codec::default();
+	match socket
+	{
+		Socket::Raw(ref mut socket) =>
+		{
+			let buffer = message.prepare(codec);
+			send_bytes(socket, &buffer).await?;
+			socket.shutdown().await?;
+		}
+		Socket::WebSocket(ref mut socket) =>
+		{
+			socket.send(message.prepare_frame(codec)).await?;
+			socket.close().await?;
+		}
+	}
+
+	let label = metrics::message_type(message.message());
+	metrics::MESSAGES_SENT.increment(label);
+	Ok(())
+}
+
+// WebSocket frames contain the same data as raw messages, minus the length.
+fn record(recorder: &mut capture::Recorder, message: &Outgoing, codec: Codec)
+{
+	let buffer = message.prepare(codec);
+	let (prefix, data) = buffer.split_at(4);
+	let length =
+		u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]);
+	let codec = Codec {
+		encoding: codec.encoding,
+		compression: codec
+			.compression
+			.filter(|_| length & MESSAGE_LENGTH_COMPRESSED_FLAG != 0),
+	};
+	recorder.record(capture::Direction::Out, message.message(), data, codec);
+}
+
+async fn write(
+	socket: &mut Socket,
+	message: &Outgoing,
+	codec: Codec,
+	sendbuffer: &sendbuffer::Receiver,
+) -> Result<(), Error>
+{
+	match socket
+	{
+		Socket::Raw(ref mut socket) =>
+		{
+			let buffer = message.prepare(codec);
+			let _writing = sendbuffer.start_writing(buffer.len());
+			send_bytes(socket, &buffer)
+				.await
+				.map_err(|error| error.into())
+		}
+		Socket::WebSocket(ref mut socket) =>
+		{
+			let frame = message.prepare_frame(codec);
+			let length = frame.len();
+			let _writing = sendbuffer.start_writing(length);
+			socket.send(frame).await.map_err(|error| error.into())
+		}
+	}
+}
+
 async fn send_bytes(
-	socket: &mut WriteHalf<TcpStream>,
-	buffer: Vec<u8>,
+	socket: &mut WriteHalf<BoxedStream>,
+	buffer: &[u8],
 ) -> Result<(), std::io::Error>
 {
-	socket.write_all(&buffer).await?;
+	socket.write_all(buffer).await?;
 
 	trace!("Sent {} bytes.", buffer.len());
 	Ok(())
 }
 
-fn prepare_message(message: Message) -> Vec<u8>
+fn prepare_message(message: &Message, codec: Codec) -> Vec<u8>
 {
 	if let Message::Pulse = message
 	{
@@ -79,17 +426,89 @@ fn prepare_message(message: Message) -> Vec<u8>
 		return zeroes.to_vec();
 	}
 
-	let (jsonstr, length) = prepare_message_data(message);
+	let (mut data, length) = prepare_message_data(message, codec.encoding);
+
+	if let Some(compression) = codec.compression
+	{
+		if length as usize >= MESSAGE_SIZE_COMPRESSION_THRESHOLD
+		{
+			if let Some(buffer) = compress_message_data(&data, compression)
+			{
+				return buffer;
+			}
+		}
+	}
 
 	let mut buffer = length.to_be_bytes().to_vec();
-	buffer.append(&mut jsonstr.into_bytes());
+	buffer.append(&mut data);
 
 	buffer
 }
 
-fn prepare_message_data(message: Message) -> (String, u32)
+fn compress_message_data(
+	uncompressed: &[u8],
+	compression: Compression,
+) -> Option<Vec<u8>>
 {
-	let jsonstr = match serde_json::to_string(&message)
+	let mut data = match compression::compress(compression, uncompressed)
+	{
+		Ok(data) => data,
+		Err(error) =>
+		{
+			warn!("Failed to compress message: {}", error);
+			return None;
+		}
+	};
+
+	if data.len() >= uncompressed.len()
+	{
+		return None;
+	}
+
+	trace!(
+		"Compressed message of length {} to length {}.",
+		uncompressed.len(),
+		data.len()
+	);
+
+	let length = data.len() as u32 | MESSAGE_LENGTH_COMPRESSED_FLAG;
+	let mut buffer = length.to_be_bytes().to_vec();
+	buffer.append(&mut data);
+
+	Some(buffer)
+}
+
+fn prepare_frame(message: &Message, codec: Codec) -> WebSocketMessage
+{
+	// Browsers cannot send or receive length-prefixed data, so instead of
+	// four zero bytes we send an empty frame.
+	if let Message::Pulse = message
+	{
+		trace!("Sending pulse...");
+
+		return WebSocketMessage::Binary(Vec::new());
+	}
+
+	// WebSocket connections are never compressed, so this is simply the
+	// length-prefixed data and we can strip off the length.
+	let mut data = prepare_message(message, codec);
+	data.drain(..4);
+
+	match codec.encoding
+	{
+		Encoding::Json => match String::from_utf8(data)
+		{
+			Ok(jsonstr) => WebSocketMessage::Text(jsonstr),
+			Err(error) => WebSocketMessage::Binary(error.into_bytes()),
+		},
+		_ => WebSocketMessage::Binary(data),
+	}
+}
+
+fn prepare_message_data(message: &Message, encoding: Encoding)
+	-> (Vec<u8>, u32)
+{
+	let data = match codec::serialize(message, encoding)
 	{
 		Ok(data) => data,
 		Err(e) =>
@@ -98,16 +517,16 @@ fn prepare_message_data(message: Message) -> (String, u32)
 		}
 	};
 
-	if jsonstr.len() >= MESSAGE_SIZE_LIMIT
+	if data.len() >= MESSAGE_SIZE_LIMIT
 	{
 		panic!(
 			"Cannot send message of length {}, \
 			 which is larger than MESSAGE_SIZE_LIMIT.",
-			jsonstr.len()
+			data.len()
 		);
 	}
 
-	let length = jsonstr.len() as u32;
+	let length = data.len() as u32;
 
 	if length as usize >= MESSAGE_SIZE_WARNING_LIMIT
 	{
@@ -120,7 +539,7 @@ fn prepare_message_data(message: Message) -> (String, u32)
 	{
 		trace!(
 			"Sending message: {}",
-			jsonstr
+			codec::preview(&data, encoding)
 				.chars()
 				.take(500)
 				.map(|x| {
@@ -137,7 +556,7 @@ fn prepare_message_data(message: Message) -> (String, u32)
 		);
 	}
 
-	(jsonstr, length)
+	(data, length)
 }
 
 #[derive(Debug)]
@@ -147,6 +566,10 @@ pub enum Error
 	{
 		error: std::io::Error
 	},
+	WebSocket
+	{
+		error: tungstenite::Error
+	},
 }
 
 impl From<std::io::Error> for Error
@@ -157,6 +580,14 @@ impl From<std::io::Error> for Error
 	}
 }
 
+impl From<tungstenite::Error> for Error
+{
+	fn from(error: tungstenite::Error) -> Self
+	{
+		Error::WebSocket { error }
+	}
+}
+
 impl std::fmt::Display for Error
 {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
@@ -164,6 +595,7 @@ impl std::fmt::Display for Error
 		match self
 		{
 			Error::Io { error } => error.fmt(f),
+			Error::WebSocket { error } => error.fmt(f),
 		}
 	}
 }
@@ -0,0 +1,533 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use super::send::Outgoing;
+
+use crate::common::keycode::Keycode;
+use crate::server::message::*;
+use crate::server::metrics;
+
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync;
+use std::sync::atomic;
+
+use log::*;
+
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Policy
+{
+	// Once this many messages are queued, general chat and pulses are no
+	// longer queued at all; other messages are queued until the timeout.
+	pub capacity: usize,
+	// Once more messages than this are queued, low-priority messages are
+	// coalesced or dropped, and the client is considered to be stuck.
+	pub threshold: usize,
+	// How long a client may stay stuck before we disconnect it.
+	pub timeout: Duration,
+}
+
+impl Default for Policy
+{
+	fn default() -> Policy
+	{
+		Policy {
+			capacity: 10000,
+			threshold: 1000,
+			timeout: Duration::from_secs(60),
+		}
+	}
+}
+
+pub fn channel(policy: Policy) -> (Sender, Receiver)
+{
+	let (sender, receiver) = mpsc::unbounded_channel::<Outgoing>();
+	let monitor = sync::Arc::new(Monitor {
+		policy,
+		start: Instant::now(),
+		queued: atomic::AtomicUsize::new(0),
+		bytes_in_flight: atomic::AtomicUsize::new(0),
+		congested_since: atomic::AtomicU64::new(NEVER),
+		writing_since: atomic::AtomicU64::new(NEVER),
+		is_parked: atomic::AtomicBool::new(false),
+	});
+	let sender = Sender {
+		sender,
+		monitor: monitor.clone(),
+	};
+	let receiver = Receiver {
+		receiver,
+		pending: VecDeque::new(),
+		monitor,
+	};
+	(sender, receiver)
+}
+
+const NEVER: u64 = 0;
+
+// Shared between all senders and the receiver of one client. Timestamps are
+// stored as milliseconds since the start, plus one so that zero means never.
+#[derive(Debug)]
+struct Monitor
+{
+	policy: Policy,
+	start: Instant,
+	queued: atomic::AtomicUsize,
+	bytes_in_flight: atomic::AtomicUsize,
+	congested_since: atomic::AtomicU64,
+	writing_since: atomic::AtomicU64,
+	is_parked: atomic::AtomicBool,
+}
+
+impl Monitor
+{
+	fn now(&self) -> u64
+	{
+		self.start.elapsed().as_millis() as u64 + 1
+	}
+
+	fn stuck_for(&self) -> Option<Duration>
+	{
+		if self.is_parked.load(atomic::Ordering::Relaxed)
+		{
+			return None;
+		}
+
+		let now = self.now();
+		let congested_since =
+			self.congested_since.load(atomic::Ordering::Relaxed);
+		let writing_since = self.writing_since.load(atomic::Ordering::Relaxed);
+		[congested_since, writing_since]
+			.iter()
+			.filter(|&&since| since != NEVER)
+			.min()
+			.map(|since| Duration::from_millis(now.saturating_sub(*since)))
+	}
+
+	fn check(&self) -> Result<(), Error>
+	{
+		match self.stuck_for()
+		{
+			Some(duration) if duration > self.policy.timeout =>
+			{
+				Err(self.slow_consumer(duration))
+			}
+			_ => Ok(()),
+		}
+	}
+
+	fn slow_consumer(&self, stuck_for: Duration) -> Error
+	{
+		Error::SlowConsumer {
+			queued: self.queued.load(atomic::Ordering::Relaxed),
+			bytes_in_flight: self
+				.bytes_in_flight
+				.load(atomic::Ordering::Relaxed),
+			stuck_for,
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct Sender
+{
+	sender: mpsc::UnboundedSender<Outgoing>,
+	monitor: sync::Arc<Monitor>,
+}
+
+impl Sender
+{
+	// While the client is parked, nothing is sent and messages pile up until
+	// it resumes its session, so it cannot be a slow consumer. Once it
+	// resumes, it gets the full timeout to catch up.
+	pub fn set_parked(&self, is_parked: bool)
+	{
+		let monitor = &self.monitor;
+		monitor
+			.is_parked
+			.store(is_parked, atomic::Ordering::Relaxed);
+		if !is_parked
+		{
+			let now = monitor.now();
+			for since in &[&monitor.congested_since, &monitor.writing_since]
+			{
+				let _ = since.fetch_update(
+					atomic::Ordering::Relaxed,
+					atomic::Ordering::Relaxed,
+					|since| if since == NEVER { None } else { Some(now) },
+				);
+			}
+		}
+	}
+
+	pub fn try_send(
+		&mut self,
+		message: impl Into<Outgoing>,
+	) -> Result<(), Error>
+	{
+		self.monitor.check()?;
+
+		let message = message.into();
+		let queued =
+			self.monitor.queued.fetch_add(1, atomic::Ordering::Relaxed);
+		if queued >= self.monitor.policy.capacity
+			&& is_expendable(message.message())
+		{
+			self.monitor.queued.fetch_sub(1, atomic::Ordering::Relaxed);
+			return Ok(());
+		}
+
+		match self.sender.send(message)
+		{
+			Ok(()) =>
+			{
+				metrics::SEND_QUEUE_DEPTH.increment();
+				if queued + 1 > self.monitor.policy.threshold
+				{
+					let _ = self.monitor.congested_since.compare_exchange(
+						NEVER,
+						self.monitor.now(),
+						atomic::Ordering::Relaxed,
+						atomic::Ordering::Relaxed,
+					);
+				}
+				Ok(())
+			}
+			Err(mpsc::error::SendError(_)) =>
+			{
+				self.monitor.queued.fetch_sub(1, atomic::Ordering::Relaxed);
+				Err(Error::Closed)
+			}
+		}
+	}
+}
+
+pub struct Receiver
+{
+	receiver: mpsc::UnboundedReceiver<Outgoing>,
+	pending: VecDeque<Outgoing>,
+	monitor: sync::Arc<Monitor>,
+}
+
+impl Receiver
+{
+	pub async fn recv(&mut self, client_id: Keycode) -> Option<Outgoing>
+	{
+		let threshold = self.monitor.policy.threshold;
+
+		while self.pending.is_empty()
+		{
+			let message = self.receiver.recv().await?;
+			self.pending.push_back(message);
+
+			if self.monitor.queued.load(atomic::Ordering::Relaxed) > threshold
+			{
+				while let Ok(message) = self.receiver.try_recv()
+				{
+					self.pending.push_back(message);
+				}
+				let dropped = coalesce(&mut self.pending);
+				if dropped > 0
+				{
+					debug!(
+						"Dropped {} low-priority messages for client {}.",
+						dropped, client_id
+					);
+					self.dequeued(dropped);
+				}
+			}
+		}
+
+		let message = self.pending.pop_front()?;
+		self.dequeued(1);
+		Some(message)
+	}
+
+	fn dequeued(&self, count: usize)
+	{
+		let queued = self
+			.monitor
+			.queued
+			.fetch_sub(count, atomic::Ordering::Relaxed);
+		metrics::SEND_QUEUE_DEPTH.add(-(count as i64));
+		if queued - count <= self.monitor.policy.threshold
+		{
+			self.monitor
+				.congested_since
+				.store(NEVER, atomic::Ordering::Relaxed);
+		}
+	}
+
+	// The write counts as finished when the returned guard is dropped, which
+	// also happens when the write is abandoned halfway.
+	pub fn start_writing(&self, length: usize) -> Writing
+	{
+		self.monitor
+			.bytes_in_flight
+			.fetch_add(length, atomic::Ordering::Relaxed);
+		self.monitor
+			.writing_since
+			.store(self.monitor.now(), atomic::Ordering::Relaxed);
+		Writing {
+			monitor: self.monitor.clone(),
+			length,
+		}
+	}
+}
+
+pub struct Writing
+{
+	monitor: sync::Arc<Monitor>,
+	length: usize,
+}
+
+impl Drop for Writing
+{
+	fn drop(&mut self)
+	{
+		self.monitor
+			.bytes_in_flight
+			.fetch_sub(self.length, atomic::Ordering::Relaxed);
+		self.monitor
+			.writing_since
+			.store(NEVER, atomic::Ordering::Relaxed);
+	}
+}
+
+impl Drop for Receiver
+{
+	fn drop(&mut self)
+	{
+		// Whatever is still queued will never be sent.
+		let queued = self.monitor.queued.load(atomic::Ordering::Relaxed);
+		metrics::SEND_QUEUE_DEPTH.add(-(queued as i64));
+	}
+}
+
+// Removes messages that are superseded by later messages in the queue, or
+// that are not worth sending to a client that cannot keep up anyway.
+// Returns the number of messages removed.
+fn coalesce(pending: &mut VecDeque<Outgoing>) -> usize
+{
+	let mut listed_lobbies = HashSet::new();
+	let mut has_pulse = false;
+
+	// Walk backwards so that we keep the latest version of each message.
+	let mut keep: Vec<bool> = pending
+		.iter()
+		.rev()
+		.map(|message| match message.message()
+		{
+			Message::ListLobby { lobby_id, .. }
+			| Message::DisbandLobby { lobby_id } => listed_lobbies.insert(*lobby_id),
+			Message::Pulse => !std::mem::replace(&mut has_pulse, true),
+			message if is_expendable(message) => false,
+			_ => true,
+		})
+		.collect();
+	keep.reverse();
+
+	let before = pending.len();
+	let mut flags = keep.into_iter();
+	pending.retain(|_| flags.next().unwrap_or(true));
+	before - pending.len()
+}
+
+// Messages that a client that cannot keep up is better off without.
+fn is_expendable(message: &Message) -> bool
+{
+	matches!(
+		message,
+		Message::Pulse
+			| Message::Chat {
+				target: ChatTarget::General,
+				..
+			}
+	)
+}
+
+#[derive(Debug)]
+pub enum Error
+{
+	SlowConsumer
+	{
+		queued: usize,
+		bytes_in_flight: usize,
+		stuck_for: Duration,
+	},
+	Closed,
+}
+
+impl std::fmt::Display for Error
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		match self
+		{
+			Error::SlowConsumer {
+				queued,
+				bytes_in_flight,
+				stuck_for,
+			} => write!(
+				f,
+				"client has been stuck for {}s with {} messages queued \
+				 and {} bytes in flight",
+				stuck_for.as_secs(),
+				queued,
+				bytes_in_flight
+			),
+			Error::Closed => write!(f, "channel closed"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	use crate::common::keycode::keycode;
+
+	fn list_lobby(lobby_id: Keycode, lobby_name: &str) -> Outgoing
+	{
+		Outgoing::from(Message::ListLobby {
+			lobby_id,
+			lobby_name: lobby_name.to_string(),
+			metadata: Default::default(),
+		})
+	}
+
+	#[test]
+	fn test_parked_client_is_not_slow()
+	{
+		let policy = Policy {
+			capacity: 100,
+			threshold: 0,
+			timeout: Duration::from_millis(50),
+		};
+		let (mut sender, _receiver) = channel(policy);
+		let pause = std::time::Duration::from_millis(100);
+		assert!(sender.try_send(Message::Pulse).is_ok());
+		std::thread::sleep(pause);
+		assert!(sender.try_send(Message::Pulse).is_err());
+
+		sender.set_parked(true);
+		assert!(sender.try_send(Message::Pulse).is_ok());
+		std::thread::sleep(pause);
+		assert!(sender.try_send(Message::Pulse).is_ok());
+
+		sender.set_parked(false);
+		assert!(sender.try_send(Message::Pulse).is_ok());
+		std::thread::sleep(pause);
+		assert!(sender.try_send(Message::Pulse).is_err());
+	}
+
+	#[test]
+	fn test_full_queue()
+	{
+		let policy = Policy {
+			capacity: 2,
+			threshold: 1,
+			timeout: Duration::from_millis(50),
+		};
+		let (mut sender, _receiver) = channel(policy);
+		let chat = || Message::Chat {
+			content: "Hello".to_string(),
+			sender: None,
+			target: ChatTarget::General,
+		};
+		assert!(sender.try_send(chat()).is_ok());
+		assert!(sender.try_send(Message::Ping).is_ok());
+		assert!(sender.try_send(chat()).is_ok());
+		assert!(sender.try_send(Message::Ping).is_ok());
+		assert_eq!(sender.monitor.queued.load(atomic::Ordering::Relaxed), 3);
+
+		std::thread::sleep(std::time::Duration::from_millis(100));
+		assert!(sender.try_send(Message::Ping).is_err());
+	}
+
+	#[test]
+	fn test_coalesce()
+	{
+		let a = keycode(1, 1);
+		let b = keycode(2, 2);
+		let mut pending: VecDeque<Outgoing> = vec![
+			list_lobby(a, "First"),
+			Message::Pulse.into(),
+			list_lobby(b, "Other"),
+			Message::Chat {
+				content: "Hello".to_string(),
+				sender: None,
+				target: ChatTarget::General,
+			}
+			.into(),
+			Message::Chat {
+				content: "Hello".to_string(),
+				sender: None,
+				target: ChatTarget::Lobby,
+			}
+			.into(),
+			list_lobby(a, "Second"),
+			Message::DisbandLobby { lobby_id: b }.into(),
+			Message::Pulse.into(),
+		]
+		.into_iter()
+		.collect();
+
+		let dropped = coalesce(&mut pending);
+		assert_eq!(dropped, 4);
+
+		let remaining: Vec<Message> =
+			pending.iter().map(|x| x.message().clone()).collect();
+		assert_eq!(remaining.len(), 4);
+		match &remaining[0]
+		{
+			Message::Chat {
+				target: ChatTarget::Lobby,
+				..
+			} => (),
+			message => panic!("unexpected {:?}", message),
+		}
+		match &remaining[1]
+		{
+			Message::ListLobby { lobby_name, .. } =>
+			{
+				assert_eq!(lobby_name, "Second")
+			}
+			message => panic!("unexpected {:?}", message),
+		}
+		match &remaining[2]
+		{
+			Message::DisbandLobby { lobby_id } => assert_eq!(*lobby_id, b),
+			message => panic!("unexpected {:?}", message),
+		}
+		match &remaining[3]
+		{
+			Message::Pulse => (),
+			message => panic!("unexpected {:?}", message),
+		}
+	}
+}
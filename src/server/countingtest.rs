@@ -102,6 +102,7 @@ async fn run_test_impl(
 
 	let initialmessage = Message::Version {
 		version: fakeversion,
+		metadata: Default::default(),
 	};
 
 	let mut has_quit = QuitStage::None;
@@ -207,7 +208,7 @@ fn handle_message(
 	{
 		Message::Ping => Ok(vec![Message::Pong]),
 		Message::Pong | Message::Pulse => Ok(Vec::new()),
-		Message::Version { version: _ } => Ok(vec![Message::JoinServer {
+		Message::Version { .. } => Ok(vec![Message::JoinServer {
 			status: None,
 			content: Some("".to_string()),
 			sender: Some((1000 + number).to_string()),
@@ -290,7 +291,7 @@ fn handle_message(
 				Ok(Vec::new())
 			}
 		}
-		Message::Closing =>
+		Message::Closing { .. } =>
 		{
 			error!("[{}] Server closing unexpectedly", number);
 			Err(())
@@ -32,6 +32,9 @@ use serde_json::json;
 
 use anyhow::anyhow;
 
+use futures::stream;
+use futures::StreamExt;
+
 use tokio::sync::mpsc;
 use tokio::time::Duration;
 
@@ -111,39 +114,64 @@ pub fn setup(settings: &Settings) -> Result<Setup, anyhow::Error>
 	}
 }
 
-pub async fn run(setup: Setup, mut posts: mpsc::Receiver<Post>)
+pub async fn run(
+	setup: Setup,
+	posts: mpsc::Receiver<Post>,
+	reloads: mpsc::Receiver<Setup>,
+)
 {
-	match setup
+	let mut connection = setup.connection;
+	if connection.is_some()
 	{
-		Setup {
-			connection: Some(connection),
-		} =>
-		{
-			info!("Connected.");
-			while let Some(post) = posts.recv().await
-			{
-				connection.send(post).await;
-			}
-			info!("Finished sending posts to Discord.");
-		}
-		Setup { connection: None } =>
+		info!("Connected.");
+	}
+
+	let mut events = stream::select(
+		posts.map(Event::Post),
+		reloads.map(|setup| Event::Reload(setup.connection)),
+	);
+	while let Some(event) = events.next().await
+	{
+		match event
 		{
-			while let Some(post) = posts.recv().await
+			Event::Post(post) => match connection
 			{
-				let message = match serde_json::to_string(&post)
+				Some(ref connection) => connection.send(post).await,
+				None => match serde_json::to_string(&post)
 				{
-					Ok(message) => message,
+					Ok(message) => debug!("{}", message),
 					Err(error) =>
 					{
 						error!("Error while jsonifying: {:?}", error);
 						debug!("Original post: {:?}", post);
-						continue;
 					}
-				};
-				debug!("{}", message);
+				},
+			},
+			Event::Reload(new_connection) =>
+			{
+				connection = new_connection;
+				if connection.is_some()
+				{
+					info!("Reconnected.");
+				}
+				else
+				{
+					info!("Disconnected.");
+				}
 			}
 		}
 	}
+
+	if connection.is_some()
+	{
+		info!("Finished sending posts to Discord.");
+	}
+}
+
+enum Event
+{
+	Post(Post),
+	Reload(Option<Connection>),
 }
 
 struct Connection
@@ -36,19 +36,29 @@ use crate::logic::player::PlayerColor;
 use crate::server::botslot::Botslot;
 use crate::server::chat;
 use crate::server::client;
+use crate::server::client::drain;
 use crate::server::discord_api;
+use crate::server::hibernation;
 use crate::server::lobby;
 use crate::server::lobby::LobbyType;
 use crate::server::lobby::Update;
 use crate::server::login::UserId;
 use crate::server::message::*;
 use crate::server::rating;
+use crate::server::tokio::State as ServerState;
 
 use std::fmt;
 
 use log::*;
 
+use serde_derive::{Deserialize, Serialize};
+
+use futures::future;
+use futures::stream;
+use futures::StreamExt;
+
 use tokio::sync::mpsc;
+use tokio::sync::watch;
 use tokio::time as timer;
 use tokio::time::{Duration, Instant};
 
@@ -107,6 +117,7 @@ pub struct BotClient
 {
 	pub slot: Botslot,
 	pub difficulty: Difficulty,
+	pub ai_name: String,
 	pub descriptive_name: String,
 	pub ai_metadata: ai::Metadata,
 	pub forwarding_metadata: ForwardingMetadata,
@@ -139,6 +150,8 @@ impl BotClient
 pub struct LocalBot
 {
 	pub slot: Botslot,
+	pub difficulty: Difficulty,
+	pub ai_name: String,
 	pub ai: ai::Commander,
 
 	pub color: PlayerColor,
@@ -201,8 +214,12 @@ pub async fn run(
 	setup: Setup,
 	discord_api: mpsc::Sender<discord_api::Post>,
 	updates: mpsc::Receiver<Update>,
+	server_state: watch::Receiver<ServerState>,
+	drain_deadline: Option<Duration>,
 ) -> Result<(), Error>
 {
+	let updates = with_shutdown(updates, server_state, drain_deadline);
+
 	if setup.host.is_some()
 	{
 		run_client_hosted_game(setup, updates).await
@@ -216,7 +233,7 @@ pub async fn run(
 pub async fn run_server_game(
 	setup: Setup,
 	mut discord_api: mpsc::Sender<discord_api::Post>,
-	mut updates: mpsc::Receiver<Update>,
+	updates: Updates,
 ) -> Result<(), Error>
 {
 	if !setup.hosted_bots.is_empty()
@@ -335,35 +352,35 @@ pub async fn run_server_game(
 	}
 
 	// Tell everyone who is playing as which color.
-	let mut initial_messages = Vec::new();
+	let mut initial_messages: Vec<client::Outgoing> = Vec::new();
 	for player in &players
 	{
-		initial_messages.push(Message::AssignColor {
+		initial_messages.push(client::Outgoing::from(Message::AssignColor {
 			color: player.color,
 			name: player.username.clone(),
-		});
+		}));
 	}
 	for bot in &mut connected_bots
 	{
 		let descriptive_name = bot.descriptive_name.clone();
-		initial_messages.push(Message::AssignColor {
+		initial_messages.push(client::Outgoing::from(Message::AssignColor {
 			color: bot.color,
 			name: descriptive_name,
-		});
+		}));
 	}
 	for bot in &mut local_bots
 	{
 		let descriptive_name = bot.ai.descriptive_name()?;
-		initial_messages.push(Message::AssignColor {
+		initial_messages.push(client::Outgoing::from(Message::AssignColor {
 			color: bot.color,
 			name: descriptive_name,
-		});
+		}));
 	}
 
 	// Tell everyone which skins are being used.
-	initial_messages.push(Message::Skins {
+	initial_messages.push(client::Outgoing::from(Message::Skins {
 		metadata: map_metadata.clone(),
-	});
+	}));
 
 	// A challenge might be set.
 	if lobby_type == LobbyType::Challenge
@@ -376,7 +393,7 @@ pub async fn run_server_game(
 		automaton.set_challenge(challenge_id)?;
 
 		let briefing = challenge::load_briefing(challenge_id)?;
-		initial_messages.push(Message::Briefing { briefing });
+		initial_messages.push(Message::Briefing { briefing }.into());
 	}
 
 	// Send the initial messages.
@@ -542,27 +559,122 @@ pub async fn run_server_game(
 		initial_messages,
 	};
 
-	loop
+	play(
+		lobby_info,
+		lobby_type,
+		automaton,
+		players,
+		connected_bots,
+		local_bots,
+		watchers,
+		updates,
+		discord_api,
+		is_rated,
+		mentioned_on_discord,
+		false,
+	)
+	.await
+}
+
+async fn play(
+	lobby_info: LobbyInfo,
+	lobby_type: LobbyType,
+	mut automaton: Automaton,
+	mut players: Vec<PlayerClient>,
+	mut connected_bots: Vec<BotClient>,
+	mut local_bots: Vec<LocalBot>,
+	mut watchers: Vec<WatcherClient>,
+	mut updates: Updates,
+	mut discord_api: mpsc::Sender<discord_api::Post>,
+	is_rated: bool,
+	mentioned_on_discord: Option<MentionedOnDiscord>,
+	is_resumed: bool,
+) -> Result<(), Error>
+{
+	let lobby_id = lobby_info.id;
+	let planning_time_in_seconds = lobby_info.planning_time_in_seconds;
+
+	// A resumed game cannot continue until at least one player has rejoined.
+	let mut is_waiting_for_rejoin = is_resumed;
+
+	let state = loop
 	{
-		let state = iterate(
+		let result = if is_waiting_for_rejoin
+		{
+			is_waiting_for_rejoin = false;
+			check(
+				&lobby_info,
+				&mut automaton,
+				&mut players,
+				&mut connected_bots,
+				&mut watchers,
+				&mut updates,
+			)
+			.await
+			.map(|()| State::InProgress)
+		}
+		else
+		{
+			iterate(
+				&lobby_info,
+				&mut automaton,
+				&mut players,
+				&mut connected_bots,
+				&mut local_bots,
+				&mut watchers,
+				&mut updates,
+				planning_time_in_seconds,
+			)
+			.await
+		};
+		let state = match result
+		{
+			Err(Error::Aborted) => State::Aborted,
+			Err(Error::Hibernated) =>
+			{
+				let game = describe_hibernated_game(
+					&lobby_info,
+					lobby_type,
+					&mut automaton,
+					&players,
+					&connected_bots,
+					&mut local_bots,
+					&watchers,
+				)?;
+				hibernation::hibernate(game);
+				State::Aborted
+			}
+			result => result?,
+		};
+
+		match state
+		{
+			State::InProgress => (),
+			State::Finished => break state,
+			State::Abandoned => break state,
+			State::AbandonedByHost => break state,
+			State::Aborted =>
+			{
+				announce_abort(&mut players, &mut watchers);
+				break state;
+			}
+		}
+	};
+	crate::common::log::set_round(None);
+
+	// An aborted game has no outcome, so we do not post or adjust ratings.
+	if state == State::Aborted
+	{
+		debug!("Game was aborted in lobby {}; lingering...", lobby_id);
+		linger(
 			&lobby_info,
-			&mut automaton,
 			&mut players,
 			&mut connected_bots,
-			&mut local_bots,
 			&mut watchers,
 			&mut updates,
-			planning_time_in_seconds,
 		)
 		.await?;
-
-		match state
-		{
-			State::InProgress => (),
-			State::Finished => break,
-			State::Abandoned => break,
-			State::AbandonedByHost => break,
-		}
+		return Ok(());
 	}
 
 	// Did we send a gameStarted post?
@@ -604,9 +716,251 @@ pub async fn run_server_game(
 	Ok(())
 }
 
+// A game that was interrupted when the server last shut down is restored from
+// its recording. None of its players are connected at first, but they can
+// rejoin it just like any other game that they were disconnected from.
+pub async fn resume(
+	game: hibernation::Game,
+	ratings: mpsc::Sender<rating::Update>,
+	discord_api: mpsc::Sender<discord_api::Post>,
+	lobby_sendbuffer: mpsc::Sender<Update>,
+	mut general_chat: mpsc::Sender<chat::Update>,
+	updates: mpsc::Receiver<Update>,
+	server_state: watch::Receiver<ServerState>,
+	drain_deadline: Option<Duration>,
+) -> Result<(), Error>
+{
+	let updates = with_shutdown(updates, server_state, drain_deadline);
+
+	let hibernation::Game {
+		lobby_id,
+		lobby_name,
+		lobby_type,
+		description_metadata,
+		match_type,
+		challenge: challenge_key,
+		map_name,
+		ruleset_name,
+		planning_time_in_seconds,
+		recording_name,
+		round,
+		players,
+		bots,
+		watchers,
+	} = game;
+
+	let mut playercolors = Vec::new();
+	for player in &players
+	{
+		playercolors.push(player.color);
+	}
+	for bot in &bots
+	{
+		playercolors.push(bot.color);
+	}
+
+	let mut automaton = Automaton::create(playercolors, &ruleset_name)?;
+
+	// Certain players might have global vision.
+	for player in &players
+	{
+		match player.vision
+		{
+			VisionType::Normal => (),
+			VisionType::Global => automaton.grant_global_vision(player.color),
+		}
+	}
+	for bot in &bots
+	{
+		match bot.vision
+		{
+			VisionType::Normal => (),
+			VisionType::Global => automaton.grant_global_vision(bot.color),
+		}
+	}
+
+	let challenge_id = match &challenge_key
+	{
+		Some(key) => challenge::load_pool()?
+			.into_iter()
+			.find(|x| &x.key == key)
+			.map(|x| x.id),
+		None => None,
+	};
+	if lobby_type == LobbyType::Challenge
+	{
+		let challenge_id = challenge_id.ok_or(Error::MissingChallengeId)?;
+		automaton.set_challenge(challenge_id)?;
+	}
+
+	automaton.restore(recording_name)?;
+	if automaton.current_round() != round
+	{
+		return Err(Error::RestoreFailed {
+			round,
+			restored_round: automaton.current_round(),
+		});
+	}
+
+	// The ids of their clients will be filled in when they rejoin.
+	let placeholder_id = || keycode(rand::random(), rand::random());
+	let players: Vec<PlayerClient> = players
+		.into_iter()
+		.map(|x| (placeholder_id(), x))
+		.map(|(id, x)| PlayerClient {
+			id,
+			user_id: x.user_id,
+			username: x.username,
+			handle: client::Handle::Disconnected { id },
+			rating_callback: Some(ratings.clone()),
+			color: x.color,
+			vision: x.vision,
+			is_defeated: automaton.is_defeated(x.color),
+			has_synced: false,
+			submitted_orders: None,
+		})
+		.collect();
+	let watchers: Vec<WatcherClient> = watchers
+		.into_iter()
+		.map(|x| (placeholder_id(), x))
+		.map(|(id, x)| WatcherClient {
+			id,
+			user_id: x.user_id,
+			username: x.username,
+			handle: client::Handle::Disconnected { id },
+			role: x.role,
+			vision_level: x.vision_level,
+			has_synced: false,
+		})
+		.collect();
+
+	// Bots that were connected to the server play on as local bots, because
+	// we have no way of knowing if and when their clients will return.
+	// A bot without a local AI of the same name sits out the rest of the game.
+	let num_bots = bots.len();
+	let mut local_bots = Vec::new();
+	let mut initial_messages: Vec<client::Outgoing> = Vec::new();
+	for player in &players
+	{
+		initial_messages.push(client::Outgoing::from(Message::AssignColor {
+			color: player.color,
+			name: player.username.clone(),
+		}));
+	}
+	for bot in bots
+	{
+		initial_messages.push(client::Outgoing::from(Message::AssignColor {
+			color: bot.color,
+			name: bot.descriptive_name,
+		}));
+
+		if !ai::exists(&bot.ai_name)
+		{
+			warn!(
+				"Cannot resume bot {} in lobby {}: there is no local AI '{}'.",
+				bot.slot, lobby_id, bot.ai_name
+			);
+			continue;
+		}
+
+		let mut ai = ai::Commander::create(
+			&bot.ai_name,
+			bot.color,
+			bot.difficulty,
+			&ruleset_name,
+			bot.slot.get_character(),
+		)?;
+		let cset = automaton.rejoin(bot.color)?;
+		ai.receive(cset.get(bot.color))?;
+
+		local_bots.push(LocalBot {
+			slot: bot.slot,
+			difficulty: bot.difficulty,
+			ai_name: bot.ai_name,
+			ai,
+			color: bot.color,
+			vision: bot.vision,
+			is_defeated: automaton.is_defeated(bot.color),
+		});
+	}
+
+	let map_metadata = map::load_metadata(&map_name).await?;
+	initial_messages.push(client::Outgoing::from(Message::Skins {
+		metadata: map_metadata.clone(),
+	}));
+	if let (LobbyType::Challenge, Some(challenge_id)) =
+		(lobby_type, challenge_id)
+	{
+		let briefing = challenge::load_briefing(challenge_id)?;
+		initial_messages.push(Message::Briefing { briefing }.into());
+	}
+
+	// The game was announced on Discord before it was interrupted.
+	let is_rated = !matches!(match_type, MatchType::Unrated);
+	let mentioned_on_discord = if players.len() == 2 && num_bots == 0
+	{
+		Some(MentionedOnDiscord {
+			first_color: players[0].color,
+			second_color: players[1].color,
+			first_player_username: players[0].username.clone(),
+			second_player_username: players[1].username.clone(),
+		})
+	}
+	else
+	{
+		None
+	};
+
+	let lobby_info = LobbyInfo {
+		id: lobby_id,
+		name: lobby_name.clone(),
+		description_metadata,
+		is_public: description_metadata.is_public,
+		match_type,
+		challenge: challenge_key,
+		num_bots,
+		map_name,
+		map_metadata,
+		ruleset_name,
+		planning_time_in_seconds,
+		initial_messages,
+	};
+
+	// List the lobby again, so that its players can find it to rejoin.
+	let (_name_in, name_out) = watch::channel(lobby_name);
+	let (_metadata_in, metadata_out) = watch::channel(description_metadata);
+	let update = chat::Update::ListLobby {
+		lobby_id,
+		name: name_out,
+		metadata: metadata_out,
+		sendbuffer: lobby_sendbuffer,
+	};
+	general_chat.send(update).await?;
+	let update = chat::Update::GameStarted { lobby_id };
+	general_chat.send(update).await?;
+
+	info!("Resumed game in lobby {} at round {}.", lobby_id, round);
+
+	play(
+		lobby_info,
+		lobby_type,
+		automaton,
+		players,
+		Vec::new(),
+		local_bots,
+		watchers,
+		updates,
+		discord_api,
+		is_rated,
+		mentioned_on_discord,
+		true,
+	)
+	.await
+}
+
 pub async fn run_client_hosted_game(
 	setup: Setup,
-	mut updates: mpsc::Receiver<Update>,
+	mut updates: Updates,
 ) -> Result<(), Error>
 {
 	if (setup.lobby_type != LobbyType::Custom
@@ -663,26 +1017,26 @@ pub async fn run_client_hosted_game(
 	}
 
 	// Tell everyone who is playing as which color.
-	let mut initial_messages = Vec::new();
+	let mut initial_messages: Vec<client::Outgoing> = Vec::new();
 	for player in &players
 	{
-		initial_messages.push(Message::AssignColor {
+		initial_messages.push(client::Outgoing::from(Message::AssignColor {
 			color: player.color,
 			name: player.username.clone(),
-		});
+		}));
 	}
 	for bot in &hosted_bots
 	{
-		initial_messages.push(Message::AssignColor {
+		initial_messages.push(client::Outgoing::from(Message::AssignColor {
 			color: bot.color,
 			name: bot.descriptive_name.clone(),
-		});
+		}));
 	}
 
 	// Tell everyone which skins are being used.
-	initial_messages.push(Message::Skins {
+	initial_messages.push(client::Outgoing::from(Message::Skins {
 		metadata: map_metadata.clone(),
-	});
+	}));
 
 	// Send the initial messages.
 	for client in &mut players
@@ -717,7 +1071,7 @@ pub async fn run_client_hosted_game(
 
 	loop
 	{
-		let state = iterate_client_hosted_game(
+		let state = match iterate_client_hosted_game(
 			&lobby_info,
 			&mut host,
 			&mut players,
@@ -725,20 +1079,30 @@ pub async fn run_client_hosted_game(
 			&mut updates,
 			planning_time_in_seconds,
 		)
-		.await?;
+		.await
+		{
+			Err(Error::Aborted) => State::Aborted,
+			Err(Error::Hibernated) => State::Aborted,
+			result => result?,
+		};
 
 		match state
 		{
 			State::InProgress => (),
 			State::Finished => break,
 			State::Abandoned => break,
+			State::Aborted =>
+			{
+				announce_abort(&mut players, &mut watchers);
+				break;
+			}
 			State::AbandonedByHost =>
 			{
-				let message = Message::Chat {
+				let message = client::Outgoing::from(Message::Chat {
 					content: "Game interrupted: host left.".to_string(),
 					sender: Some("server".to_string()),
 					target: ChatTarget::Lobby,
-				};
+				});
 				for client in players.iter_mut()
 				{
 					client.handle.send(message.clone());
@@ -771,6 +1135,42 @@ pub async fn run_client_hosted_game(
 	Ok(())
 }
 
+type Updates = stream::BoxStream<'static, Update>;
+
+// Games interrupt themselves when the drain deadline passes or when the server
+// has closed, even if none of their clients is around to tell them to.
+fn with_shutdown(
+	updates: mpsc::Receiver<Update>,
+	server_state: watch::Receiver<ServerState>,
+	drain_deadline: Option<Duration>,
+) -> Updates
+{
+	let drain_state = server_state.clone();
+	let deadline_passed = stream::iter(drain_deadline)
+		.flat_map(move |deadline| {
+			drain::countdown(drain_state.clone(), deadline)
+		})
+		.filter(|event| match event
+		{
+			drain::Event::Countdown { .. } => future::ready(false),
+			drain::Event::DeadlinePassed => future::ready(true),
+		})
+		.map(|_| ());
+	let closed = server_state
+		.filter(|state| future::ready(*state == ServerState::Closed))
+		.take(1)
+		.map(|_| ());
+	let hibernations = stream::select(deadline_passed, closed)
+		.map(|()| Update::ForGame(Sub::Hibernate));
+
+	// The game ends once its lobby is no longer reachable.
+	let updates = updates.map(Some).chain(stream::iter(std::iter::once(None)));
+	stream::select(updates, hibernations.map(Some))
+		.take_while(|update| future::ready(update.is_some()))
+		.filter_map(future::ready)
+		.boxed()
+}
+
 #[derive(Debug)]
 pub enum Sub
 {
@@ -781,11 +1181,11 @@ pub enum Sub
 	},
 	Sync
 	{
-		client_id: Keycode
+		client_id: Keycode,
 	},
 	Resign
 	{
-		client_id: Keycode
+		client_id: Keycode,
 	},
 	BotOrders
 	{
@@ -793,6 +1193,8 @@ pub enum Sub
 		slot: Botslot,
 		orders: Vec<Order>,
 	},
+	Abort,
+	Hibernate,
 }
 
 #[derive(Debug)]
@@ -837,7 +1239,7 @@ struct LobbyInfo
 	ruleset_name: String,
 	planning_time_in_seconds: Option<u32>,
 	description_metadata: LobbyMetadata,
-	initial_messages: Vec<Message>,
+	initial_messages: Vec<client::Outgoing>,
 }
 
 struct MentionedOnDiscord
@@ -855,6 +1257,77 @@ enum State
 	Finished,
 	Abandoned,
 	AbandonedByHost,
+	Aborted,
+}
+
+fn describe_hibernated_game(
+	lobby: &LobbyInfo,
+	lobby_type: LobbyType,
+	automaton: &mut Automaton,
+	players: &[PlayerClient],
+	connected_bots: &[BotClient],
+	local_bots: &mut [LocalBot],
+	watchers: &[WatcherClient],
+) -> Result<hibernation::Game, Error>
+{
+	let players = players
+		.iter()
+		.filter(|x| !x.is_retired())
+		.map(|x| hibernation::Player {
+			user_id: x.user_id,
+			username: x.username.clone(),
+			color: x.color,
+			vision: x.vision,
+		})
+		.collect();
+	let mut bots: Vec<hibernation::Bot> = connected_bots
+		.iter()
+		.map(|x| hibernation::Bot {
+			slot: x.slot,
+			ai_name: x.ai_name.clone(),
+			difficulty: x.difficulty,
+			descriptive_name: x.descriptive_name.clone(),
+			color: x.color,
+			vision: x.vision,
+		})
+		.collect();
+	for bot in local_bots.iter_mut()
+	{
+		bots.push(hibernation::Bot {
+			slot: bot.slot,
+			ai_name: bot.ai_name.clone(),
+			difficulty: bot.difficulty,
+			descriptive_name: bot.ai.descriptive_name()?,
+			color: bot.color,
+			vision: bot.vision,
+		});
+	}
+	let watchers = watchers
+		.iter()
+		.map(|x| hibernation::Watcher {
+			user_id: x.user_id,
+			username: x.username.clone(),
+			role: x.role,
+			vision_level: x.vision_level,
+		})
+		.collect();
+
+	Ok(hibernation::Game {
+		lobby_id: lobby.id,
+		lobby_name: lobby.name.clone(),
+		lobby_type,
+		description_metadata: lobby.description_metadata,
+		match_type: lobby.match_type,
+		challenge: lobby.challenge.clone(),
+		map_name: lobby.map_name.clone(),
+		ruleset_name: lobby.ruleset_name.clone(),
+		planning_time_in_seconds: lobby.planning_time_in_seconds,
+		recording_name: lobby.id.to_string(),
+		round: automaton.current_round(),
+		players,
+		bots,
+		watchers,
+	})
 }
 
 async fn iterate(
@@ -864,10 +1337,12 @@ async fn iterate(
 	connected_bots: &mut Vec<BotClient>,
 	local_bots: &mut Vec<LocalBot>,
 	watchers: &mut Vec<WatcherClient>,
-	updates: &mut mpsc::Receiver<Update>,
+	updates: &mut Updates,
 	planning_time_in_seconds: Option<u32>,
 ) -> Result<State, Error>
 {
+	crate::common::log::set_round(Some(automaton.current_round()));
+
 	while automaton.is_active()
 	{
 		let cset = automaton.act()?;
@@ -918,9 +1393,9 @@ async fn iterate(
 	// and has finished rejoining.
 	check(lobby, automaton, players, connected_bots, watchers, updates).await?;
 
-	let message = Message::Sync {
+	let message = client::Outgoing::from(Message::Sync {
 		time_remaining_in_seconds: planning_time_in_seconds,
-	};
+	});
 	for client in players.iter_mut()
 	{
 		client.has_synced = false;
@@ -983,7 +1458,7 @@ async fn iterate_client_hosted_game(
 	host: &mut HostClient,
 	players: &mut Vec<PlayerClient>,
 	watchers: &mut Vec<WatcherClient>,
-	updates: &mut mpsc::Receiver<Update>,
+	updates: &mut Updates,
 	planning_time_in_seconds: Option<u32>,
 ) -> Result<State, Error>
 {
@@ -1016,9 +1491,9 @@ async fn iterate_client_hosted_game(
 	// and has finished rejoining.
 	check(lobby, host, players, &mut Vec::new(), watchers, updates).await?;
 
-	let message = Message::Sync {
+	let message = client::Outgoing::from(Message::Sync {
 		time_remaining_in_seconds: planning_time_in_seconds,
-	};
+	});
 	for client in players.into_iter()
 	{
 		client.has_synced = false;
@@ -1107,12 +1582,26 @@ fn broadcast(
 		bot.ai.receive(changes)?;
 	}
 
+	// Watchers with the same vision level receive the same changes, so we
+	// only need to serialize them once.
+	let mut messages_for_watchers: Vec<(PlayerColor, client::Outgoing)> =
+		Vec::new();
 	for client in watchers
 	{
-		let changes = cset.get(client.vision_level);
-		let message = Message::Changes {
-			changes,
-			forwarding: None,
+		let vision = client.vision_level;
+		let message = match messages_for_watchers.iter().find(|x| x.0 == vision)
+		{
+			Some((_, message)) => message.clone(),
+			None =>
+			{
+				let changes = cset.get(vision);
+				let message = client::Outgoing::from(Message::Changes {
+					changes,
+					forwarding: None,
+				});
+				messages_for_watchers.push((vision, message.clone()));
+				message
+			}
 		};
 		client.handle.send(message);
 	}
@@ -1135,7 +1624,7 @@ async fn rest(
 	players: &mut Vec<PlayerClient>,
 	bots: &mut Vec<BotClient>,
 	watchers: &mut Vec<WatcherClient>,
-	updates: &mut mpsc::Receiver<Update>,
+	updates: &mut Updates,
 ) -> Result<(), Error>
 {
 	// Start the planning phase when all players (or all watchers if
@@ -1149,7 +1638,7 @@ async fn rest(
 	{
 		trace!("Waiting until all players/watchers have synced...");
 
-		let update = match updates.recv().await
+		let update = match updates.next().await
 		{
 			Some(update) => update,
 			None => return Err(Error::Abandoned),
@@ -1182,6 +1671,8 @@ async fn rest(
 					}
 				}
 			}
+			Update::ForGame(Sub::Abort) => return Err(Error::Aborted),
+			Update::ForGame(Sub::Hibernate) => return Err(Error::Hibernated),
 			Update::ForGame(Sub::Resign { client_id }) =>
 			{
 				handle_resign(lobby, handler, players, client_id).await?;
@@ -1251,6 +1742,7 @@ async fn rest(
 			}
 			Update::Msg(message) =>
 			{
+				let message = client::Outgoing::from(message);
 				for client in players.iter_mut()
 				{
 					client.handle.send(message.clone());
@@ -1293,7 +1785,7 @@ async fn check(
 	players: &mut Vec<PlayerClient>,
 	bots: &mut Vec<BotClient>,
 	watchers: &mut Vec<WatcherClient>,
-	updates: &mut mpsc::Receiver<Update>,
+	updates: &mut Updates,
 ) -> Result<(), Error>
 {
 	if players.is_empty()
@@ -1305,7 +1797,7 @@ async fn check(
 	{
 		trace!("Waiting for at least one live player...");
 
-		let update = match updates.recv().await
+		let update = match updates.next().await
 		{
 			Some(update) => update,
 			None => return Err(Error::Abandoned),
@@ -1325,6 +1817,8 @@ async fn check(
 			{
 				debug!("Ignoring sync from {} after resting", client_id);
 			}
+			Update::ForGame(Sub::Abort) => return Err(Error::Aborted),
+			Update::ForGame(Sub::Hibernate) => return Err(Error::Hibernated),
 			Update::ForGame(Sub::Resign { client_id }) =>
 			{
 				handle_resign(lobby, handler, players, client_id).await?;
@@ -1394,6 +1888,7 @@ async fn check(
 			}
 			Update::Msg(message) =>
 			{
+				let message = client::Outgoing::from(message);
 				for client in players.iter_mut()
 				{
 					client.handle.send(message.clone());
@@ -1424,7 +1919,7 @@ async fn sleep(
 	players: &mut Vec<PlayerClient>,
 	connected_bots: &mut Vec<BotClient>,
 	watchers: &mut Vec<WatcherClient>,
-	updates: &mut mpsc::Receiver<Update>,
+	updates: &mut Updates,
 ) -> Result<(), Error>
 {
 	let num_bots = lobby.num_bots;
@@ -1458,7 +1953,7 @@ async fn sleep(
 	{
 		trace!("Waiting until all players have submitted orders...");
 
-		let update = match timer::timeout_at(end, updates.recv()).await
+		let update = match timer::timeout_at(end, updates.next()).await
 		{
 			Ok(Some(update)) => update,
 			Ok(None) => return Err(Error::Abandoned),
@@ -1504,6 +1999,8 @@ async fn sleep(
 			{
 				debug!("Ignoring sync from {} while sleeping", client_id);
 			}
+			Update::ForGame(Sub::Abort) => return Err(Error::Aborted),
+			Update::ForGame(Sub::Hibernate) => return Err(Error::Hibernated),
 			Update::ForGame(Sub::Resign { client_id }) =>
 			{
 				handle_resign(lobby, handler, players, client_id).await?;
@@ -1592,6 +2089,7 @@ async fn sleep(
 			}
 			Update::Msg(message) =>
 			{
+				let message = client::Outgoing::from(message);
 				for client in players.iter_mut()
 				{
 					client.handle.send(message.clone());
@@ -1643,7 +2141,7 @@ async fn stage(
 	players: &mut Vec<PlayerClient>,
 	connected_bots: &mut Vec<BotClient>,
 	watchers: &mut Vec<WatcherClient>,
-	updates: &mut mpsc::Receiver<Update>,
+	updates: &mut Updates,
 ) -> Result<(), Error>
 {
 	let start = Instant::now();
@@ -1658,7 +2156,7 @@ async fn stage(
 	{
 		trace!("Waiting until all players have staged orders...");
 
-		let update = match timer::timeout_at(end, updates.recv()).await
+		let update = match timer::timeout_at(end, updates.next()).await
 		{
 			Ok(Some(update)) => update,
 			Ok(None) => return Err(Error::Abandoned),
@@ -1702,6 +2200,8 @@ async fn stage(
 			{
 				debug!("Ignoring sync from {} while staging", client_id);
 			}
+			Update::ForGame(Sub::Abort) => return Err(Error::Aborted),
+			Update::ForGame(Sub::Hibernate) => return Err(Error::Hibernated),
 			Update::ForGame(Sub::Resign { client_id }) =>
 			{
 				handle_resign(lobby, handler, players, client_id).await?;
@@ -1771,6 +2271,7 @@ async fn stage(
 			}
 			Update::Msg(message) =>
 			{
+				let message = client::Outgoing::from(message);
 				for client in players.iter_mut()
 				{
 					client.handle.send(message.clone());
@@ -1793,7 +2294,7 @@ async fn sync_host(
 	host: &mut HostClient,
 	players: &mut Vec<PlayerClient>,
 	watchers: &mut Vec<WatcherClient>,
-	updates: &mut mpsc::Receiver<Update>,
+	updates: &mut Updates,
 ) -> Result<(), Error>
 {
 	host.handle.send(Message::HostSync { metadata: None });
@@ -1811,7 +2312,7 @@ async fn sync_host(
 			return Ok(());
 		}
 
-		let update = match updates.recv().await
+		let update = match updates.next().await
 		{
 			Some(update) => update,
 			None => return Err(Error::Abandoned),
@@ -1853,9 +2354,9 @@ async fn sync_host(
 			{
 				if client_id == host.id
 				{
-					let message = Message::Briefing {
+					let message = client::Outgoing::from(Message::Briefing {
 						briefing: briefing.clone(),
-					};
+					});
 					for client in players.iter_mut()
 					{
 						client.handle.send(message.clone());
@@ -1897,6 +2398,8 @@ async fn sync_host(
 			{
 				debug!("Ignoring sync from {} while hostsyncing", client_id);
 			}
+			Update::ForGame(Sub::Abort) => return Err(Error::Aborted),
+			Update::ForGame(Sub::Hibernate) => return Err(Error::Hibernated),
 			Update::ForGame(Sub::Resign { client_id }) =>
 			{
 				if let Some(client) = players.iter().find(|x| x.id == client_id)
@@ -1979,6 +2482,7 @@ async fn sync_host(
 			{}
 			Update::Msg(message) =>
 			{
+				let message = client::Outgoing::from(message);
 				for client in players.iter_mut()
 				{
 					client.handle.send(message.clone());
@@ -2023,15 +2527,32 @@ fn forward_changes(
 	}
 }
 
+fn announce_abort(players: &mut [PlayerClient], watchers: &mut [WatcherClient])
+{
+	let message = client::Outgoing::from(Message::Chat {
+		content: "Game ended by the server.".to_string(),
+		sender: Some("server".to_string()),
+		target: ChatTarget::Lobby,
+	});
+	for client in players.iter_mut()
+	{
+		client.handle.send(message.clone());
+	}
+	for client in watchers.iter_mut()
+	{
+		client.handle.send(message.clone());
+	}
+}
+
 async fn linger(
 	lobby: &LobbyInfo,
 	players: &mut Vec<PlayerClient>,
 	bots: &mut Vec<BotClient>,
 	watchers: &mut Vec<WatcherClient>,
-	updates: &mut mpsc::Receiver<Update>,
+	updates: &mut Updates,
 ) -> Result<(), Error>
 {
-	while let Some(update) = updates.recv().await
+	while let Some(update) = updates.next().await
 	{
 		match update
 		{
@@ -2063,6 +2584,7 @@ async fn linger(
 			{}
 			Update::Msg(message) =>
 			{
+				let message = client::Outgoing::from(message);
 				for client in players.iter_mut()
 				{
 					client.handle.send(message.clone());
@@ -2192,11 +2714,11 @@ fn do_join(
 	}
 
 	// Tell everyone who the newcomer is.
-	let message = Message::JoinLobby {
+	let message = client::Outgoing::from(Message::JoinLobby {
 		lobby_id: Some(lobby.id),
 		username: Some(client_username.clone()),
 		invite: None,
-	};
+	});
 	for other in players.iter_mut()
 	{
 		other.handle.send(message.clone());
@@ -2621,10 +3143,10 @@ async fn handle_leave(
 		}
 	};
 
-	let message = Message::LeaveLobby {
+	let message = client::Outgoing::from(Message::LeaveLobby {
 		lobby_id: Some(lobby_id),
 		username: Some(username),
-	};
+	});
 
 	for client in players.iter_mut()
 	{
@@ -2657,6 +3179,8 @@ async fn handle_leave(
 pub enum Error
 {
 	Abandoned,
+	Aborted,
+	Hibernated,
 	InvalidSetup,
 	MissingChallengeId,
 	ClientGone
@@ -2675,6 +3199,12 @@ pub enum Error
 	{
 		error: mpsc::error::SendError<chat::Update>,
 	},
+	RestoreFailed
+	{
+		round: u32,
+		restored_round: u32,
+	},
+	MapMetadata(std::io::Error),
 	Interface(automaton::InterfaceError),
 }
 
@@ -2702,6 +3232,14 @@ impl From<mpsc::error::SendError<chat::Update>> for Error
 	}
 }
 
+impl From<std::io::Error> for Error
+{
+	fn from(error: std::io::Error) -> Self
+	{
+		Error::MapMetadata(error)
+	}
+}
+
 impl From<automaton::InterfaceError> for Error
 {
 	fn from(error: automaton::InterfaceError) -> Self
@@ -2717,12 +3255,23 @@ impl fmt::Display for Error
 		match self
 		{
 			Error::Abandoned => write!(f, "{:#?}", &self),
+			Error::Aborted => write!(f, "{:#?}", &self),
+			Error::Hibernated => write!(f, "{:#?}", &self),
 			Error::InvalidSetup => write!(f, "{:#?}", &self),
 			Error::MissingChallengeId => write!(f, "{:#?}", &self),
 			Error::ClientGone { .. } => write!(f, "{:#?}", &self),
 			Error::ResultDropped { .. } => write!(f, "{:#?}", &self),
 			Error::DiscordApiPostDropped { .. } => write!(f, "{:#?}", &self),
 			Error::GeneralChat { .. } => write!(f, "{:#?}", &self),
+			Error::RestoreFailed {
+				round,
+				restored_round,
+			} => write!(
+				f,
+				"restored round {} instead of round {}",
+				restored_round, round
+			),
+			Error::MapMetadata(error) => error.fmt(f),
 			Error::Interface(error) => error.fmt(f),
 		}
 	}
@@ -2744,7 +3293,8 @@ pub struct PlayerResult
 	pub challenge: Option<String>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum MatchType
 {
 	Competitive,
@@ -0,0 +1,256 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use crate::server::settings::ENV_HANDOFF;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync;
+use std::sync::atomic;
+
+use log::*;
+
+use serde_derive::{Deserialize, Serialize};
+
+use tokio::io::AsyncReadExt;
+use tokio::time::Duration;
+
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The listening sockets of this process, by what they are listening on.
+static LISTENERS: sync::Mutex<BTreeMap<String, RawFd>> =
+	sync::Mutex::new(BTreeMap::new());
+
+// The listening sockets passed to this process that have not been claimed.
+static INHERITED: sync::Mutex<Option<Inheritance>> = sync::Mutex::new(None);
+
+static HANDED_OFF: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Inheritance
+{
+	listeners: BTreeMap<String, RawFd>,
+	registration: Option<String>,
+	ready_fd: RawFd,
+}
+
+pub fn inherit() -> Result<(), Error>
+{
+	let raw = match std::env::var(ENV_HANDOFF)
+	{
+		Ok(raw) => raw,
+		Err(std::env::VarError::NotPresent) => return Ok(()),
+		Err(error) => return Err(Error::Variable(error)),
+	};
+	std::env::remove_var(ENV_HANDOFF);
+
+	let inheritance: Inheritance = serde_json::from_str(&raw)?;
+	info!(
+		"Taking over {} listening sockets from the previous server.",
+		inheritance.listeners.len()
+	);
+	*lock(&INHERITED) = Some(inheritance);
+	Ok(())
+}
+
+pub fn is_successor() -> bool
+{
+	lock(&INHERITED).is_some()
+}
+
+pub fn take_tcp(key: &str) -> Option<std::net::TcpListener>
+{
+	let fd = take(key)?;
+	// Safety: our predecessor listened for TCP connections on this socket,
+	// and we have removed it from the inheritance so it has one owner.
+	Some(unsafe { std::net::TcpListener::from_raw_fd(fd) })
+}
+
+pub fn take_unix(key: &str) -> Option<std::os::unix::net::UnixListener>
+{
+	let fd = take(key)?;
+	// Safety: our predecessor listened for Unix domain socket connections on
+	// this socket, and we have removed it from the inheritance so it has one
+	// owner.
+	Some(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) })
+}
+
+fn take(key: &str) -> Option<RawFd>
+{
+	let fd = lock(&INHERITED).as_mut()?.listeners.remove(key)?;
+	info!("Inherited the listening socket for {}.", key);
+	Some(fd)
+}
+
+pub fn register(key: String, listener: &impl AsRawFd)
+{
+	lock(&LISTENERS).insert(key, listener.as_raw_fd());
+}
+
+pub fn registration() -> Option<String>
+{
+	lock(&INHERITED).as_mut()?.registration.take()
+}
+
+// Lets our predecessor know that we are accepting connections.
+pub fn notify_ready()
+{
+	let fd = match lock(&INHERITED).as_ref()
+	{
+		Some(inheritance) => inheritance.ready_fd,
+		None => return,
+	};
+	// Safety: our predecessor gave us this end of a socket pair and nothing
+	// else in this process uses it.
+	let mut stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) };
+	match std::io::Write::write_all(&mut stream, b"\n")
+	{
+		Ok(()) => info!("Told the previous server that we are ready."),
+		Err(error) => warn!("Failed to notify previous server: {}", error),
+	}
+}
+
+pub fn is_handed_off() -> bool
+{
+	HANDED_OFF.load(atomic::Ordering::Relaxed)
+}
+
+// Starts a new server process with the same arguments that takes over our
+// listening sockets, and waits until it is ready.
+pub async fn hand_off(registration: Option<String>) -> Result<(), Error>
+{
+	let listeners = lock(&LISTENERS).clone();
+	let (ready_out, ready_in) = std::os::unix::net::UnixStream::pair()?;
+	let inheritance = Inheritance {
+		listeners,
+		registration,
+		ready_fd: ready_in.as_raw_fd(),
+	};
+	let raw = serde_json::to_string(&inheritance)?;
+
+	let mut fds: Vec<RawFd> = inheritance.listeners.values().copied().collect();
+	fds.push(inheritance.ready_fd);
+
+	// We start the executable by the name that started us rather than by
+	// current_exe(), because a deploy will have replaced the file.
+	let mut args = std::env::args_os();
+	let executable = args
+		.next()
+		.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+	let mut command = tokio::process::Command::new(executable);
+	command.args(args);
+	command.env(ENV_HANDOFF, raw);
+	command.stdin(std::process::Stdio::null());
+	// Safety: the closure only calls fcntl, which is async-signal-safe.
+	unsafe {
+		command.pre_exec(move || {
+			for &fd in &fds
+			{
+				if libc::fcntl(fd, libc::F_SETFD, 0) == -1
+				{
+					return Err(std::io::Error::last_os_error());
+				}
+			}
+			Ok(())
+		});
+	}
+	let mut successor = command.spawn()?;
+	info!("Started new server process {}.", successor.id());
+	drop(ready_in);
+
+	ready_out.set_nonblocking(true)?;
+	let mut ready_out = tokio::net::UnixStream::from_std(ready_out)?;
+	let mut buffer = [0u8; 1];
+	match tokio::time::timeout(READY_TIMEOUT, ready_out.read(&mut buffer)).await
+	{
+		Ok(Ok(1)) =>
+		{
+			HANDED_OFF.store(true, atomic::Ordering::Relaxed);
+			Ok(())
+		}
+		Ok(Ok(_)) => Err(Error::SuccessorExited),
+		Ok(Err(error)) => Err(Error::Io(error)),
+		Err(_elapsed) =>
+		{
+			successor.kill()?;
+			Err(Error::SuccessorNotReady)
+		}
+	}
+}
+
+fn lock<T>(mutex: &'static sync::Mutex<T>) -> sync::MutexGuard<'static, T>
+{
+	match mutex.lock()
+	{
+		Ok(guard) => guard,
+		Err(poisoned) => poisoned.into_inner(),
+	}
+}
+
+#[derive(Debug)]
+pub enum Error
+{
+	SuccessorExited,
+	SuccessorNotReady,
+	Variable(std::env::VarError),
+	Io(std::io::Error),
+	Json(serde_json::Error),
+}
+
+impl From<std::io::Error> for Error
+{
+	fn from(error: std::io::Error) -> Self
+	{
+		Error::Io(error)
+	}
+}
+
+impl From<serde_json::Error> for Error
+{
+	fn from(error: serde_json::Error) -> Self
+	{
+		Error::Json(error)
+	}
+}
+
+impl fmt::Display for Error
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		match self
+		{
+			Error::SuccessorExited => write!(f, "new server process exited"),
+			Error::SuccessorNotReady =>
+			{
+				write!(f, "new server process did not become ready in time")
+			}
+			Error::Variable(error) => error.fmt(f),
+			Error::Io(error) => error.fmt(f),
+			Error::Json(error) => error.fmt(f),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
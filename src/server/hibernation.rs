@@ -0,0 +1,259 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use crate::common::keycode::Keycode;
+use crate::logic::difficulty::Difficulty;
+use crate::logic::player::PlayerColor;
+use crate::server::botslot::Botslot;
+use crate::server::game::MatchType;
+use crate::server::handoff;
+use crate::server::lobby;
+use crate::server::lobby::LobbyType;
+use crate::server::login::UserId;
+use crate::server::message::{LobbyMetadata, Role, VisionType};
+
+use std::fmt;
+use std::path::Path;
+use std::sync;
+
+use log::*;
+
+use serde_derive::{Deserialize, Serialize};
+
+// Games that were still running and lobbies that were still open when the
+// server shut down, to be written to the game manifest.
+static HIBERNATED: sync::Mutex<Manifest> = sync::Mutex::new(Manifest {
+	games: Vec::new(),
+	lobbies: Vec::new(),
+});
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest
+{
+	#[serde(default)]
+	games: Vec<Game>,
+	#[serde(default)]
+	lobbies: Vec<lobby::Snapshot>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Game
+{
+	pub lobby_id: Keycode,
+	pub lobby_name: String,
+	pub lobby_type: LobbyType,
+	pub description_metadata: LobbyMetadata,
+	pub match_type: MatchType,
+	#[serde(default)]
+	pub challenge: Option<String>,
+	pub map_name: String,
+	pub ruleset_name: String,
+	#[serde(default)]
+	pub planning_time_in_seconds: Option<u32>,
+	pub recording_name: String,
+	pub round: u32,
+	pub players: Vec<Player>,
+	pub bots: Vec<Bot>,
+	pub watchers: Vec<Watcher>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Player
+{
+	pub user_id: UserId,
+	pub username: String,
+	pub color: PlayerColor,
+	pub vision: VisionType,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bot
+{
+	pub slot: Botslot,
+	pub ai_name: String,
+	pub difficulty: Difficulty,
+	pub descriptive_name: String,
+	pub color: PlayerColor,
+	pub vision: VisionType,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Watcher
+{
+	pub user_id: UserId,
+	pub username: String,
+	pub role: Role,
+	pub vision_level: PlayerColor,
+}
+
+pub fn hibernate(game: Game)
+{
+	info!(
+		"Recording game in lobby {} at round {} in the game manifest.",
+		game.lobby_id, game.round
+	);
+	lock().games.push(game);
+}
+
+pub fn hibernate_lobby(snapshot: lobby::Snapshot)
+{
+	info!(
+		"Recording lobby {} ({}) in the game manifest.",
+		snapshot.lobby_id, snapshot.name
+	);
+	lock().lobbies.push(snapshot);
+}
+
+pub fn load(filename: &str)
+	-> Result<(Vec<Game>, Vec<lobby::Snapshot>), Error>
+{
+	// The manifest that was loaded before is only left behind if the server
+	// did not shut down cleanly, but a new server process that we handed off
+	// to must leave those games and lobbies to us.
+	let loaded = loaded_filename(filename);
+	let manifest = if Path::new(filename).exists()
+	{
+		read(filename)?
+	}
+	else if !handoff::is_successor() && Path::new(&loaded).exists()
+	{
+		warn!("Loading game manifest left behind by a previous crash.");
+		read(&loaded)?
+	}
+	else
+	{
+		return Ok((Vec::new(), Vec::new()));
+	};
+
+	info!(
+		"Loaded {} games to resume and {} lobbies to restore.",
+		manifest.games.len(),
+		manifest.lobbies.len()
+	);
+	Ok((manifest.games, manifest.lobbies))
+}
+
+// Moves the manifest aside once its games and lobbies belong to this process,
+// keeping it until it is replaced at shutdown in case we crash before then.
+pub fn claim(filename: &str) -> Result<(), Error>
+{
+	if Path::new(filename).exists()
+	{
+		std::fs::rename(filename, loaded_filename(filename))?;
+	}
+	Ok(())
+}
+
+pub fn save(filename: &str) -> Result<(), Error>
+{
+	let manifest = lock().clone();
+	if manifest.games.is_empty() && manifest.lobbies.is_empty()
+	{
+		remove_if_exists(filename)?;
+		return remove_if_exists(&loaded_filename(filename));
+	}
+
+	info!(
+		"Writing {} games and {} lobbies to the game manifest.",
+		manifest.games.len(),
+		manifest.lobbies.len()
+	);
+	let raw = serde_json::to_string_pretty(&manifest)?;
+	let tmpfilename = format!("{}.tmp", filename);
+	std::fs::write(&tmpfilename, raw)?;
+	std::fs::rename(&tmpfilename, filename)?;
+	remove_if_exists(&loaded_filename(filename))
+}
+
+fn loaded_filename(filename: &str) -> String
+{
+	format!("{}.loaded", filename)
+}
+
+fn remove_if_exists(filename: &str) -> Result<(), Error>
+{
+	match std::fs::remove_file(filename)
+	{
+		Ok(()) => Ok(()),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+		Err(e) => Err(e.into()),
+	}
+}
+
+fn read(filename: &str) -> Result<Manifest, Error>
+{
+	let raw = std::fs::read_to_string(filename)?;
+	let manifest: Manifest = serde_json::from_str(&raw)?;
+	Ok(manifest)
+}
+
+fn lock() -> sync::MutexGuard<'static, Manifest>
+{
+	match HIBERNATED.lock()
+	{
+		Ok(guard) => guard,
+		Err(poisoned) => poisoned.into_inner(),
+	}
+}
+
+#[derive(Debug)]
+pub enum Error
+{
+	Io(std::io::Error),
+	Json(serde_json::Error),
+}
+
+impl From<std::io::Error> for Error
+{
+	fn from(error: std::io::Error) -> Self
+	{
+		Error::Io(error)
+	}
+}
+
+impl From<serde_json::Error> for Error
+{
+	fn from(error: serde_json::Error) -> Self
+	{
+		Error::Json(error)
+	}
+}
+
+impl fmt::Display for Error
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		match self
+		{
+			Error::Io(error) => error.fmt(f),
+			Error::Json(error) => error.fmt(f),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
@@ -26,6 +26,7 @@ mod secrets;
 
 pub use secrets::Invite;
 pub use secrets::Salts;
+pub use secrets::Secret;
 pub use secrets::Secrets;
 
 use crate::common::keycode::*;
@@ -43,28 +44,42 @@ use crate::server::chat;
 use crate::server::client;
 use crate::server::discord_api;
 use crate::server::game;
+use crate::server::hibernation;
 use crate::server::login::UserId;
 use crate::server::message::*;
+use crate::server::metrics;
 use crate::server::rating;
+use crate::server::tokio::State as ServerState;
 
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::io;
 use std::sync;
 use std::sync::atomic;
+use std::time::SystemTime;
+
+use futures::future;
+use futures::future::Either;
+use futures::StreamExt;
 
 use log::*;
 
 use rand::Rng;
 
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
 
 use tokio::sync::mpsc;
 use tokio::sync::watch;
+use tokio::time::Duration;
 
 use vec_drain_where::VecDrainWhereExt;
 
+// A restored lobby that none of its members have returned to is disbanded
+// once it has been vacant for this long, counting from the shutdown.
+const MAX_VACANCY: Duration = Duration::from_secs(30 * 60);
+
 #[derive(Debug)]
 pub enum Update
 {
@@ -230,9 +245,20 @@ pub enum Sub
 		general_chat: mpsc::Sender<chat::Update>,
 		lobby_sendbuffer: mpsc::Sender<Update>,
 	},
+
+	Disband
+	{
+		general_chat: mpsc::Sender<chat::Update>,
+	},
+	Expire
+	{
+		general_chat: mpsc::Sender<chat::Update>,
+	},
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(
+	Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq,
+)]
 #[serde(rename_all = "snake_case")]
 pub enum LobbyType
 {
@@ -268,20 +294,222 @@ pub fn create(
 	ratings: mpsc::Sender<rating::Update>,
 	discord_api: mpsc::Sender<discord_api::Post>,
 	canary: mpsc::Sender<()>,
+	server_state: watch::Receiver<ServerState>,
+	drain_deadline: Option<Duration>,
 ) -> mpsc::Sender<Update>
 {
 	let key = rand::random();
 	let data = ticker.fetch_add(1, atomic::Ordering::Relaxed);
 	let lobby_id = keycode(key, data);
 
+	spawn(
+		lobby_id,
+		None,
+		ratings,
+		discord_api,
+		canary,
+		server_state,
+		drain_deadline,
+	)
+}
+
+pub fn restore(
+	snapshot: Snapshot,
+	ratings: mpsc::Sender<rating::Update>,
+	discord_api: mpsc::Sender<discord_api::Post>,
+	general_chat: mpsc::Sender<chat::Update>,
+	canary: mpsc::Sender<()>,
+	server_state: watch::Receiver<ServerState>,
+	drain_deadline: Option<Duration>,
+)
+{
+	let lobby_id = snapshot.lobby_id;
+	let vacant_for = match snapshot.vacant_since
+	{
+		Some(vacant_since) =>
+		{
+			Duration::from_secs(now().saturating_sub(vacant_since))
+		}
+		None => Duration::from_secs(0),
+	};
+	let snapshot = Some(snapshot);
+	let mut lobby_sendbuffer = spawn(
+		lobby_id,
+		snapshot,
+		ratings,
+		discord_api,
+		canary,
+		server_state.clone(),
+		drain_deadline,
+	);
+
+	// The lobby is listed as soon as it has been restored, so that its
+	// former members can find it again.
+	let update = Update::ForSetup(Sub::Save {
+		lobby_sendbuffer: lobby_sendbuffer.clone(),
+		general_chat: general_chat.clone(),
+	});
+	match lobby_sendbuffer.try_send(update)
+	{
+		Ok(()) => (),
+		Err(error) => error!("Failed to list lobby {}: {}", lobby_id, error),
+	}
+
+	let remaining = MAX_VACANCY.checked_sub(vacant_for).unwrap_or_default();
+	let task = expire(lobby_sendbuffer, general_chat, server_state, remaining);
+	tokio::spawn(crate::common::log::for_lobby(lobby_id, task));
+}
+
+// Disbands a restored lobby once it has been vacant for too long, unless the
+// server starts shutting down first.
+async fn expire(
+	mut lobby_sendbuffer: mpsc::Sender<Update>,
+	general_chat: mpsc::Sender<chat::Update>,
+	server_state: watch::Receiver<ServerState>,
+	remaining: Duration,
+)
+{
+	let expired = tokio::time::delay_for(remaining);
+	let closing = server_state
+		.filter(|state| future::ready(*state != ServerState::Open))
+		.into_future();
+	match future::select(expired, closing).await
+	{
+		Either::Left(((), _)) =>
+		{
+			// The lobby might have started a game or disbanded already.
+			let update = Update::ForSetup(Sub::Expire { general_chat });
+			let _ = lobby_sendbuffer.send(update).await;
+		}
+		Either::Right(_) => (),
+	}
+}
+
+fn now() -> u64
+{
+	SystemTime::now()
+		.duration_since(SystemTime::UNIX_EPOCH)
+		.map(|x| x.as_secs())
+		.unwrap_or(0)
+}
+
+pub fn resume(
+	game: hibernation::Game,
+	ratings: mpsc::Sender<rating::Update>,
+	discord_api: mpsc::Sender<discord_api::Post>,
+	general_chat: mpsc::Sender<chat::Update>,
+	canary: mpsc::Sender<()>,
+	server_state: watch::Receiver<ServerState>,
+	drain_deadline: Option<Duration>,
+)
+{
+	let lobby_id = game.lobby_id;
 	let (updates_in, updates_out) = mpsc::channel::<Update>(1000);
 
-	let task = run(lobby_id, ratings, discord_api, canary, updates_out);
-	tokio::spawn(task);
+	let task = async move {
+		metrics::RUNNING_GAMES.increment();
+		let result = game::resume(
+			game,
+			ratings,
+			discord_api,
+			updates_in,
+			general_chat,
+			updates_out,
+			server_state,
+			drain_deadline,
+		)
+		.await;
+		metrics::RUNNING_GAMES.decrement();
+
+		match result
+		{
+			Ok(()) => debug!("Lobby {} has disbanded.", lobby_id),
+			Err(error) =>
+			{
+				error!(
+					"Resumed game crashed in lobby {}: {:?}",
+					lobby_id, error
+				)
+			}
+		}
+		let _discarded = canary;
+	};
+	tokio::spawn(crate::common::log::for_lobby(lobby_id, task));
+}
+
+fn spawn(
+	lobby_id: Keycode,
+	snapshot: Option<Snapshot>,
+	ratings: mpsc::Sender<rating::Update>,
+	discord_api: mpsc::Sender<discord_api::Post>,
+	canary: mpsc::Sender<()>,
+	server_state: watch::Receiver<ServerState>,
+	drain_deadline: Option<Duration>,
+) -> mpsc::Sender<Update>
+{
+	let (updates_in, updates_out) = mpsc::channel::<Update>(1000);
+
+	let task = run(
+		lobby_id,
+		snapshot,
+		ratings,
+		discord_api,
+		canary,
+		server_state,
+		drain_deadline,
+		updates_out,
+	);
+	tokio::spawn(crate::common::log::for_lobby(lobby_id, task));
 
 	updates_in
 }
 
+// The setup of a listed lobby that was still open when the server shut down.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Snapshot
+{
+	pub lobby_id: Keycode,
+	pub name: String,
+	lobby_type: LobbyType,
+	is_public: bool,
+	map_name: String,
+	ruleset_name: String,
+	timer_in_seconds: u32,
+	#[serde(default)]
+	challenge_key: Option<String>,
+	#[serde(default)]
+	bots: Vec<BotSnapshot>,
+	#[serde(default)]
+	invitations: Vec<Invitation>,
+	// In seconds since the Unix epoch.
+	#[serde(default)]
+	vacant_since: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BotSnapshot
+{
+	slot: Botslot,
+	ai_name: String,
+	difficulty: Difficulty,
+	#[serde(default)]
+	color: Option<PlayerColor>,
+	#[serde(default)]
+	visiontype: Option<VisionType>,
+}
+
+// The salts of a client that sent out invites before the server shut down,
+// so that those invites remain valid after the lobby is restored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Invitation
+{
+	client_id: Keycode,
+	salts: Salts,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum Stage
 {
@@ -341,17 +569,25 @@ struct Lobby
 
 	stage: Stage,
 	rating_database_for_games: mpsc::Sender<rating::Update>,
+
+	server_state: watch::Receiver<ServerState>,
+	invitations: Vec<Invitation>,
+	vacant_since: Option<u64>,
 }
 
 async fn run(
 	lobby_id: Keycode,
+	snapshot: Option<Snapshot>,
 	ratings: mpsc::Sender<rating::Update>,
 	discord_api: mpsc::Sender<discord_api::Post>,
 	canary: mpsc::Sender<()>,
+	server_state: watch::Receiver<ServerState>,
+	drain_deadline: Option<Duration>,
 	mut updates: mpsc::Receiver<Update>,
 )
 {
-	let lobby = match initialize(lobby_id, ratings).await
+	let game_server_state = server_state.clone();
+	let mut lobby = match initialize(lobby_id, ratings, server_state).await
 	{
 		Ok(lobby) => lobby,
 		Err(error) =>
@@ -361,6 +597,19 @@ async fn run(
 		}
 	};
 
+	if let Some(snapshot) = snapshot
+	{
+		match restore_setup(&mut lobby, snapshot).await
+		{
+			Ok(()) => debug!("Restored lobby {}.", lobby_id),
+			Err(error) =>
+			{
+				error!("Failed to restore lobby {}: {:?}", lobby_id, error);
+				return;
+			}
+		}
+	}
+
 	let game = match run_setup(lobby, &mut updates).await
 	{
 		Ok(game) => game,
@@ -375,7 +624,18 @@ async fn run(
 	{
 		debug!("Game started in lobby {}.", lobby_id);
 
-		match game::run(game, discord_api, updates).await
+		metrics::RUNNING_GAMES.increment();
+		let result = game::run(
+			game,
+			discord_api,
+			updates,
+			game_server_state,
+			drain_deadline,
+		)
+		.await;
+		metrics::RUNNING_GAMES.decrement();
+
+		match result
 		{
 			Ok(()) =>
 			{}
@@ -394,6 +654,7 @@ async fn run(
 async fn initialize(
 	lobby_id: Keycode,
 	rating_database_for_games: mpsc::Sender<rating::Update>,
+	server_state: watch::Receiver<ServerState>,
 ) -> Result<Lobby, Error>
 {
 	let ai_pool = ai::load_pool().into_iter().map(|x| (x, None)).collect();
@@ -437,9 +698,168 @@ async fn initialize(
 		challenge: None,
 		stage: Stage::Setup,
 		rating_database_for_games,
+		server_state,
+		invitations: Vec::new(),
+		vacant_since: None,
+	})
+}
+
+async fn restore_setup(
+	lobby: &mut Lobby,
+	snapshot: Snapshot,
+) -> Result<(), Error>
+{
+	let Snapshot {
+		lobby_id: _,
+		name,
+		lobby_type,
+		is_public,
+		map_name,
+		ruleset_name,
+		timer_in_seconds,
+		challenge_key,
+		bots,
+		invitations,
+		vacant_since,
+	} = snapshot;
+
+	// There are no clients yet, so nobody needs to be told about the changes.
+	let mut clients = Vec::new();
+
+	lobby.name = name;
+	lobby.is_public = is_public;
+	lobby.invitations = invitations;
+	lobby.vacant_since = Some(vacant_since.unwrap_or_else(now));
+
+	match lobby_type
+	{
+		LobbyType::Generic => (),
+		LobbyType::OneVsOne =>
+		{
+			lobby.lobby_type = LobbyType::OneVsOne;
+			restrict_map_pool_for_one_vs_one(lobby).await?;
+		}
+		LobbyType::Custom => become_custom_lobby(lobby, &mut clients).await?,
+		LobbyType::Challenge =>
+		{
+			// The challenge determines the map, ruleset, timer and bots.
+			let key = challenge_key.ok_or(Error::EmptyChallengePool)?;
+			return pick_challenge(lobby, &mut clients, key).await;
+		}
+		LobbyType::Tutorial | LobbyType::Replay =>
+		{
+			warn!("Cannot restore {:?} lobby {}.", lobby_type, lobby.id);
+			return Ok(());
+		}
+	}
+
+	pick_map(lobby, &mut clients, map_name).await?;
+	if ruleset_name != lobby.ruleset_name
+	{
+		pick_ruleset(lobby, &mut clients, ruleset_name).await?;
+	}
+	if timer_in_seconds != lobby.timer_in_seconds
+	{
+		pick_timer(lobby, &mut clients, timer_in_seconds).await?;
+	}
+
+	for bot in bots
+	{
+		restore_bot(lobby, bot);
+	}
+
+	Ok(())
+}
+
+fn restore_bot(lobby: &mut Lobby, snapshot: BotSnapshot)
+{
+	if lobby.num_players >= lobby.max_players
+	{
+		warn!("Cannot restore bot in lobby {}: lobby full", lobby.id);
+		return;
+	}
+
+	let i = match lobby.open_botslots.iter().position(|&x| x == snapshot.slot)
+	{
+		Some(i) => i,
+		None =>
+		{
+			warn!("Cannot restore bot in lobby {}: slot taken", lobby.id);
+			return;
+		}
+	};
+	let slot = lobby.open_botslots.swap_remove(i);
+
+	if let Some(color) = snapshot.color
+	{
+		if let Some(i) = lobby.available_colors.iter().position(|&x| x == color)
+		{
+			lobby.available_colors.remove(i);
+			lobby.bot_colors.insert(slot, color);
+		}
+	}
+	if let Some(visiontype) = snapshot.visiontype
+	{
+		lobby.bot_visiontypes.insert(slot, visiontype);
+	}
+
+	lobby.bots.push(Bot {
+		slot,
+		ai_name: snapshot.ai_name,
+		difficulty: snapshot.difficulty,
+	});
+	lobby.num_players += 1;
+}
+
+fn make_snapshot(lobby: &Lobby) -> Option<Snapshot>
+{
+	// Unlisted lobbies cannot be found again, client hosted lobbies cannot
+	// exist without their host and tutorials are for a single player.
+	if lobby.listing.is_none() || lobby.is_client_hosted
+	{
+		return None;
+	}
+	match lobby.lobby_type
+	{
+		LobbyType::Generic
+		| LobbyType::OneVsOne
+		| LobbyType::Custom
+		| LobbyType::Challenge => (),
+		LobbyType::Tutorial | LobbyType::Replay => return None,
+	}
+
+	let bots = lobby
+		.bots
+		.iter()
+		.map(|bot| BotSnapshot {
+			slot: bot.slot,
+			ai_name: bot.ai_name.clone(),
+			difficulty: bot.difficulty,
+			color: lobby.bot_colors.get(&bot.slot).copied(),
+			visiontype: lobby.bot_visiontypes.get(&bot.slot).copied(),
+		})
+		.collect();
+
+	Some(Snapshot {
+		lobby_id: lobby.id,
+		name: lobby.name.clone(),
+		lobby_type: lobby.lobby_type,
+		is_public: lobby.is_public,
+		map_name: lobby.map_name.clone(),
+		ruleset_name: lobby.ruleset_name.clone(),
+		timer_in_seconds: lobby.timer_in_seconds,
+		challenge_key: lobby.challenge.as_ref().map(|(_id, key)| key.clone()),
+		bots,
+		invitations: lobby.invitations.clone(),
+		vacant_since: Some(lobby.vacant_since.unwrap_or_else(now)),
 	})
 }
 
+fn is_server_closing(lobby: &Lobby) -> bool
+{
+	*lobby.server_state.borrow() != ServerState::Open
+}
+
 async fn run_setup(
 	mut lobby: Lobby,
 	updates: &mut mpsc::Receiver<Update>,
@@ -455,6 +875,16 @@ async fn run_setup(
 			None => continue,
 		}
 	}
+
+	// Lobbies that were still open when the server shut down are recorded,
+	// including restored lobbies that are still waiting for their members.
+	if is_server_closing(&lobby)
+	{
+		if let Some(snapshot) = make_snapshot(&lobby)
+		{
+			hibernation::hibernate_lobby(snapshot);
+		}
+	}
 	Ok(None)
 }
 
@@ -802,6 +1232,21 @@ async fn handle_sub(
 		{
 			try_start(lobby, clients, &mut general_chat, lobby_sendbuffer).await
 		}
+
+		Sub::Disband { mut general_chat } =>
+		{
+			disband(lobby, clients, &mut general_chat).await?;
+			Ok(None)
+		}
+		Sub::Expire { mut general_chat } =>
+		{
+			if lobby.vacant_since.is_some() && clients.is_empty()
+			{
+				debug!("Nobody has returned to lobby {}.", lobby.id);
+				disband(lobby, clients, &mut general_chat).await?;
+			}
+			Ok(None)
+		}
 	}
 }
 
@@ -955,8 +1400,13 @@ async fn handle_join(
 			warn!("Client {} has invite for different lobby", client_id);
 			return Ok(());
 		}
-		// Make sure the person that sent the invitation is still present.
+		// Make sure the person that sent the invitation is still present,
+		// or was present when the server shut down.
 		clients.iter().any(|x| x.handle.verify_invite(&invite))
+			|| lobby
+				.invitations
+				.iter()
+				.any(|x| x.salts.verify(x.client_id, &invite))
 	}
 	else
 	{
@@ -977,6 +1427,7 @@ async fn handle_join(
 		lobby_sendbuffer,
 		clients,
 	);
+	lobby.vacant_since = None;
 
 	let update = chat::Update::JoinedLobby {
 		client_id,
@@ -1210,6 +1661,23 @@ async fn handle_leave(
 			.collect()
 	};
 
+	// Keep the invites of clients that leave because the server is shutting
+	// down, so that they still work if the lobby is restored.
+	let is_closing = is_server_closing(lobby);
+	if is_closing
+	{
+		for client in &removed
+		{
+			if let Some(salts) = client.handle.salts()
+			{
+				lobby.invitations.push(Invitation {
+					client_id: client.id,
+					salts: salts.clone(),
+				});
+			}
+		}
+	}
+
 	handle_removed(lobby, clients, removed).await?;
 
 	let update = chat::Update::LeftLobby {
@@ -1220,6 +1688,13 @@ async fn handle_leave(
 
 	if clients.is_empty()
 	{
+		// A lobby that is left empty because the server is shutting down is
+		// recorded once it has been disbanded, so that it can be restored.
+		if !is_closing
+		{
+			lobby.listing = None;
+		}
+
 		let update = chat::Update::DisbandLobby { lobby_id: lobby.id };
 		general_chat.send(update).await?;
 	}
@@ -1231,6 +1706,25 @@ async fn handle_leave(
 	Ok(())
 }
 
+async fn disband(
+	lobby: &mut Lobby,
+	clients: &mut Vec<Client>,
+	general_chat: &mut mpsc::Sender<chat::Update>,
+) -> Result<(), Error>
+{
+	debug!("Disbanding lobby {} on request.", lobby.id);
+
+	// Just like when the host leaves, everyone is removed from the lobby.
+	let removed: Vec<Client> = std::mem::take(clients);
+	handle_removed(lobby, clients, removed).await?;
+
+	lobby.listing = None;
+	let update = chat::Update::DisbandLobby { lobby_id: lobby.id };
+	general_chat.send(update).await?;
+
+	Ok(())
+}
+
 async fn handle_removed(
 	lobby: &mut Lobby,
 	clients: &mut Vec<Client>,
@@ -3158,6 +3652,7 @@ async fn start(
 			connected_bots.push(game::BotClient {
 				slot: bot.slot,
 				difficulty: bot.difficulty,
+				ai_name: bot.ai_name.clone(),
 				descriptive_name,
 				ai_metadata,
 				forwarding_metadata,
@@ -3190,6 +3685,8 @@ async fn start(
 
 			local_bots.push(game::LocalBot {
 				slot: bot.slot,
+				difficulty: bot.difficulty,
+				ai_name: bot.ai_name.clone(),
 				ai,
 
 				color,
@@ -3251,6 +3748,9 @@ async fn start(
 		general_chat.send(update).await?;
 	}
 
+	let update = chat::Update::GameStarted { lobby_id: lobby.id };
+	general_chat.send(update).await?;
+
 	Ok(game)
 }
 
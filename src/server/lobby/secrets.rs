@@ -28,7 +28,9 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use rand::Rng;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use schemars::JsonSchema;
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
 // serde externally tagged
 pub enum Invite
@@ -60,7 +62,7 @@ pub struct Secret
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Salt([u8; 20]);
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
 pub struct Secrets
 {
@@ -68,7 +70,8 @@ pub struct Secrets
 	pub spectate_secret: Secret,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub struct Salts
 {
 	pub join_secret_salt: Salt,
@@ -85,6 +88,23 @@ impl Salts
 			spectate_secret_salt: Salt(rng.gen()),
 		}
 	}
+
+	pub fn verify(&self, client_id: Keycode, invite: &Invite) -> bool
+	{
+		match invite
+		{
+			Invite::JoinSecret(secret) =>
+			{
+				secret.client_id == client_id
+					&& secret.salt == self.join_secret_salt
+			}
+			Invite::SpectateSecret(secret) =>
+			{
+				secret.client_id == client_id
+					&& secret.salt == self.spectate_secret_salt
+			}
+		}
+	}
 }
 
 impl Secrets
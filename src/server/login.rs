@@ -28,6 +28,9 @@ use crate::server::message::*;
 use crate::server::rating;
 use crate::server::settings::*;
 
+use std::collections::HashMap;
+use std::sync;
+
 use log::*;
 
 use serde_aux::field_attributes::deserialize_number_from_string;
@@ -45,11 +48,20 @@ pub struct Request
 	pub account_identifier: String,
 	pub token: String,
 	pub metadata: JoinMetadata,
+	pub is_local: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct UserId(u64);
 
+impl UserId
+{
+	pub fn as_u64(self) -> u64
+	{
+		self.0
+	}
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct LoginData
 {
@@ -94,10 +106,13 @@ impl std::fmt::Display for SteamId
 pub struct Server
 {
 	connection: Option<Connection>,
+	local_bots: sync::RwLock<HashMap<String, LocalBot>>,
 }
 
 pub fn connect(settings: &Settings) -> Result<Server, anyhow::Error>
 {
+	let local_bots = sync::RwLock::new(collect_local_bots(settings));
+
 	if settings.login_server.is_some()
 		|| (!cfg!(feature = "version-is-dev")
 			&& (!cfg!(debug_assertions) || cfg!(feature = "candidate")))
@@ -105,11 +120,15 @@ pub fn connect(settings: &Settings) -> Result<Server, anyhow::Error>
 		let connection = Connection::open(settings)?;
 		Ok(Server {
 			connection: Some(connection),
+			local_bots,
 		})
 	}
 	else
 	{
-		Ok(Server { connection: None })
+		Ok(Server {
+			connection: None,
+			local_bots,
+		})
 	}
 }
 
@@ -120,6 +139,19 @@ impl Server
 		request: Request,
 	) -> Result<LoginData, ResponseStatus>
 	{
+		if request.is_local
+		{
+			let local_bots = match self.local_bots.read()
+			{
+				Ok(local_bots) => local_bots,
+				Err(poisoned) => poisoned.into_inner(),
+			};
+			if let Some(bot) = local_bots.get(&request.account_identifier)
+			{
+				return Ok(local_bot_login(bot));
+			}
+		}
+
 		match &self.connection
 		{
 			Some(ref connection) => connection.login(request).await,
@@ -128,6 +160,29 @@ impl Server
 	}
 }
 
+impl Server
+{
+	pub fn reload_local_bots(&self, settings: &Settings)
+	{
+		let mut local_bots = match self.local_bots.write()
+		{
+			Ok(local_bots) => local_bots,
+			Err(poisoned) => poisoned.into_inner(),
+		};
+		*local_bots = collect_local_bots(settings);
+	}
+}
+
+fn collect_local_bots(settings: &Settings) -> HashMap<String, LocalBot>
+{
+	settings
+		.local_bots
+		.iter()
+		.flatten()
+		.map(|bot| (bot.username.clone(), bot.clone()))
+		.collect()
+}
+
 impl Server
 {
 	fn dev_login(&self, request: Request) -> Result<LoginData, ResponseStatus>
@@ -177,6 +232,24 @@ impl Server
 	}
 }
 
+// Bots that connect through the local socket are trusted to be who they say
+// they are, so they do not need a session token from the login server.
+fn local_bot_login(bot: &LocalBot) -> LoginData
+{
+	info!("Local bot '{}' logged in.", bot.username);
+
+	LoginData {
+		user_id: UserId(bot.user_id),
+		username: bot.username.clone(),
+		unlocks: enum_set!(Unlock::Bot),
+		rating_data: rating::Data {
+			rating: 0.0,
+			stars: 0,
+			stars_per_challenge: HashMap::new(),
+		},
+	}
+}
+
 struct Connection
 {
 	http: http::Client,
@@ -35,18 +35,18 @@ use tokio::sync::watch;
 use tokio::time::Duration;
 
 pub async fn run(
-	setup: Setup,
+	mut setup: Setup,
 	server_state: watch::Receiver<ServerState>,
 	mut slack_api: mpsc::Sender<slack_api::Post>,
 )
 {
-	let interval = tokio::time::interval(Duration::from_secs(300));
+	let interval = tokio::time::interval(Duration::from_secs(60));
 	let closed = wait_until_closed(server_state).boxed();
 	let mut events = interval.take_until(closed);
 
 	while let Some(_event) = events.next().await
 	{
-		do_check(&setup, &mut slack_api).await;
+		do_check(&mut setup, &mut slack_api);
 	}
 }
 
@@ -63,10 +63,10 @@ async fn wait_until_closed(mut server_state: watch::Receiver<ServerState>)
 	}
 }
 
-async fn do_check(setup: &Setup, slack_api: &mut mpsc::Sender<slack_api::Post>)
+fn do_check(setup: &mut Setup, slack_api: &mut mpsc::Sender<slack_api::Post>)
 {
 	trace!("Checking...");
-	match check(&setup).await
+	match tokio::task::block_in_place(|| setup.rotate_if_due())
 	{
 		Ok(()) =>
 		{
@@ -87,53 +87,3 @@ async fn do_check(setup: &Setup, slack_api: &mut mpsc::Sender<slack_api::Post>)
 		}
 	}
 }
-
-async fn check(setup: &Setup) -> Result<(), Error>
-{
-	let command = tokio::process::Command::new("logrotate")
-		.arg("--state")
-		.arg(&setup.statusfilename)
-		.arg(&setup.conffilename)
-		.status();
-	let status = command.await?;
-	if status.success()
-	{
-		Ok(())
-	}
-	else
-	{
-		Err(Error::Failure(status))
-	}
-}
-
-#[derive(Debug)]
-enum Error
-{
-	Io(tokio::io::Error),
-	Failure(std::process::ExitStatus),
-}
-
-impl From<tokio::io::Error> for Error
-{
-	fn from(error: tokio::io::Error) -> Error
-	{
-		Error::Io(error)
-	}
-}
-
-impl std::fmt::Display for Error
-{
-	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
-	{
-		match self
-		{
-			Error::Io(error) => error.fmt(f),
-			Error::Failure(status) =>
-			{
-				write!(f, "exited with status {}", status)
-			}
-		}
-	}
-}
-
-impl std::error::Error for Error {}
@@ -35,11 +35,13 @@ use crate::server::botslot::Botslot;
 use crate::server::botslot::EmptyBotslot;
 use crate::server::lobby;
 use crate::server::lobby::LobbyType;
+use crate::server::schema;
 
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum Message
@@ -50,6 +52,9 @@ pub enum Message
 	Version
 	{
 		version: Version,
+
+		#[serde(default, skip_serializing_if = "is_zero")]
+		metadata: VersionMetadata,
 	},
 	JoinServer
 	{
@@ -65,6 +70,12 @@ pub enum Message
 		#[serde(default, skip_serializing_if = "is_zero")]
 		metadata: JoinMetadataOrTagMetadata,
 	},
+	// Sent by the server after joining, and again after every resume.
+	ResumeToken
+	{
+		#[serde(rename = "content")]
+		token: String,
+	},
 	LeaveServer
 	{
 		#[serde(default, skip_serializing_if = "is_zero")]
@@ -136,6 +147,7 @@ pub enum Message
 		username_or_slot: UsernameOrSlot,
 
 		#[serde(rename = "player")]
+		#[schemars(with = "schema::PlayerColor")]
 		color: PlayerColor,
 	},
 	#[serde(rename = "claim_visiontype")] // The capital T would cause "_type".
@@ -159,6 +171,7 @@ pub enum Message
 		#[serde(rename = "sender")]
 		username_or_slot: UsernameOrSlot,
 
+		#[schemars(with = "schema::Difficulty")]
 		difficulty: Difficulty,
 	},
 	PickMap
@@ -211,6 +224,7 @@ pub enum Message
 		#[serde(rename = "content")]
 		map_name: String,
 
+		#[schemars(with = "schema::MapMetadata")]
 		metadata: map::Metadata,
 	},
 	ListRuleset
@@ -228,6 +242,7 @@ pub enum Message
 		name: String,
 
 		#[serde(rename = "player")]
+		#[schemars(with = "schema::PlayerColor")]
 		color: PlayerColor,
 	},
 	RulesetRequest
@@ -254,6 +269,7 @@ pub enum Message
 	},
 	Skins
 	{
+		#[schemars(with = "schema::MapMetadata")]
 		metadata: map::Metadata,
 	},
 	InGame
@@ -273,6 +289,7 @@ pub enum Message
 		role: Option<Role>,
 
 		#[serde(default, skip_serializing_if = "is_zero")]
+		#[schemars(with = "Option<schema::PlayerColor>")]
 		player: Option<PlayerColor>,
 
 		#[serde(default, skip_serializing_if = "is_zero", rename = "content")]
@@ -282,6 +299,7 @@ pub enum Message
 		timer_in_seconds: Option<u32>,
 
 		#[serde(default, skip_serializing_if = "Option::is_none")]
+		#[schemars(with = "Option<schema::Difficulty>")]
 		difficulty: Option<Difficulty>,
 
 		#[serde(default, skip_serializing_if = "Option::is_none")]
@@ -294,6 +312,7 @@ pub enum Message
 		role: Option<Role>,
 
 		#[serde(default, skip_serializing_if = "is_zero")]
+		#[schemars(with = "Option<schema::PlayerColor>")]
 		player: Option<PlayerColor>,
 
 		#[serde(default, skip_serializing_if = "is_zero", rename = "content")]
@@ -326,6 +345,7 @@ pub enum Message
 	},
 	HostRejoinRequest
 	{
+		#[schemars(with = "schema::PlayerColor")]
 		player: PlayerColor,
 
 		#[serde(rename = "content")]
@@ -333,16 +353,19 @@ pub enum Message
 	},
 	HostRejoinChanges
 	{
+		#[schemars(with = "schema::PlayerColor")]
 		player: PlayerColor,
 
 		#[serde(rename = "content")]
 		username: String,
 
+		#[schemars(with = "Vec<schema::Change>")]
 		changes: Vec<Change>,
 	},
 	#[serde(rename = "change")]
 	Changes
 	{
+		#[schemars(with = "Vec<schema::Change>")]
 		changes: Vec<Change>,
 
 		#[serde(default, skip_serializing_if = "Option::is_none")]
@@ -352,6 +375,7 @@ pub enum Message
 	#[serde(rename = "order_new")]
 	Orders
 	{
+		#[schemars(with = "Vec<schema::Order>")]
 		orders: Vec<Order>,
 
 		#[serde(default, skip_serializing_if = "Option::is_none")]
@@ -389,7 +413,11 @@ pub enum Message
 		#[serde(default, skip_serializing_if = "is_zero", rename = "time")]
 		stars: i32,
 	},
-	Closing,
+	Closing
+	{
+		#[serde(default, skip_serializing_if = "is_zero")]
+		reason: Option<RefusalReason>,
+	},
 	Closed,
 	Quit,
 	Chat
@@ -409,9 +437,94 @@ pub enum Message
 	{
 		content: String,
 	},
+	Error
+	{
+		#[serde(rename = "content")]
+		code: ErrorCode,
+
+		#[serde(default, skip_serializing_if = "is_zero")]
+		metadata: ErrorMetadata,
+	},
 }
 
-#[derive(PartialEq, Eq, Copy, Clone, Serialize, Deserialize, Debug)]
+impl Message
+{
+	// The value of the "type" field that this message is serialized with.
+	pub fn type_name(&self) -> &'static str
+	{
+		match self
+		{
+			Message::Pulse => "pulse",
+			Message::Ping => "ping",
+			Message::Pong => "pong",
+			Message::Version { .. } => "version",
+			Message::JoinServer { .. } => "join_server",
+			Message::LeaveServer { .. } => "leave_server",
+			Message::JoinLobby { .. } => "join_lobby",
+			Message::LeaveLobby { .. } => "leave_lobby",
+			Message::MakeLobby { .. } => "make_lobby",
+			Message::SaveLobby => "save_lobby",
+			Message::DisbandLobby { .. } => "disband_lobby",
+			Message::LockLobby => "lock_lobby",
+			Message::UnlockLobby => "unlock_lobby",
+			Message::NameLobby { .. } => "name_lobby",
+			Message::ListLobby { .. } => "list_lobby",
+			Message::ClaimHost { .. } => "claim_host",
+			Message::ClaimRole { .. } => "claim_role",
+			Message::ClaimColor { .. } => "claim_color",
+			Message::ClaimVisionType { .. } => "claim_visiontype",
+			Message::ClaimAi { .. } => "claim_ai",
+			Message::ClaimDifficulty { .. } => "claim_difficulty",
+			Message::PickMap { .. } => "pick_map",
+			Message::PickTimer { .. } => "pick_timer",
+			Message::PickChallenge { .. } => "pick_challenge",
+			Message::PickRuleset { .. } => "pick_ruleset",
+			Message::AddBot { .. } => "add_bot",
+			Message::RemoveBot { .. } => "remove_bot",
+			Message::ListChallenge { .. } => "list_challenge",
+			Message::ListAi { .. } => "list_ai",
+			Message::ListMap { .. } => "list_map",
+			Message::ListRuleset { .. } => "list_ruleset",
+			Message::EnableCustomMaps => "enable_custom_maps",
+			Message::AssignColor { .. } => "assign_color",
+			Message::RulesetRequest { .. } => "ruleset_request",
+			Message::RulesetData { .. } => "ruleset_data",
+			Message::RulesetUnknown { .. } => "ruleset_unknown",
+			Message::Secrets { .. } => "secrets",
+			Message::Skins { .. } => "skins",
+			Message::InGame { .. } => "in_game",
+			Message::Start => "start",
+			Message::Game { .. } => "game",
+			Message::Tutorial { .. } => "tutorial",
+			Message::Challenge => "challenge",
+			Message::Briefing { .. } => "briefing",
+			Message::ReplayWithAnimations { .. } => "replay",
+			Message::Resign { .. } => "resign",
+			Message::HostSync { .. } => "host_sync",
+			Message::HostRejoinRequest { .. } => "host_rejoin_request",
+			Message::HostRejoinChanges { .. } => "host_rejoin_changes",
+			Message::Changes { .. } => "change",
+			Message::Orders { .. } => "order_new",
+			Message::Sync { .. } => "sync",
+			Message::Init => "init",
+			Message::RatingAndStars { .. } => "rating_and_stars",
+			Message::UpdatedRating { .. } => "rating",
+			Message::RecentStars { .. } => "recent_stars",
+			Message::Closing { .. } => "closing",
+			Message::Closed => "closed",
+			Message::Quit => "quit",
+			Message::Chat { .. } => "chat",
+			Message::LinkAccounts { .. } => "link_accounts",
+			Message::Debug { .. } => "debug",
+			Message::Error { .. } => "error",
+			Message::ResumeToken { .. } => "resume_token",
+		}
+	}
+}
+
+#[derive(
+	PartialEq, Eq, Copy, Clone, Serialize, Deserialize, JsonSchema, Debug,
+)]
 #[serde(rename_all = "lowercase")]
 pub enum ChatTarget
 {
@@ -419,7 +532,65 @@ pub enum ChatTarget
 	Lobby,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+// Sent along with Closing when the server refuses a new connection.
+#[derive(
+	Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, Debug,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum RefusalReason
+{
+	// The server has as many connections as it can handle.
+	ServerFull,
+	// There are too many connections from the same address.
+	IpConnectionLimit,
+	// There have been too many recent connections from the same address.
+	IpRateLimit,
+	// The address has been banned.
+	IpBlocked,
+}
+
+// Sent by the server when it ignores a message from the client, so that
+// client and bot authors do not have to guess what went wrong.
+#[derive(
+	Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, Debug,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode
+{
+	// The message could not be parsed.
+	MalformedMessage,
+	// The message is only ever sent by the server.
+	UnexpectedMessage,
+	// The message has a combination of fields that we do not accept.
+	InvalidMessage,
+	// The message cannot be sent by bots.
+	NotAllowedForBots,
+	// The message requires the client to have joined the server.
+	NotOnline,
+	// The metadata a bot sent along with ListAi is invalid.
+	InvalidBotMetadata,
+	// The client sent too many invalid messages and will be disconnected.
+	TooManyErrors,
+	// A server operator disconnected the client.
+	Kicked,
+}
+
+#[derive(
+	Clone, PartialEq, Serialize, Deserialize, JsonSchema, Default, Debug,
+)]
+pub struct ErrorMetadata
+{
+	// The type of the message that caused the error, if it could be parsed.
+	#[serde(default, skip_serializing_if = "is_zero")]
+	pub message_type: Option<String>,
+
+	#[serde(default, skip_serializing_if = "is_zero")]
+	pub detail: Option<String>,
+}
+
+#[derive(
+	Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, Debug,
+)]
 #[serde(rename_all = "lowercase")]
 pub enum Role
 {
@@ -439,7 +610,9 @@ impl Role
 	}
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+#[derive(
+	Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, Debug,
+)]
 #[serde(rename_all = "lowercase")]
 pub enum VisionType
 {
@@ -447,9 +620,77 @@ pub enum VisionType
 	Global,
 }
 
+#[derive(
+	Debug, Clone, Serialize, Deserialize, JsonSchema, Default, PartialEq, Eq,
+)]
+pub struct VersionMetadata
+{
+	// Sent by the client, in order of preference.
+	#[serde(default, skip_serializing_if = "is_zero")]
+	pub supported_compressions: Vec<Compression>,
+
+	// Sent by the server; every message after this one may be compressed.
+	#[serde(default, skip_serializing_if = "is_zero")]
+	pub compression: Option<Compression>,
+
+	// Sent by the client, in order of preference.
+	#[serde(default, skip_serializing_if = "is_zero")]
+	pub supported_encodings: Vec<Encoding>,
+
+	// Sent by the server; every message after this one uses this encoding.
+	#[serde(default, skip_serializing_if = "is_zero")]
+	pub encoding: Option<Encoding>,
+
+	// Sent by the client to ask for its traffic to be captured for debugging;
+	// sent back by the server if it agrees.
+	#[serde(default, skip_serializing_if = "is_zero")]
+	pub capture: bool,
+
+	// Sent by the client if it can resume its session after its connection
+	// drops; sent back by the server if it will issue resume tokens.
+	#[serde(default, skip_serializing_if = "is_zero")]
+	pub resumable: bool,
+
+	// Sent by the client to resume a session after its connection dropped.
+	#[serde(default, skip_serializing_if = "is_zero")]
+	pub resume_token: Option<String>,
+
+	// Sent by the server if the session was resumed; if not, the client
+	// has to join the server again.
+	#[serde(default, skip_serializing_if = "is_zero")]
+	pub resumed: bool,
+}
+
+#[derive(
+	Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, Debug,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression
+{
+	Zlib,
+	Zstd,
+	#[serde(other)]
+	#[schemars(skip)]
+	Unsupported,
+}
+
+#[derive(
+	Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, Debug,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding
+{
+	Json,
+	#[serde(rename = "msgpack")]
+	MessagePack,
+	#[serde(other)]
+	#[schemars(skip)]
+	Unsupported,
+}
+
 // Botslot strings always start with % and usernames cannot contain %, so we
 // can try to deserialize as a Botslot and if that fails it is a username.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum UsernameOrSlot
 {
@@ -458,7 +699,7 @@ pub enum UsernameOrSlot
 	Username(String),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum JoinMetadataOrTagMetadata
 {
@@ -474,7 +715,9 @@ impl Default for JoinMetadataOrTagMetadata
 	}
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[derive(
+	Debug, Clone, Serialize, Deserialize, JsonSchema, Default, PartialEq, Eq,
+)]
 pub struct JoinMetadata
 {
 	#[serde(default, skip_serializing_if = "Option::is_none")]
@@ -484,7 +727,9 @@ pub struct JoinMetadata
 	pub merge_token: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[derive(
+	Debug, Clone, Serialize, Deserialize, JsonSchema, Default, PartialEq, Eq,
+)]
 pub struct TagMetadata
 {
 	#[serde(default, skip_serializing_if = "is_zero")]
@@ -501,7 +746,15 @@ pub struct TagMetadata
 }
 
 #[derive(
-	PartialEq, Eq, Copy, Clone, Serialize, Deserialize, Default, Debug,
+	PartialEq,
+	Eq,
+	Copy,
+	Clone,
+	Serialize,
+	Deserialize,
+	JsonSchema,
+	Default,
+	Debug,
 )]
 pub struct LobbyMetadata
 {
@@ -521,19 +774,21 @@ pub struct LobbyMetadata
 	pub is_public: bool,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct AccountLinkingMetadata
 {
 	pub discord_user_id: String,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug)]
 pub struct BotAuthorsMetadata
 {
 	pub authors: String,
 }
 
-#[derive(PartialEq, Eq, Clone, Copy, Serialize, Deserialize, Debug)]
+#[derive(
+	PartialEq, Eq, Clone, Copy, Serialize, Deserialize, JsonSchema, Debug,
+)]
 #[serde(untagged)]
 pub enum ForwardingMetadata
 {
@@ -543,11 +798,12 @@ pub enum ForwardingMetadata
 	},
 	ClientHosted
 	{
-		player: PlayerColor
+		#[schemars(with = "schema::PlayerColor")]
+		player: PlayerColor,
 	},
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(untagged)]
 pub enum ListAiMetadata
 {
@@ -558,7 +814,7 @@ pub enum ListAiMetadata
 	Authors(BotAuthorsMetadata),
 }
 
-#[derive(Copy, Clone, Serialize, Deserialize, Debug)]
+#[derive(Copy, Clone, Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(untagged)]
 pub enum ListRulesetMetadata
 {
@@ -608,10 +864,11 @@ pub enum OnOrOff
 	On = 1,
 }
 
-#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Debug)]
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, JsonSchema, Debug)]
 pub struct HostSyncMetadata
 {
 	#[serde(default, skip_serializing_if = "is_zero")]
+	#[schemars(with = "Vec<schema::PlayerColor>")]
 	pub defeated_players: Vec<PlayerColor>,
 
 	pub game_over: bool,
@@ -0,0 +1,541 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use crate::server::handoff;
+use crate::server::message::*;
+use crate::server::tokio::State as ServerState;
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::Write as IoWrite;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync;
+use std::sync::atomic;
+
+use log::*;
+
+use futures::{FutureExt, StreamExt};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+pub static CONNECTED_CLIENTS: Gauge = Gauge::new();
+pub static LOGGED_IN_USERS: Gauge = Gauge::new();
+pub static OPEN_LOBBIES: GaugeVec = GaugeVec::new();
+pub static RUNNING_GAMES: Gauge = Gauge::new();
+pub static MESSAGES_RECEIVED: CounterVec = CounterVec::new();
+pub static MESSAGES_SENT: CounterVec = CounterVec::new();
+pub static SEND_QUEUE_DEPTH: Gauge = Gauge::new();
+pub static LOGIN_LATENCY: Histogram = Histogram::new();
+pub static LOGIN_FAILURES: CounterVec = CounterVec::new();
+pub static RATING_UPDATE_FAILURES: Counter = Counter::new();
+pub static REJECTED_CONNECTIONS: CounterVec = CounterVec::new();
+
+pub struct Counter
+{
+	value: atomic::AtomicU64,
+}
+
+impl Counter
+{
+	pub const fn new() -> Counter
+	{
+		Counter {
+			value: atomic::AtomicU64::new(0),
+		}
+	}
+
+	pub fn increment(&self)
+	{
+		self.value.fetch_add(1, atomic::Ordering::Relaxed);
+	}
+
+	fn get(&self) -> u64
+	{
+		self.value.load(atomic::Ordering::Relaxed)
+	}
+}
+
+pub struct Gauge
+{
+	value: atomic::AtomicI64,
+}
+
+impl Gauge
+{
+	pub const fn new() -> Gauge
+	{
+		Gauge {
+			value: atomic::AtomicI64::new(0),
+		}
+	}
+
+	pub fn increment(&self)
+	{
+		self.add(1);
+	}
+
+	pub fn decrement(&self)
+	{
+		self.add(-1);
+	}
+
+	pub fn add(&self, amount: i64)
+	{
+		self.value.fetch_add(amount, atomic::Ordering::Relaxed);
+	}
+
+	pub fn set(&self, value: i64)
+	{
+		self.value.store(value, atomic::Ordering::Relaxed);
+	}
+
+	fn get(&self) -> i64
+	{
+		self.value.load(atomic::Ordering::Relaxed)
+	}
+}
+
+// Counters with a single label, such as the type of a message.
+pub struct CounterVec
+{
+	values: sync::Mutex<BTreeMap<String, u64>>,
+}
+
+impl CounterVec
+{
+	pub const fn new() -> CounterVec
+	{
+		CounterVec {
+			values: sync::Mutex::new(BTreeMap::new()),
+		}
+	}
+
+	pub fn increment(&self, label: &str)
+	{
+		let mut values = lock(&self.values);
+		match values.get_mut(label)
+		{
+			Some(value) => *value += 1,
+			None =>
+			{
+				values.insert(label.to_string(), 1);
+			}
+		}
+	}
+
+	fn get(&self) -> Vec<(String, u64)>
+	{
+		let values = lock(&self.values);
+		values.iter().map(|(k, v)| (k.clone(), *v)).collect()
+	}
+}
+
+// Gauges with a single label, that are all set at once.
+pub struct GaugeVec
+{
+	values: sync::Mutex<BTreeMap<String, i64>>,
+}
+
+impl GaugeVec
+{
+	pub const fn new() -> GaugeVec
+	{
+		GaugeVec {
+			values: sync::Mutex::new(BTreeMap::new()),
+		}
+	}
+
+	pub fn set_all(&self, new_values: BTreeMap<String, i64>)
+	{
+		let mut values = lock(&self.values);
+		// Labels that are no longer present should drop to zero instead of
+		// keeping their last value.
+		for value in values.values_mut()
+		{
+			*value = 0;
+		}
+		values.extend(new_values);
+	}
+
+	fn get(&self) -> Vec<(String, i64)>
+	{
+		let values = lock(&self.values);
+		values.iter().map(|(k, v)| (k.clone(), *v)).collect()
+	}
+}
+
+const HISTOGRAM_BUCKETS_IN_SECONDS: [f64; 10] =
+	[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub struct Histogram
+{
+	data: sync::Mutex<HistogramData>,
+}
+
+struct HistogramData
+{
+	buckets: [u64; HISTOGRAM_BUCKETS_IN_SECONDS.len()],
+	sum_in_seconds: f64,
+	count: u64,
+}
+
+impl Histogram
+{
+	pub const fn new() -> Histogram
+	{
+		Histogram {
+			data: sync::Mutex::new(HistogramData {
+				buckets: [0; HISTOGRAM_BUCKETS_IN_SECONDS.len()],
+				sum_in_seconds: 0.0,
+				count: 0,
+			}),
+		}
+	}
+
+	pub fn observe(&self, duration: Duration)
+	{
+		let seconds = duration.as_secs_f64();
+		let mut data = lock(&self.data);
+		for (i, bound) in HISTOGRAM_BUCKETS_IN_SECONDS.iter().enumerate()
+		{
+			if seconds <= *bound
+			{
+				data.buckets[i] += 1;
+			}
+		}
+		data.sum_in_seconds += seconds;
+		data.count += 1;
+	}
+}
+
+fn lock<T>(mutex: &sync::Mutex<T>) -> sync::MutexGuard<'_, T>
+{
+	// A panic while holding the lock cannot leave the metrics in a state
+	// that is worse than slightly inaccurate.
+	match mutex.lock()
+	{
+		Ok(guard) => guard,
+		Err(poisoned) => poisoned.into_inner(),
+	}
+}
+
+// The type of a message, as it appears in the "type" field.
+pub fn message_type(message: &Message) -> &'static str
+{
+	message.type_name()
+}
+
+pub fn render() -> String
+{
+	let mut output = String::new();
+	render_gauge(
+		&mut output,
+		"epicinium_connected_clients",
+		"Number of open client connections.",
+		&CONNECTED_CLIENTS,
+	);
+	render_gauge(
+		&mut output,
+		"epicinium_logged_in_users",
+		"Number of users that have joined the server.",
+		&LOGGED_IN_USERS,
+	);
+	render_gauge_vec(
+		&mut output,
+		"epicinium_open_lobbies",
+		"Number of listed lobbies.",
+		"lobby_type",
+		&OPEN_LOBBIES,
+	);
+	render_gauge(
+		&mut output,
+		"epicinium_running_games",
+		"Number of games in progress.",
+		&RUNNING_GAMES,
+	);
+	render_counter_vec(
+		&mut output,
+		"epicinium_messages_received_total",
+		"Number of messages received from clients.",
+		"type",
+		&MESSAGES_RECEIVED,
+	);
+	render_counter_vec(
+		&mut output,
+		"epicinium_messages_sent_total",
+		"Number of messages sent to clients.",
+		"type",
+		&MESSAGES_SENT,
+	);
+	render_gauge(
+		&mut output,
+		"epicinium_send_queue_depth",
+		"Number of messages queued for all clients combined.",
+		&SEND_QUEUE_DEPTH,
+	);
+	render_histogram(
+		&mut output,
+		"epicinium_login_duration_seconds",
+		"Time taken by the login server to respond.",
+		&LOGIN_LATENCY,
+	);
+	render_counter_vec(
+		&mut output,
+		"epicinium_login_failures_total",
+		"Number of failed logins.",
+		"status",
+		&LOGIN_FAILURES,
+	);
+	render_counter(
+		&mut output,
+		"epicinium_rating_update_failures_total",
+		"Number of ratings or stars that could not be stored.",
+		&RATING_UPDATE_FAILURES,
+	);
+	render_counter_vec(
+		&mut output,
+		"epicinium_rejected_connections_total",
+		"Number of connections refused because of limits or bans.",
+		"limit",
+		&REJECTED_CONNECTIONS,
+	);
+	output
+}
+
+fn render_header(output: &mut String, name: &str, help: &str, kind: &str)
+{
+	let _ = writeln!(output, "# HELP {} {}", name, help);
+	let _ = writeln!(output, "# TYPE {} {}", name, kind);
+}
+
+fn render_counter(output: &mut String, name: &str, help: &str, x: &Counter)
+{
+	render_header(output, name, help, "counter");
+	let _ = writeln!(output, "{} {}", name, x.get());
+}
+
+fn render_gauge(output: &mut String, name: &str, help: &str, x: &Gauge)
+{
+	render_header(output, name, help, "gauge");
+	let _ = writeln!(output, "{} {}", name, x.get());
+}
+
+fn render_counter_vec(
+	output: &mut String,
+	name: &str,
+	help: &str,
+	label: &str,
+	x: &CounterVec,
+)
+{
+	render_header(output, name, help, "counter");
+	for (value, count) in x.get()
+	{
+		let _ =
+			writeln!(output, "{}{{{}=\"{}\"}} {}", name, label, value, count);
+	}
+}
+
+fn render_gauge_vec(
+	output: &mut String,
+	name: &str,
+	help: &str,
+	label: &str,
+	x: &GaugeVec,
+)
+{
+	render_header(output, name, help, "gauge");
+	for (value, count) in x.get()
+	{
+		let _ =
+			writeln!(output, "{}{{{}=\"{}\"}} {}", name, label, value, count);
+	}
+}
+
+fn render_histogram(output: &mut String, name: &str, help: &str, x: &Histogram)
+{
+	render_header(output, name, help, "histogram");
+	let data = lock(&x.data);
+	for (i, bound) in HISTOGRAM_BUCKETS_IN_SECONDS.iter().enumerate()
+	{
+		let count = data.buckets[i];
+		let _ =
+			writeln!(output, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+	}
+	let _ = writeln!(output, "{}_bucket{{le=\"+Inf\"}} {}", name, data.count);
+	let _ = writeln!(output, "{}_sum {}", name, data.sum_in_seconds);
+	let _ = writeln!(output, "{}_count {}", name, data.count);
+}
+
+pub async fn run(port: u16, server_state: watch::Receiver<ServerState>)
+{
+	// The metrics reveal nothing secret, but they are not meant for players,
+	// so we only listen on the loopback interface.
+	let address = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+	let key = format!("metrics/{}", address);
+	let bound = match handoff::take_tcp(&key)
+	{
+		Some(listener) => TcpListener::from_std(listener),
+		None => TcpListener::bind(&address).await,
+	};
+	let mut listener = match bound
+	{
+		Ok(listener) => listener,
+		Err(error) =>
+		{
+			error!("Failed to serve metrics on {}: {}", address, error);
+			return;
+		}
+	};
+	handoff::register(key, &listener);
+	info!("Serving metrics on {}...", address);
+
+	let closed = wait_until_closed(server_state).boxed();
+	let mut connections = listener.incoming().take_until(closed);
+
+	while let Some(connection) = connections.next().await
+	{
+		match connection
+		{
+			Ok(socket) =>
+			{
+				tokio::spawn(serve(socket));
+			}
+			Err(error) =>
+			{
+				warn!("Failed to accept metrics connection: {:?}", error);
+			}
+		}
+	}
+}
+
+async fn wait_until_closed(mut server_state: watch::Receiver<ServerState>)
+{
+	while let Some(state) = server_state.recv().await
+	{
+		match state
+		{
+			ServerState::Open => (),
+			// After a handoff, the new server process serves the metrics.
+			ServerState::Closing if handoff::is_handed_off() => break,
+			ServerState::Closing => (),
+			ServerState::Closed => break,
+		}
+	}
+}
+
+async fn serve(socket: TcpStream)
+{
+	let timeout = Duration::from_secs(10);
+	match tokio::time::timeout(timeout, respond(socket)).await
+	{
+		Ok(Ok(())) => (),
+		Ok(Err(error)) => debug!("Error serving metrics: {}", error),
+		Err(_elapsed) => debug!("Timed out while serving metrics."),
+	}
+}
+
+async fn respond(mut socket: TcpStream) -> Result<(), std::io::Error>
+{
+	// We only need the request line, but we read the entire head so that
+	// the scraper does not see its request being cut off.
+	let mut request = Vec::new();
+	let mut buffer = [0u8; 1024];
+	while !request.ends_with(b"\r\n\r\n") && request.len() < 8192
+	{
+		let n = socket.read(&mut buffer).await?;
+		if n == 0
+		{
+			break;
+		}
+		request.extend_from_slice(&buffer[..n]);
+	}
+
+	let (status, content_type, body) = if request.starts_with(b"GET /metrics ")
+		|| request.starts_with(b"GET / ")
+	{
+		("200 OK", "text/plain; version=0.0.4", render())
+	}
+	else
+	{
+		("404 Not Found", "text/plain", "Not Found\n".to_string())
+	};
+
+	let mut response = Vec::new();
+	write!(
+		&mut response,
+		"HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+		 Connection: close\r\n\r\n{}",
+		status,
+		content_type,
+		body.len(),
+		body
+	)?;
+	socket.write_all(&response).await?;
+	socket.shutdown(std::net::Shutdown::Write)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn test_message_type()
+	{
+		assert_eq!(message_type(&Message::Pulse), "pulse");
+		assert_eq!(
+			message_type(&Message::EnableCustomMaps),
+			"enable_custom_maps"
+		);
+		let message = Message::Chat {
+			content: "x".repeat(1000),
+			sender: None,
+			target: ChatTarget::General,
+		};
+		assert_eq!(message_type(&message), "chat");
+		let message = Message::ReplayWithAnimations {
+			on_or_off: OnOrOff::Off,
+		};
+		assert_eq!(message_type(&message), "replay");
+	}
+
+	#[test]
+	fn test_histogram()
+	{
+		let histogram = Histogram::new();
+		histogram.observe(Duration::from_millis(30));
+		histogram.observe(Duration::from_secs(20));
+		let mut output = String::new();
+		render_histogram(&mut output, "x", "X.", &histogram);
+		assert!(output.contains("x_bucket{le=\"0.025\"} 0\n"));
+		assert!(output.contains("x_bucket{le=\"0.05\"} 1\n"));
+		assert!(output.contains("x_bucket{le=\"10\"} 1\n"));
+		assert!(output.contains("x_bucket{le=\"+Inf\"} 2\n"));
+		assert!(output.contains("x_count 2\n"));
+	}
+}
@@ -21,21 +21,30 @@
  * Sander in 't Veld (sander@abunchofhacks.coop)
  */
 
+mod admin;
+mod bans;
 mod botslot;
 mod chat;
 mod client;
 mod discord_api;
 mod game;
+mod handoff;
+mod hibernation;
 mod lobby;
 mod login;
 mod logrotate;
 mod message;
+mod metrics;
 mod portal;
 mod rating;
+mod reload;
 mod slack_api;
 mod terminate;
+mod tls;
 
 pub mod countingtest;
+pub mod replay;
+pub mod schema;
 pub mod settings;
 pub mod tokio;
 
@@ -79,15 +79,24 @@ pub fn setup(settings: &Settings) -> Result<Setup, anyhow::Error>
 	}
 }
 
-pub async fn bind(setup: Setup) -> Result<Binding, anyhow::Error>
+// A server that takes over from a previous server process also takes over
+// its registration, if any, so that the port stays the same.
+pub async fn bind(
+	setup: Setup,
+	inherited_registration: Option<String>,
+) -> Result<Binding, anyhow::Error>
 {
-	match setup
+	match (setup, inherited_registration)
 	{
-		Setup::Real {
-			http,
-			registration_url,
-		} => Connection::bind(http, registration_url).await,
-		Setup::Dev { port } =>
+		(Setup::Real { http, .. }, Some(url)) => Connection::adopt(http, &url),
+		(
+			Setup::Real {
+				http,
+				registration_url,
+			},
+			None,
+		) => Connection::bind(http, registration_url).await,
+		(Setup::Dev { port }, _) =>
 		{
 			let binding = Binding {
 				connection: None,
@@ -109,6 +118,13 @@ impl Binding
 		}
 	}
 
+	pub fn registration(&self) -> Option<String>
+	{
+		self.connection
+			.as_ref()
+			.map(|connection| connection.registered_url.to_string())
+	}
+
 	pub async fn unbind(self) -> Result<(), anyhow::Error>
 	{
 		match self.connection
@@ -176,6 +192,24 @@ impl Connection
 		})
 	}
 
+	fn adopt(http: http::Client, url: &str) -> Result<Binding, anyhow::Error>
+	{
+		let registered_url = http::Url::parse(url)?;
+		let port: u16 = registered_url
+			.path_segments()
+			.and_then(|mut segments| segments.next_back())
+			.ok_or_else(|| anyhow!("invalid registration '{}'", url))?
+			.parse()?;
+
+		Ok(Binding {
+			connection: Some(Connection {
+				http,
+				registered_url,
+			}),
+			port,
+		})
+	}
+
 	async fn deregister(self) -> Result<(), anyhow::Error>
 	{
 		let _: http::Response = self
@@ -29,6 +29,7 @@ use crate::server::game::MatchType;
 use crate::server::login::UserId;
 use crate::server::message::Message;
 use crate::server::message::ResponseStatus;
+use crate::server::metrics;
 use crate::server::settings::Settings;
 
 use std::collections::HashMap;
@@ -146,6 +147,7 @@ impl Database
 			None =>
 			{
 				error!("Missing entry for user id {:?}!", user_id);
+				metrics::RATING_UPDATE_FAILURES.increment();
 				// We do not want this to end the rating task.
 				return;
 			}
@@ -172,6 +174,7 @@ impl Database
 						Ok(()) => (),
 						Err(error) =>
 						{
+							metrics::RATING_UPDATE_FAILURES.increment();
 							error!("Error running server: {}", error);
 							error!("{:#?}", error);
 							println!("Error running server: {}", error);
@@ -212,6 +215,7 @@ impl Database
 					Ok(()) => (),
 					Err(error) =>
 					{
+						metrics::RATING_UPDATE_FAILURES.increment();
 						error!("Error running server: {}", error);
 						error!("{:#?}", error);
 						println!("Error running server: {}", error);
@@ -0,0 +1,245 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use crate::server::discord_api;
+use crate::server::login;
+use crate::server::settings::{Loader, Settings};
+use crate::server::slack_api;
+use crate::server::tokio::State as ServerState;
+
+use std::sync;
+
+use log::*;
+
+use futures::{FutureExt, StreamExt};
+
+use tokio::signal::unix::SignalKind;
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+
+pub async fn run(
+	settings: Settings,
+	load_settings: Loader,
+	login_server: sync::Arc<login::Server>,
+	slack_api: mpsc::Sender<slack_api::Setup>,
+	discord_api: mpsc::Sender<discord_api::Setup>,
+	server_state: watch::Receiver<ServerState>,
+)
+{
+	let handler = match tokio::signal::unix::signal(SignalKind::hangup())
+	{
+		Ok(handler) => handler,
+		Err(error) =>
+		{
+			error!("Error running server: {}", error);
+			error!("{:#?}", error);
+			println!("Error running server: {}", error);
+			return;
+		}
+	};
+
+	let closed = wait_until_closed(server_state).boxed();
+	let mut signals = handler.take_until(closed);
+
+	let mut reloader = Reloader {
+		settings,
+		login_server,
+		slack_api,
+		discord_api,
+	};
+
+	while let Some(()) = signals.next().await
+	{
+		info!("Reloading settings...");
+		let result = match load_settings()
+		{
+			Ok(settings) => reloader.apply(settings).await,
+			Err(error) => Err(error),
+		};
+		match result
+		{
+			Ok(()) => (),
+			Err(error) => error!("Failed to reload settings: {:#}", error),
+		}
+	}
+}
+
+async fn wait_until_closed(mut server_state: watch::Receiver<ServerState>)
+{
+	while let Some(state) = server_state.recv().await
+	{
+		match state
+		{
+			ServerState::Open => (),
+			ServerState::Closing => (),
+			ServerState::Closed => break,
+		}
+	}
+}
+
+struct Reloader
+{
+	// The settings that are currently in effect.
+	settings: Settings,
+	login_server: sync::Arc<login::Server>,
+	slack_api: mpsc::Sender<slack_api::Setup>,
+	discord_api: mpsc::Sender<discord_api::Setup>,
+}
+
+impl Reloader
+{
+	async fn apply(&mut self, settings: Settings) -> Result<(), anyhow::Error>
+	{
+		let mut current = as_map(&self.settings)?;
+		let reloaded = as_map(&settings)?;
+		let changed: Vec<String> = reloaded
+			.iter()
+			.filter(|(key, value)| current.get(*key) != Some(value))
+			.map(|(key, _)| key.clone())
+			.collect();
+		if changed.is_empty()
+		{
+			info!("Settings are unchanged.");
+			return Ok(());
+		}
+
+		let changed_among = |keys: &[&'static str]| -> Vec<&'static str> {
+			keys.iter()
+				.copied()
+				.filter(|key| changed.iter().any(|x| x == key))
+				.collect()
+		};
+		let mut applied: Vec<&str> = Vec::new();
+		let mut failed: Vec<&str> = Vec::new();
+
+		let keys = changed_among(&["loglevel"]);
+		if !keys.is_empty()
+		{
+			let level = settings
+				.loglevel
+				.unwrap_or(crate::common::log::Level::Verbose);
+			crate::common::log::set_level(level);
+			applied.extend(keys);
+		}
+
+		let keys = changed_among(&["slackurl", "slackname"]);
+		if !keys.is_empty()
+		{
+			match slack_api::setup(&settings)
+			{
+				Ok(setup) => match self.slack_api.send(setup).await
+				{
+					Ok(()) => applied.extend(keys),
+					Err(_) =>
+					{
+						error!("Failed to reload Slack: task stopped");
+						failed.extend(keys);
+					}
+				},
+				Err(error) =>
+				{
+					error!("Failed to reload Slack: {:#}", error);
+					failed.extend(keys);
+				}
+			}
+		}
+
+		let keys = changed_among(&["discordurl"]);
+		if !keys.is_empty()
+		{
+			match discord_api::setup(&settings)
+			{
+				Ok(setup) => match self.discord_api.send(setup).await
+				{
+					Ok(()) => applied.extend(keys),
+					Err(_) =>
+					{
+						error!("Failed to reload Discord: task stopped");
+						failed.extend(keys);
+					}
+				},
+				Err(error) =>
+				{
+					error!("Failed to reload Discord: {:#}", error);
+					failed.extend(keys);
+				}
+			}
+		}
+
+		let keys = changed_among(&["local-bots"]);
+		if !keys.is_empty()
+		{
+			self.login_server.reload_local_bots(&settings);
+			applied.extend(keys);
+		}
+
+		// Nothing reads this setting, so changing it has no effect at all.
+		let unsupported = changed_among(&["allow-discord-login"]);
+
+		for key in &applied
+		{
+			if let Some(value) = reloaded.get(*key)
+			{
+				current.insert(key.to_string(), value.clone());
+			}
+		}
+		self.settings = serde_json::from_value(current.into())?;
+
+		let needs_restart: Vec<&str> = changed
+			.iter()
+			.map(|key| key.as_str())
+			.filter(|key| !applied.contains(key) && !failed.contains(key))
+			.filter(|key| !unsupported.contains(key))
+			.collect();
+		if !applied.is_empty()
+		{
+			info!("Applied settings: {}.", applied.join(", "));
+		}
+		if !unsupported.is_empty()
+		{
+			warn!(
+				"Settings that are not supported: {}.",
+				unsupported.join(", ")
+			);
+		}
+		if !needs_restart.is_empty()
+		{
+			warn!(
+				"Settings that need a restart to take effect: {}.",
+				needs_restart.join(", ")
+			);
+		}
+		Ok(())
+	}
+}
+
+fn as_map(
+	settings: &Settings,
+) -> Result<serde_json::Map<String, serde_json::Value>, anyhow::Error>
+{
+	match serde_json::to_value(settings)?
+	{
+		serde_json::Value::Object(map) => Ok(map),
+		_ => Err(anyhow::anyhow!("settings are not an object")),
+	}
+}
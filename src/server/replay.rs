@@ -0,0 +1,209 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use crate::server::client::capture::{Direction, Entry};
+use crate::server::message::*;
+
+use std::net::SocketAddr;
+
+use anyhow::Context;
+
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+// Replays the messages that were sent by captured clients, each over its own
+// connection, to a server running in dev mode. The n-th capture logs in with
+// dev token n, so at most eight captures can be replayed at the same time.
+#[tokio::main]
+pub async fn run(
+	filenames: Vec<String>,
+	server: String,
+	port: u16,
+	instant: bool,
+) -> Result<(), anyhow::Error>
+{
+	let serveraddress: SocketAddr = format!("{}:{}", server, port).parse()?;
+
+	let mut replays = Vec::new();
+	for (number, filename) in filenames.iter().enumerate()
+	{
+		let entries = load(filename)?;
+		replays.push(replay(number, entries, serveraddress, instant));
+	}
+	futures::future::try_join_all(replays).await?;
+	Ok(())
+}
+
+fn load(filename: &str) -> Result<Vec<Entry>, anyhow::Error>
+{
+	let raw = std::fs::read_to_string(filename)
+		.with_context(|| format!("reading '{}'", filename))?;
+	let mut entries = Vec::new();
+	for (i, line) in raw.lines().enumerate()
+	{
+		let entry: Entry = serde_json::from_str(line).with_context(|| {
+			format!("parsing line {} of '{}'", i + 1, filename)
+		})?;
+		if entry.direction == Direction::In
+		{
+			entries.push(entry);
+		}
+	}
+	Ok(entries)
+}
+
+async fn replay(
+	number: usize,
+	entries: Vec<Entry>,
+	serveraddress: SocketAddr,
+	instant: bool,
+) -> Result<(), anyhow::Error>
+{
+	let connection = TcpStream::connect(&serveraddress).await?;
+	let (reader, writer) = tokio::io::split(connection);
+
+	let (sendbuffer, messages) = mpsc::channel::<Message>(1000);
+	let token = (number + 1).to_string();
+
+	let send_task = send_messages(number, writer, messages);
+	let receive_task = receive_messages(number, reader, sendbuffer.clone());
+	let script_task = follow_script(entries, sendbuffer, token, instant);
+
+	futures::try_join!(send_task, receive_task, script_task)?;
+	Ok(())
+}
+
+async fn follow_script(
+	entries: Vec<Entry>,
+	mut sendbuffer: mpsc::Sender<Message>,
+	token: String,
+	instant: bool,
+) -> Result<(), anyhow::Error>
+{
+	let start = Instant::now();
+	let mut has_quit = false;
+
+	for entry in entries
+	{
+		let message = match entry.message()?
+		{
+			// We answer pings ourselves, as they are not deterministic.
+			Message::Pulse | Message::Pong => continue,
+			// Stick to plain JSON so that we can read what the server says.
+			Message::Version { version, .. } => Message::Version {
+				version,
+				metadata: Default::default(),
+			},
+			// Tokens are not captured, so we use a dev token instead.
+			Message::JoinServer {
+				status: None,
+				content: None,
+				sender,
+				metadata,
+			} => Message::JoinServer {
+				status: None,
+				content: Some(token.clone()),
+				sender,
+				metadata,
+			},
+			Message::Quit =>
+			{
+				has_quit = true;
+				Message::Quit
+			}
+			message => message,
+		};
+
+		if !instant
+		{
+			let due = start + Duration::from_millis(entry.milliseconds);
+			tokio::time::delay_until(due).await;
+		}
+
+		sendbuffer.send(message).await?;
+	}
+
+	if !has_quit
+	{
+		sendbuffer.send(Message::Quit).await?;
+	}
+	Ok(())
+}
+
+async fn send_messages(
+	number: usize,
+	mut socket: WriteHalf<TcpStream>,
+	mut messages: mpsc::Receiver<Message>,
+) -> Result<(), anyhow::Error>
+{
+	while let Some(message) = messages.recv().await
+	{
+		let jsonstr = serde_json::to_string(&message)?;
+		println!("[{}] -> {}", number, jsonstr);
+
+		let length = jsonstr.len() as u32;
+		socket.write_all(&length.to_be_bytes()).await?;
+		socket.write_all(jsonstr.as_bytes()).await?;
+	}
+	Ok(())
+}
+
+async fn receive_messages(
+	number: usize,
+	mut socket: ReadHalf<TcpStream>,
+	mut sendbuffer: mpsc::Sender<Message>,
+) -> Result<(), anyhow::Error>
+{
+	loop
+	{
+		let length = match socket.read_u32().await
+		{
+			Ok(length) => length,
+			Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof =>
+			{
+				return Ok(());
+			}
+			Err(error) => return Err(error.into()),
+		};
+		if length == 0
+		{
+			continue;
+		}
+
+		let mut buffer = vec![0u8; length as usize];
+		socket.read_exact(&mut buffer).await?;
+		let jsonstr = String::from_utf8(buffer)?;
+		println!("[{}] <- {}", number, jsonstr);
+
+		let message: Message = serde_json::from_str(&jsonstr)?;
+		match message
+		{
+			Message::Ping => sendbuffer.send(Message::Pong).await?,
+			Message::Quit => return Ok(()),
+			_ => (),
+		}
+	}
+}
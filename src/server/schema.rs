@@ -0,0 +1,310 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use crate::common::keycode::Keycode;
+use crate::common::version::Version;
+use crate::server::botslot::{Botslot, EmptyBotslot};
+use crate::server::lobby::Secret;
+use crate::server::message::{Message, OnOrOff, ResponseStatus};
+
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::{InstanceType, Metadata, Schema, SchemaObject};
+use schemars::JsonSchema;
+
+use serde::de::DeserializeOwned;
+
+pub fn generate() -> schemars::schema::RootSchema
+{
+	let settings = SchemaSettings::draft07();
+	let generator = SchemaGenerator::new(settings);
+	generator.into_root_schema_for::<Message>()
+}
+
+pub fn generate_as_json() -> Result<String, serde_json::Error>
+{
+	let schema = generate();
+	serde_json::to_string_pretty(&schema)
+}
+
+// Types that are (de)serialized through their string representation.
+
+fn string_schema(description: &str) -> Schema
+{
+	let schema = SchemaObject {
+		instance_type: Some(InstanceType::String.into()),
+		metadata: Some(Box::new(Metadata {
+			description: Some(description.to_string()),
+			..Default::default()
+		})),
+		..Default::default()
+	};
+	schema.into()
+}
+
+impl JsonSchema for Version
+{
+	fn schema_name() -> String
+	{
+		"Version".to_string()
+	}
+
+	fn json_schema(_gen: &mut SchemaGenerator) -> Schema
+	{
+		string_schema("A version such as \"1.1.0\" or \"1.1.0-rc2\".")
+	}
+}
+
+impl JsonSchema for Keycode
+{
+	fn schema_name() -> String
+	{
+		"Keycode".to_string()
+	}
+
+	fn json_schema(_gen: &mut SchemaGenerator) -> Schema
+	{
+		string_schema("A base32 identifier, used for lobbies and clients.")
+	}
+}
+
+impl JsonSchema for Botslot
+{
+	fn schema_name() -> String
+	{
+		"Botslot".to_string()
+	}
+
+	fn json_schema(_gen: &mut SchemaGenerator) -> Schema
+	{
+		string_schema(
+			"A percent sign followed by a capital letter, e.g. \"%A\".",
+		)
+	}
+}
+
+impl JsonSchema for EmptyBotslot
+{
+	fn schema_name() -> String
+	{
+		"EmptyBotslot".to_string()
+	}
+
+	fn json_schema(_gen: &mut SchemaGenerator) -> Schema
+	{
+		let schema = SchemaObject {
+			instance_type: Some(InstanceType::String.into()),
+			enum_values: Some(vec!["".into()]),
+			..Default::default()
+		};
+		schema.into()
+	}
+}
+
+impl JsonSchema for Secret
+{
+	fn schema_name() -> String
+	{
+		"Secret".to_string()
+	}
+
+	fn json_schema(_gen: &mut SchemaGenerator) -> Schema
+	{
+		string_schema("An opaque secret that grants access to a lobby.")
+	}
+}
+
+// Types that are (de)serialized as integers using serde_repr, which schemars
+// does not understand. We list whichever values deserialize successfully,
+// so that this cannot get out of sync with the enum itself.
+
+fn repr_schema<T: DeserializeOwned>() -> Schema
+{
+	let values = (0..=u8::MAX)
+		.filter(|&x| serde_json::from_value::<T>(x.into()).is_ok())
+		.map(|x| x.into())
+		.collect();
+	let schema = SchemaObject {
+		instance_type: Some(InstanceType::Integer.into()),
+		enum_values: Some(values),
+		..Default::default()
+	};
+	schema.into()
+}
+
+impl JsonSchema for ResponseStatus
+{
+	fn schema_name() -> String
+	{
+		"ResponseStatus".to_string()
+	}
+
+	fn json_schema(_gen: &mut SchemaGenerator) -> Schema
+	{
+		repr_schema::<ResponseStatus>()
+	}
+}
+
+impl JsonSchema for OnOrOff
+{
+	fn schema_name() -> String
+	{
+		"OnOrOff".to_string()
+	}
+
+	fn json_schema(_gen: &mut SchemaGenerator) -> Schema
+	{
+		repr_schema::<OnOrOff>()
+	}
+}
+
+// Types defined in epicinium_lib, for which we cannot implement JsonSchema
+// directly. These mirrors are only used to describe the wire format.
+
+#[derive(JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayerColor
+{
+	None,
+	Red,
+	Blue,
+	Yellow,
+	Teal,
+	Black,
+	Pink,
+	Indigo,
+	Purple,
+	Blind,
+	Observer,
+}
+
+#[derive(JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Difficulty
+{
+	None,
+	Easy,
+	Medium,
+	Hard,
+}
+
+/// A change to the game state, as defined by the game logic.
+#[derive(JsonSchema)]
+pub struct Change(pub serde_json::Value);
+
+/// An order given by a player, as defined by the game logic.
+#[derive(JsonSchema)]
+pub struct Order(pub serde_json::Value);
+
+#[derive(JsonSchema)]
+pub struct MapMetadata
+{
+	pub playercount: i32,
+	pub rows: i32,
+	pub cols: i32,
+
+	#[serde(rename = "pool")]
+	pub pool_type: PoolType,
+
+	#[serde(rename = "ruleset", default)]
+	pub ruleset_name: Option<String>,
+
+	#[serde(flatten)]
+	pub other: std::collections::HashMap<String, serde_json::Value>,
+}
+
+#[derive(JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolType
+{
+	None,
+	Multiplayer,
+	Custom,
+	Diorama,
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	use crate::logic;
+
+	use serde_json::json;
+
+	// Regenerate with `cargo run --bin schema > schema/protocol.json`.
+	const EXPECTED: &str = include_str!("../../schema/protocol.json");
+
+	#[test]
+	fn test_up_to_date() -> Result<(), serde_json::Error>
+	{
+		let generated = generate_as_json()?;
+		assert!(
+			generated.trim() == EXPECTED.trim(),
+			"schema/protocol.json is out of date; regenerate it with \
+			 `cargo run --bin schema > schema/protocol.json`"
+		);
+		Ok(())
+	}
+
+	fn enum_values<T: JsonSchema>() -> Vec<serde_json::Value>
+	{
+		let schema = schemars::schema_for!(T);
+		schema.schema.enum_values.unwrap_or_default()
+	}
+
+	#[test]
+	fn test_mirrors() -> Result<(), serde_json::Error>
+	{
+		for value in enum_values::<PlayerColor>()
+		{
+			let color: logic::player::PlayerColor =
+				serde_json::from_value(value.clone())?;
+			assert_eq!(serde_json::to_value(color)?, value);
+		}
+		let players = enum_values::<PlayerColor>();
+		for color in logic::player::color_pool()
+		{
+			assert!(players.contains(&serde_json::to_value(color)?));
+		}
+
+		for value in enum_values::<Difficulty>()
+		{
+			let difficulty: logic::difficulty::Difficulty =
+				serde_json::from_value(value.clone())?;
+			assert_eq!(serde_json::to_value(difficulty)?, value);
+		}
+
+		for value in enum_values::<PoolType>()
+		{
+			let metadata: logic::map::Metadata =
+				serde_json::from_value(json!({
+					"playercount": 2,
+					"rows": 10,
+					"cols": 10,
+					"pool": value,
+				}))?;
+			assert_eq!(serde_json::to_value(metadata)?["pool"], value);
+		}
+		Ok(())
+	}
+}
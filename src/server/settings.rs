@@ -22,26 +22,75 @@
  */
 
 use crate::common::log;
+use crate::common::logrotate;
 
 use std::path::Path;
 
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 
 use anyhow::Context;
 
-#[derive(Clone, Deserialize, Debug, Default)]
-#[serde(rename_all = "kebab-case")]
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Settings
 {
 	#[serde(default)]
 	pub logname: Option<String>,
 	#[serde(default)]
 	pub loglevel: Option<log::Level>,
+	#[serde(default)]
+	pub logformat: Option<log::Format>,
+	#[serde(default)]
+	pub log_rotate_size: Option<u64>,
+	#[serde(default)]
+	pub log_rotate_age: Option<u64>,
+	#[serde(default)]
+	pub log_retention: Option<usize>,
+	#[serde(default)]
+	pub log_compress: Option<bool>,
 
 	#[serde(default)]
-	pub server: Option<String>,
+	pub server: Option<OneOrMany<String>>,
 	#[serde(default)]
 	pub port: Option<u16>,
+	#[serde(default)]
+	pub websocket_port: Option<u16>,
+	#[serde(default)]
+	pub tls_certificate: Option<String>,
+	#[serde(default)]
+	pub tls_private_key: Option<String>,
+	#[serde(default)]
+	pub proxy_protocol: Option<bool>,
+	#[serde(default)]
+	pub unix_socket: Option<String>,
+	#[serde(default)]
+	pub local_bots: Option<Vec<LocalBot>>,
+	#[serde(default)]
+	pub admin_socket: Option<String>,
+	#[serde(default)]
+	pub metrics_port: Option<u16>,
+	#[serde(default)]
+	pub max_connections: Option<usize>,
+	#[serde(default)]
+	pub max_connections_per_ip: Option<usize>,
+	#[serde(default)]
+	pub max_connection_rate_per_ip: Option<usize>,
+	#[serde(default)]
+	pub ban_list: Option<String>,
+	#[serde(default)]
+	pub allow_capture: Option<bool>,
+	#[serde(default)]
+	pub send_queue_capacity: Option<usize>,
+	#[serde(default)]
+	pub send_queue_threshold: Option<usize>,
+	#[serde(default)]
+	pub slow_client_timeout: Option<u64>,
+	#[serde(default)]
+	pub resume_grace_period: Option<u64>,
+	#[serde(default)]
+	pub drain_deadline: Option<u64>,
+	#[serde(default)]
+	pub game_manifest: Option<String>,
 
 	#[serde(default)]
 	pub login_server: Option<String>,
@@ -59,15 +108,233 @@ pub struct Settings
 	pub discordurl: Option<String>,
 }
 
+// A bot account that may log in through the Unix socket without a token.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct LocalBot
+{
+	pub user_id: u64,
+	pub username: String,
+}
+
+// Either a single value or a list of values, such as "::" or
+// ["0.0.0.0", "::1"].
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum OneOrMany<T>
+{
+	One(T),
+	Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T>
+{
+	pub fn into_vec(self) -> Vec<T>
+	{
+		match self
+		{
+			OneOrMany::One(value) => vec![value],
+			OneOrMany::Many(values) => values,
+		}
+	}
+}
+
+// Loads the settings again in the same way they were loaded at startup,
+// so that they can be reloaded while the server is running.
+pub type Loader = Box<dyn Fn() -> Result<Settings, anyhow::Error> + Send>;
+
+// Environment variables such as EPICINIUM_SLOW_CLIENT_TIMEOUT override the
+// setting with the corresponding key, in this case 'slow-client-timeout'.
+pub const ENV_PREFIX: &str = "EPICINIUM_";
+
+// Names the settings file itself, so it is not a setting.
+pub const ENV_SETTINGS_FILENAME: &str = "EPICINIUM_SETTINGS";
+
+// Passes listening sockets to a new server process, so it is not a setting.
+pub const ENV_HANDOFF: &str = "EPICINIUM_HANDOFF";
+
+// Used when no settings file is given, if they exist.
+const DEFAULT_FILENAMES: [&str; 2] =
+	["settings-server.toml", "settings-server.json"];
+
+type Layer = serde_json::Map<String, serde_json::Value>;
+
 impl Settings
 {
+	// Loads a TOML file if the filename ends in '.toml' and JSON otherwise.
 	pub fn load(filename: &str) -> Result<Settings, anyhow::Error>
 	{
-		let filename = Path::new(filename);
-		let raw = std::fs::read_to_string(filename)?;
-		let settings = serde_json::from_str(&raw).with_context(|| {
-			format!("parsing settings from '{}'", filename.display())
-		})?;
+		let layer = load_layer(filename)?;
+		let settings = from_layer(layer)
+			.with_context(|| format!("parsing settings from '{}'", filename))?;
 		Ok(settings)
 	}
+
+	// Combines the settings file, then environment variables and then the
+	// given overrides, where each layer takes precedence over the ones
+	// before it. Without a filename, the server can be configured entirely
+	// through environment variables.
+	pub fn load_layered(
+		filename: Option<&str>,
+		overrides: &Settings,
+	) -> Result<Settings, anyhow::Error>
+	{
+		let filename = filename.or_else(|| {
+			DEFAULT_FILENAMES
+				.iter()
+				.copied()
+				.find(|filename| Path::new(filename).exists())
+		});
+		let mut merged = match filename
+		{
+			Some(filename) =>
+			{
+				let layer = load_layer(filename)?;
+				// Check the file on its own first, for a clearer error.
+				let _checked =
+					from_layer(layer.clone()).with_context(|| {
+						format!("parsing settings from '{}'", filename)
+					})?;
+				layer
+			}
+			None => Layer::new(),
+		};
+
+		let variables = std::env::vars()
+			.filter(|(name, _)| name.starts_with(ENV_PREFIX))
+			.filter(|(name, _)| name != ENV_SETTINGS_FILENAME)
+			.filter(|(name, _)| name != ENV_HANDOFF);
+		for (name, value) in variables
+		{
+			let key = name[ENV_PREFIX.len()..].to_lowercase().replace('_', "-");
+			let mut layer = Layer::new();
+			layer.insert(key.clone(), parse_value(&value));
+			if from_layer(layer.clone()).is_err()
+			{
+				layer.insert(key, serde_json::Value::String(value));
+			}
+			let _checked = from_layer(layer.clone()).with_context(|| {
+				format!("parsing environment variable {}", name)
+			})?;
+			merged.extend(layer);
+		}
+
+		let overrides = match serde_json::to_value(overrides)?
+		{
+			serde_json::Value::Object(layer) => layer,
+			_ => Layer::new(),
+		};
+		merged.extend(overrides.into_iter().filter(|(_, x)| !x.is_null()));
+
+		from_layer(merged)
+	}
+
+	// A size or age of zero means logs are not rotated for that reason.
+	pub fn log_rotation(&self) -> logrotate::Policy
+	{
+		let defaults = logrotate::Policy::default();
+		let max_size = match self.log_rotate_size
+		{
+			Some(0) => None,
+			Some(bytes) => Some(bytes),
+			None => defaults.max_size,
+		};
+		let max_age = match self.log_rotate_age
+		{
+			Some(0) => None,
+			Some(seconds) => Some(std::time::Duration::from_secs(seconds)),
+			None => defaults.max_age,
+		};
+		logrotate::Policy {
+			max_size,
+			max_age,
+			retention: self.log_retention.unwrap_or(defaults.retention),
+			compress: self.log_compress.unwrap_or(defaults.compress),
+		}
+	}
+
+	// The settings that are set, in the format of a JSON settings file.
+	pub fn describe(&self) -> Result<String, anyhow::Error>
+	{
+		let layer: Layer = match serde_json::to_value(self)?
+		{
+			serde_json::Value::Object(layer) =>
+			{
+				layer.into_iter().filter(|(_, x)| !x.is_null()).collect()
+			}
+			_ => Layer::new(),
+		};
+		let description = serde_json::to_string_pretty(&layer)?;
+		Ok(description)
+	}
+}
+
+fn load_layer(filename: &str) -> Result<Layer, anyhow::Error>
+{
+	let path = Path::new(filename);
+	let raw = std::fs::read_to_string(path)
+		.with_context(|| format!("reading settings from '{}'", filename))?;
+	let is_toml = path.extension().is_some_and(|x| x == "toml");
+	let value: serde_json::Value = if is_toml
+	{
+		toml::from_str(&raw)
+			.with_context(|| format!("parsing settings from '{}'", filename))?
+	}
+	else
+	{
+		serde_json::from_str(&raw)
+			.with_context(|| format!("parsing settings from '{}'", filename))?
+	};
+	match value
+	{
+		serde_json::Value::Object(layer) => Ok(layer),
+		_ => Err(anyhow::anyhow!(
+			"expected a table of settings in '{}'",
+			filename
+		)),
+	}
+}
+
+fn from_layer(layer: Layer) -> Result<Settings, anyhow::Error>
+{
+	let settings = serde_json::from_value(serde_json::Value::Object(layer))?;
+	Ok(settings)
+}
+
+// Values are read as JSON if possible, so that numbers, booleans and lists
+// can be given. If that does not fit the setting, the value is read as a
+// plain string instead.
+fn parse_value(value: &str) -> serde_json::Value
+{
+	match serde_json::from_str(value)
+	{
+		Ok(value) => value,
+		Err(_) => serde_json::Value::String(value.to_string()),
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn test_parse_value()
+	{
+		assert_eq!(parse_value("30"), serde_json::json!(30));
+		assert_eq!(parse_value("::"), serde_json::json!("::"));
+		assert_eq!(
+			parse_value(r#"["::", "0.0.0.0"]"#),
+			serde_json::json!(["::", "0.0.0.0"])
+		);
+	}
+
+	#[test]
+	fn test_unknown_keys()
+	{
+		let mut layer = Layer::new();
+		layer.insert("prot".to_string(), serde_json::json!(9999));
+		let error = from_layer(layer).unwrap_err().to_string();
+		assert!(error.contains("unknown field `prot`"), "{}", error);
+	}
 }
@@ -31,6 +31,9 @@ use serde_json::json;
 
 use anyhow::anyhow;
 
+use futures::stream;
+use futures::StreamExt;
+
 use tokio::sync::mpsc;
 
 use reqwest as http;
@@ -61,26 +64,58 @@ pub fn setup(settings: &Settings) -> Result<Setup, anyhow::Error>
 	}
 }
 
-pub async fn run(setup: Setup, mut posts: mpsc::Receiver<Post>)
+pub async fn run(
+	setup: Setup,
+	posts: mpsc::Receiver<Post>,
+	reloads: mpsc::Receiver<Setup>,
+)
 {
-	match setup
+	let mut connection = setup.connection;
+	if let Some(ref connection) = connection
 	{
-		Setup {
-			connection: Some(mut connection),
-		} =>
-		{
-			info!("Connected.");
-			connection.run(posts).await;
-			info!("Finished sending posts to Slack.");
-		}
-		Setup { connection: None } =>
+		info!("Connected.");
+		connection.send_status("Server started.").await;
+	}
+
+	let mut events = stream::select(
+		posts.map(Event::Post),
+		reloads.map(|setup| Event::Reload(setup.connection)),
+	);
+	while let Some(event) = events.next().await
+	{
+		match event
 		{
-			while let Some(post) = posts.recv().await
+			Event::Post(post) => match connection
+			{
+				Some(ref connection) => connection.send(post).await,
+				None => debug!("{}", post.message),
+			},
+			Event::Reload(new_connection) =>
 			{
-				debug!("{}", post.message);
+				connection = new_connection;
+				if connection.is_some()
+				{
+					info!("Reconnected.");
+				}
+				else
+				{
+					info!("Disconnected.");
+				}
 			}
 		}
 	}
+
+	if let Some(ref connection) = connection
+	{
+		connection.send_status("Server stopped.").await;
+		info!("Finished sending posts to Slack.");
+	}
+}
+
+enum Event
+{
+	Post(Post),
+	Reload(Option<Connection>),
 }
 
 struct Connection
@@ -121,20 +156,10 @@ impl Connection
 		Ok(connection)
 	}
 
-	async fn run(&mut self, mut posts: mpsc::Receiver<Post>)
+	async fn send_status(&self, message: &str)
 	{
 		let post = Post {
-			message: "Server started.".to_string(),
-		};
-		self.send(post).await;
-
-		while let Some(post) = posts.recv().await
-		{
-			self.send(post).await;
-		}
-
-		let post = Post {
-			message: "Server stopped.".to_string(),
+			message: message.to_string(),
 		};
 		self.send(post).await;
 	}
@@ -30,12 +30,20 @@ use log::*;
 pub struct Setup
 {
 	filename: String,
+	contents: String,
 }
 
 impl Drop for Setup
 {
 	fn drop(&mut self)
 	{
+		// After a handoff, the file belongs to the new server process.
+		match std::fs::read_to_string(&self.filename)
+		{
+			Ok(contents) if contents != self.contents => return,
+			_ => (),
+		}
+
 		match std::fs::remove_file(&self.filename)
 		{
 			Ok(()) =>
@@ -48,16 +56,20 @@ impl Drop for Setup
 	}
 }
 
-pub fn setup() -> Result<Setup, std::io::Error>
+// A server that takes over from a previous server process replaces its file.
+pub fn setup(is_successor: bool) -> Result<Setup, std::io::Error>
 {
 	let filename = "terminate.sh".to_string();
+	let contents = format!("kill -TERM {}", std::process::id());
 	let mut file = std::fs::OpenOptions::new()
 		.write(true)
-		.create_new(true)
+		.create_new(!is_successor)
+		.create(is_successor)
+		.truncate(is_successor)
 		.open(&filename)?;
-	write!(file, "kill -TERM {}", std::process::id())?;
+	file.write_all(contents.as_bytes())?;
 	file.set_permissions(Permissions::from_mode(0o744))?;
 
-	let setup = Setup { filename };
+	let setup = Setup { filename, contents };
 	Ok(setup)
 }
@@ -0,0 +1,61 @@
+/*
+ * Part of epicinium_server
+ * developed by A Bunch of Hacks.
+ *
+ * Copyright (c) 2018-2021 A Bunch of Hacks
+ *
+ * This library is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This library is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ * [authors:]
+ * Sander in 't Veld (sander@abunchofhacks.coop)
+ */
+
+use crate::server::settings::Settings;
+
+use std::sync;
+
+use anyhow::anyhow;
+
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+
+pub type Acceptor = sync::Arc<SslAcceptor>;
+
+pub fn setup(settings: &Settings) -> Result<Option<Acceptor>, anyhow::Error>
+{
+	let (certificate, private_key) =
+		match (&settings.tls_certificate, &settings.tls_private_key)
+		{
+			(Some(certificate), Some(private_key)) =>
+			{
+				(certificate, private_key)
+			}
+			(None, None) => return Ok(None),
+			(Some(_), None) =>
+			{
+				return Err(anyhow!("missing 'tls-private-key'"))
+			}
+			(None, Some(_)) =>
+			{
+				return Err(anyhow!("missing 'tls-certificate'"))
+			}
+		};
+
+	let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+	builder.set_certificate_chain_file(certificate)?;
+	builder.set_private_key_file(private_key, SslFiletype::PEM)?;
+	builder.check_private_key()?;
+	let acceptor = builder.build();
+
+	Ok(Some(sync::Arc::new(acceptor)))
+}
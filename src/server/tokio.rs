@@ -25,32 +25,47 @@ use crate::common::coredump::enable_coredumps;
 use crate::common::keycode::*;
 use crate::logic::challenge;
 use crate::logic::ruleset;
+use crate::server::admin;
+use crate::server::bans;
 use crate::server::chat;
 use crate::server::client;
 use crate::server::discord_api;
+use crate::server::handoff;
+use crate::server::hibernation;
+use crate::server::lobby;
 use crate::server::login;
 use crate::server::logrotate;
+use crate::server::message::RefusalReason;
+use crate::server::metrics;
 use crate::server::portal;
 use crate::server::rating;
+use crate::server::reload;
 use crate::server::settings::*;
 use crate::server::slack_api;
 use crate::server::terminate;
+use crate::server::tls;
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
 use std::sync;
 use std::sync::atomic;
 
 use log::*;
 
 use anyhow::anyhow;
+use anyhow::Context;
 
 use futures::future;
-use futures::{FutureExt, StreamExt};
+use futures::{FutureExt, StreamExt, TryStreamExt};
 
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{UnixListener, UnixStream};
 use tokio::signal::unix::SignalKind;
 use tokio::sync::mpsc;
 use tokio::sync::watch;
+use tokio::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, Type};
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum State
@@ -70,27 +85,45 @@ pub struct Server
 	discord_setup: discord_api::Setup,
 	rating_database: rating::Database,
 	challenge_pool: Vec<challenge::Challenge>,
-	ip_address: String,
+	ip_addresses: Vec<IpAddr>,
+	websocket_port: Option<u16>,
+	tls: Option<tls::Acceptor>,
+	proxy_protocol: bool,
+	unix_socket: Option<String>,
+	admin_socket: Option<String>,
+	metrics_port: Option<u16>,
+	client_options: client::Options,
+	admissions: client::Admissions,
+	bans: bans::BanList,
+	hibernated_games: Vec<hibernation::Game>,
+	hibernated_lobbies: Vec<lobby::Snapshot>,
+	settings: Settings,
+	load_settings: Loader,
 }
 
 pub fn setup_server(
 	settings: &Settings,
+	load_settings: Loader,
 	log_setup: logrotate::Setup,
 ) -> Result<Server, anyhow::Error>
 {
 	enable_coredumps()?;
 	increase_sockets()?;
 
-	let scoped_terminate = terminate::setup()?;
+	handoff::inherit()?;
 
-	let server = settings
-		.server
-		.as_ref()
-		.ok_or_else(|| anyhow!("missing 'server'"))?;
-	let ip_address = server.to_string();
+	let scoped_terminate = terminate::setup(handoff::is_successor())?;
+
+	let ip_addresses = parse_ip_addresses(settings)?;
 
 	ruleset::initialize_collection()?;
 
+	let (hibernated_games, hibernated_lobbies) = match &settings.game_manifest
+	{
+		Some(filename) => hibernation::load(filename)?,
+		None => (Vec::new(), Vec::new()),
+	};
+
 	let server = Server {
 		scoped_terminate,
 		log_setup,
@@ -100,7 +133,20 @@ pub fn setup_server(
 		discord_setup: discord_api::setup(settings)?,
 		rating_database: rating::initialize(settings)?,
 		challenge_pool: challenge::load_pool()?,
-		ip_address,
+		ip_addresses,
+		websocket_port: settings.websocket_port,
+		tls: tls::setup(settings)?,
+		proxy_protocol: settings.proxy_protocol.unwrap_or(false),
+		unix_socket: settings.unix_socket.clone(),
+		admin_socket: settings.admin_socket.clone(),
+		metrics_port: settings.metrics_port,
+		client_options: client::Options::from_settings(settings)?,
+		admissions: client::Admissions::from_settings(settings),
+		bans: bans::BanList::load(settings)?,
+		hibernated_games,
+		hibernated_lobbies,
+		settings: settings.clone(),
+		load_settings,
 	};
 	Ok(server)
 }
@@ -117,32 +163,132 @@ pub async fn run_server(server: Server)
 		discord_setup,
 		rating_database,
 		challenge_pool,
-		ip_address,
+		ip_addresses,
+		websocket_port,
+		tls,
+		proxy_protocol,
+		unix_socket,
+		admin_socket,
+		metrics_port,
+		client_options,
+		admissions,
+		bans,
+		hibernated_games,
+		hibernated_lobbies,
+		settings,
+		load_settings,
 	} = server;
 
+	let game_manifest = settings.game_manifest.clone();
+	let login_server = sync::Arc::new(login_server);
+
 	let (slack_in, slack_out) = mpsc::channel::<slack_api::Post>(10000);
-	let slack_task = slack_api::run(slack_setup, slack_out);
+	let (slack_reload_in, slack_reload_out) = mpsc::channel(1);
+	let slack_task = slack_api::run(slack_setup, slack_out, slack_reload_out);
 
 	let (rating_in, rating_out) = mpsc::channel::<rating::Update>(10000);
 	let rating_task = rating::run(rating_database, rating_out);
 
 	let (discord_in, discord_out) = mpsc::channel::<discord_api::Post>(10000);
-	let discord_task = discord_api::run(discord_setup, discord_out);
+	let (discord_reload_in, discord_reload_out) = mpsc::channel(1);
+	let discord_task =
+		discord_api::run(discord_setup, discord_out, discord_reload_out);
 
 	let (state_in, state_out) = watch::channel(State::Open);
 	let (client_canary_in, client_canary_out) = mpsc::channel::<()>(1);
 	let (general_canary_in, general_canary_out) = mpsc::channel::<()>(1);
-	let close_task =
-		wait_for_close(general_canary_out, client_canary_out, state_in);
+	let (handoff_in, handoff_out) = mpsc::channel::<()>(1);
+	let close_task = wait_for_close(
+		general_canary_out,
+		client_canary_out,
+		handoff_out,
+		state_in,
+	);
 
 	let (general_in, general_out) = mpsc::channel::<chat::Update>(10000);
-	let chat_task = chat::run(general_out, general_canary_in, &challenge_pool);
+	let (admin_chat_in, admin_chat_out) = mpsc::channel::<chat::Update>(100);
+	let chat_task = chat::run(
+		general_out,
+		admin_chat_out,
+		general_canary_in,
+		&challenge_pool,
+	);
+
+	// The recorded games and lobbies now belong to this process, which records
+	// them again if they are still around when it shuts down.
+	if let Some(filename) = &game_manifest
+	{
+		match hibernation::claim(filename)
+		{
+			Ok(()) => (),
+			Err(error) => error!("Failed to claim game manifest: {}", error),
+		}
+	}
+
+	let drain_deadline = settings.drain_deadline.map(Duration::from_secs);
+	for game in hibernated_games
+	{
+		lobby::resume(
+			game,
+			rating_in.clone(),
+			discord_in.clone(),
+			general_in.clone(),
+			client_canary_in.clone(),
+			state_out.clone(),
+			drain_deadline,
+		);
+	}
+	for snapshot in hibernated_lobbies
+	{
+		lobby::restore(
+			snapshot,
+			rating_in.clone(),
+			discord_in.clone(),
+			general_in.clone(),
+			client_canary_in.clone(),
+			state_out.clone(),
+			drain_deadline,
+		);
+	}
 
 	let logrotate_task =
 		logrotate::run(log_setup, state_out.clone(), slack_in.clone());
 
+	let admin_task = match admin_socket
+	{
+		Some(path) =>
+		{
+			admin::run(path, admin_chat_in, bans.clone(), state_out.clone())
+				.boxed()
+		}
+		None => future::ready(()).boxed(),
+	};
+
+	let reload_task = reload::run(
+		settings,
+		load_settings,
+		login_server.clone(),
+		slack_reload_in,
+		discord_reload_in,
+		state_out.clone(),
+	);
+
+	let metrics_task = match metrics_port
+	{
+		Some(port) => metrics::run(port, state_out.clone()).boxed(),
+		None => future::ready(()).boxed(),
+	};
+
 	let acceptance_task = accept_clients(
-		ip_address,
+		ip_addresses,
+		websocket_port,
+		tls,
+		proxy_protocol,
+		unix_socket,
+		client_options,
+		admissions,
+		bans,
+		client::Sessions::default(),
 		login_server,
 		portal_setup,
 		general_in,
@@ -151,24 +297,98 @@ pub async fn run_server(server: Server)
 		discord_in,
 		state_out,
 		client_canary_in,
+		handoff_in,
 	);
 
-	let server_task = future::join4(
+	let server_task = future::join5(
 		acceptance_task,
 		future::join(chat_task, rating_task),
 		future::join3(slack_task, discord_task, logrotate_task),
+		future::join3(admin_task, metrics_task, reload_task),
 		close_task,
 	)
-	.map(|((), ((), ()), ((), (), ()), ())| ());
+	.map(|((), ((), ()), ((), (), ()), ((), (), ()), ())| ());
 
 	server_task.await;
 
+	if handoff::is_handed_off()
+	{
+		// The new server process has loaded the game manifest when it
+		// started, and our games were played to the end instead.
+		info!("Not writing the game manifest after handing off.");
+	}
+	else if let Some(filename) = &game_manifest
+	{
+		match hibernation::save(filename)
+		{
+			Ok(()) => (),
+			Err(error) => error!("Failed to write game manifest: {}", error),
+		}
+	}
+
 	let _discarded = scoped_terminate;
 }
 
+#[derive(Debug, Clone, Copy)]
+enum Transport
+{
+	Tcp,
+	WebSocket,
+	Unix,
+}
+
+enum Listener
+{
+	Tcp(TcpListener),
+	Unix(UnixListener),
+}
+
+#[derive(Debug)]
+enum Incoming
+{
+	Tcp(TcpStream),
+	Unix(UnixStream),
+}
+
+// The ticket of an admitted connection, or why it was turned away.
+type Admission = Result<client::Ticket, RefusalReason>;
+
+fn parse_ip_addresses(settings: &Settings)
+	-> Result<Vec<IpAddr>, anyhow::Error>
+{
+	let addresses = settings
+		.server
+		.clone()
+		.ok_or_else(|| anyhow!("missing 'server'"))?
+		.into_vec();
+	if addresses.is_empty()
+	{
+		return Err(anyhow!("'server' must contain at least one address"));
+	}
+
+	addresses
+		.iter()
+		.map(|address| {
+			// IPv6 addresses may be written with brackets, as in URLs.
+			let ip = address.trim_start_matches('[').trim_end_matches(']');
+			ip.parse().with_context(|| {
+				format!("invalid IP address '{}' in 'server'", address)
+			})
+		})
+		.collect()
+}
+
 async fn accept_clients(
-	ip_address: String,
-	login_server: login::Server,
+	ip_addresses: Vec<IpAddr>,
+	websocket_port: Option<u16>,
+	tls: Option<tls::Acceptor>,
+	proxy_protocol: bool,
+	unix_socket: Option<String>,
+	client_options: client::Options,
+	admissions: client::Admissions,
+	bans: bans::BanList,
+	sessions: client::Sessions,
+	login: sync::Arc<login::Server>,
 	portal_setup: portal::Setup,
 	general_chat: mpsc::Sender<chat::Update>,
 	ratings: mpsc::Sender<rating::Update>,
@@ -176,9 +396,11 @@ async fn accept_clients(
 	discord_api: mpsc::Sender<discord_api::Post>,
 	server_state: watch::Receiver<State>,
 	client_canary: mpsc::Sender<()>,
+	handed_off: mpsc::Sender<()>,
 )
 {
-	let binding = match portal::bind(portal_setup).await
+	let binding = match portal::bind(portal_setup, handoff::registration())
+		.await
 	{
 		Ok(binding) => binding,
 		Err(error) =>
@@ -192,22 +414,16 @@ async fn accept_clients(
 	// If binding succeeds, we must unbind.
 
 	let port = binding.port;
-	let address: SocketAddr = match format!("{}:{}", ip_address, port).parse()
-	{
-		Ok(address) => address,
-		Err(error) =>
-		{
-			warn!("Failed to parse '{}:{}': {}", ip_address, port, error);
-			let localhost = std::net::Ipv4Addr::new(127, 0, 0, 1);
-			SocketAddr::new(std::net::IpAddr::V4(localhost), port)
-		}
-	};
-
-	let listener = match TcpListener::bind(&address).await
+	let listeners = match bind_listeners(
+		&ip_addresses,
+		port,
+		websocket_port,
+		unix_socket.as_deref(),
+	)
 	{
-		Ok(listener) => match binding.confirm().await
+		Ok(listeners) => match binding.confirm().await
 		{
-			Ok(()) => Some(listener),
+			Ok(()) => Some(listeners),
 			Err(error) =>
 			{
 				error!("Error running server: {}", error);
@@ -225,25 +441,57 @@ async fn accept_clients(
 		}
 	};
 
-	if let Some(listener) = listener
+	if let Some(listeners) = listeners
 	{
-		info!("Listening on {}...", address);
-
-		listen(
-			listener,
-			login_server,
-			general_chat,
-			ratings,
-			slack_api,
-			discord_api,
-			server_state,
-			client_canary,
-		)
-		.await;
+		handoff::notify_ready();
+
+		let ticker = sync::Arc::new(atomic::AtomicU64::new(rand::random()));
+		let lobbyticker =
+			sync::Arc::new(atomic::AtomicU64::new(rand::random()));
+
+		let tasks = listeners.into_iter().map(|(listener, transport)| {
+			listen(
+				listener,
+				transport,
+				tls.clone(),
+				proxy_protocol,
+				client_options,
+				admissions.clone(),
+				bans.clone(),
+				sessions.clone(),
+				login.clone(),
+				ticker.clone(),
+				lobbyticker.clone(),
+				general_chat.clone(),
+				ratings.clone(),
+				slack_api.clone(),
+				discord_api.clone(),
+				server_state.clone(),
+				client_canary.clone(),
+			)
+		});
+		let handoff_task =
+			wait_for_handoff(&binding, server_state.clone(), handed_off);
+		future::join(future::join_all(tasks), handoff_task).await;
 
 		info!("Stopped listening.");
 	}
 
+	// After a handoff, the socket and registration belong to the new server.
+	if handoff::is_handed_off()
+	{
+		return;
+	}
+
+	if let Some(path) = unix_socket
+	{
+		match std::fs::remove_file(&path)
+		{
+			Ok(()) => (),
+			Err(error) => warn!("Failed to remove '{}': {}", path, error),
+		}
+	}
+
 	match binding.unbind().await
 	{
 		Ok(()) => (),
@@ -256,9 +504,133 @@ async fn accept_clients(
 	}
 }
 
+fn bind_listeners(
+	ip_addresses: &[IpAddr],
+	port: u16,
+	websocket_port: Option<u16>,
+	unix_socket: Option<&str>,
+) -> Result<Vec<(Listener, Transport)>, anyhow::Error>
+{
+	// An IPv6 listener on '::' would also claim the port for IPv4, unless we
+	// tell it not to, so that IPv4 addresses can be listed separately.
+	let dual_stack = !ip_addresses.iter().any(|ip| ip.is_ipv4());
+
+	let mut listeners = Vec::new();
+
+	for &ip in ip_addresses
+	{
+		let address = SocketAddr::new(ip, port);
+		let key = format!("tcp/{}", address);
+		let listener = bind_or_inherit(key, address, dual_stack)?;
+		info!("Listening on {}...", address);
+		listeners.push((Listener::Tcp(listener), Transport::Tcp));
+	}
+
+	if let Some(port) = websocket_port
+	{
+		for &ip in ip_addresses
+		{
+			let address = SocketAddr::new(ip, port);
+			let key = format!("websocket/{}", address);
+			let listener = bind_or_inherit(key, address, dual_stack)?;
+			info!("Listening for WebSocket connections on {}...", address);
+			listeners.push((Listener::Tcp(listener), Transport::WebSocket));
+		}
+	}
+
+	if let Some(path) = unix_socket
+	{
+		let key = format!("unix/{}", path);
+		let listener = match handoff::take_unix(&key)
+		{
+			Some(listener) => UnixListener::from_std(listener)?,
+			None => bind_unix_socket(path)?,
+		};
+		handoff::register(key, &listener);
+		info!("Listening on {}...", path);
+		listeners.push((Listener::Unix(listener), Transport::Unix));
+	}
+
+	Ok(listeners)
+}
+
+fn bind_or_inherit(
+	key: String,
+	address: SocketAddr,
+	dual_stack: bool,
+) -> Result<TcpListener, anyhow::Error>
+{
+	let listener = match handoff::take_tcp(&key)
+	{
+		Some(listener) => TcpListener::from_std(listener)?,
+		None => bind(address, dual_stack)?,
+	};
+	handoff::register(key, &listener);
+	Ok(listener)
+}
+
+fn bind(
+	address: SocketAddr,
+	dual_stack: bool,
+) -> Result<TcpListener, anyhow::Error>
+{
+	let domain = match address
+	{
+		SocketAddr::V4(_) => Domain::ipv4(),
+		SocketAddr::V6(_) => Domain::ipv6(),
+	};
+	let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+	if address.is_ipv6()
+	{
+		socket.set_only_v6(!dual_stack)?;
+	}
+	socket.set_reuse_address(true)?;
+	socket
+		.bind(&address.into())
+		.with_context(|| format!("binding to {}", address))?;
+	socket.listen(1024)?;
+
+	let listener = socket.into_tcp_listener();
+	listener.set_nonblocking(true)?;
+	let listener = TcpListener::from_std(listener)?;
+	Ok(listener)
+}
+
+fn bind_unix_socket(path: &str) -> Result<UnixListener, anyhow::Error>
+{
+	// A socket left behind by a previous run would make binding fail.
+	match std::fs::symlink_metadata(path)
+	{
+		Ok(metadata) if metadata.file_type().is_socket() =>
+		{
+			std::fs::remove_file(path)?;
+		}
+		_ => (),
+	}
+
+	let listener = UnixListener::bind(path)
+		.with_context(|| format!("binding to '{}'", path))?;
+
+	// Anyone who can connect is trusted to run local bots, so we only let
+	// the owner and group of the server connect. To be safe from anyone
+	// connecting before this, put the socket in a directory of its own.
+	let permissions = std::fs::Permissions::from_mode(0o660);
+	std::fs::set_permissions(path, permissions)?;
+	Ok(listener)
+}
+
 async fn listen(
-	mut listener: TcpListener,
-	login_server: login::Server,
+	mut listener: Listener,
+	transport: Transport,
+	tls: Option<tls::Acceptor>,
+	proxy_protocol: bool,
+	client_options: client::Options,
+	admissions: client::Admissions,
+	bans: bans::BanList,
+	sessions: client::Sessions,
+	login: sync::Arc<login::Server>,
+	ticker: sync::Arc<atomic::AtomicU64>,
+	lobbyticker: sync::Arc<atomic::AtomicU64>,
 	general_chat: mpsc::Sender<chat::Update>,
 	ratings: mpsc::Sender<rating::Update>,
 	slack_api: mpsc::Sender<slack_api::Post>,
@@ -267,19 +639,25 @@ async fn listen(
 	client_canary: mpsc::Sender<()>,
 )
 {
-	let login = sync::Arc::new(login_server);
-
-	let mut ticker: u64 = rand::random();
-	let lobbyticker = sync::Arc::new(atomic::AtomicU64::new(rand::random()));
-
 	let closing = wait_for_closing(server_state.clone()).boxed();
-	let mut connections = listener.incoming().take_until(closing);
+	let incoming = match listener
+	{
+		Listener::Tcp(ref mut listener) =>
+		{
+			listener.incoming().map_ok(Incoming::Tcp).boxed()
+		}
+		Listener::Unix(ref mut listener) =>
+		{
+			listener.incoming().map_ok(Incoming::Unix).boxed()
+		}
+	};
+	let mut connections = incoming.take_until(closing);
 
-	while let Some(socket) = connections.next().await
+	while let Some(incoming) = connections.next().await
 	{
-		let socket = match socket
+		let incoming = match incoming
 		{
-			Ok(socket) => socket,
+			Ok(incoming) => incoming,
 			Err(error) =>
 			{
 				warn!("Failed to connect client: {:?}", error);
@@ -287,27 +665,200 @@ async fn listen(
 			}
 		};
 
-		info!("Accepting incoming connection: {:?}", socket);
+		// Behind a proxy, the address of the client itself is only known
+		// once the handshake has revealed it.
+		if proxy_protocol
+		{
+			debug!("Accepting proxied connection: {:?}", incoming);
+		}
+		else
+		{
+			info!("Accepting incoming connection: {:?}", incoming);
+		}
 
-		let serial = ticker;
-		ticker += 1;
+		let serial = ticker.fetch_add(1, atomic::Ordering::Relaxed);
 		let key: u16 = rand::random();
 		let id = keycode(key, serial);
 
-		client::accept(
-			socket,
-			id,
-			login.clone(),
-			general_chat.clone(),
-			ratings.clone(),
-			slack_api.clone(),
-			discord_api.clone(),
-			server_state.clone(),
-			client_canary.clone(),
-			lobbyticker.clone(),
-		);
+		let accept = {
+			let admissions = admissions.clone();
+			let bans = bans.clone();
+			let sessions = sessions.clone();
+			let login = login.clone();
+			let general_chat = general_chat.clone();
+			let ratings = ratings.clone();
+			let slack_api = slack_api.clone();
+			let discord_api = discord_api.clone();
+			let server_state = server_state.clone();
+			let client_canary = client_canary.clone();
+			let lobbyticker = lobbyticker.clone();
+			move |connection, address, admission: Admission| {
+				let mut ticket = match admission
+				{
+					Ok(ticket) => ticket,
+					Err(reason) =>
+					{
+						client::refuse(connection, id, reason);
+						return;
+					}
+				};
+
+				// Behind a proxy, bans and the limits per IP address can only
+				// be applied once the handshake has revealed the address.
+				if proxy_protocol
+				{
+					let checked = match address
+					{
+						client::Address::Ip(ip) => check_ban(&bans, id, ip),
+						client::Address::Local => Ok(()),
+					};
+					let checked = checked.and_then(|()| {
+						admissions
+							.attribute(&mut ticket, address)
+							.map_err(Into::into)
+					});
+					match checked
+					{
+						Ok(()) => (),
+						Err(reason) =>
+						{
+							client::refuse(connection, id, reason);
+							return;
+						}
+					}
+				}
+
+				client::accept(
+					connection,
+					id,
+					address,
+					client_options,
+					sessions,
+					login,
+					general_chat,
+					ratings,
+					slack_api,
+					discord_api,
+					server_state,
+					client_canary,
+					lobbyticker,
+					ticket,
+					bans,
+				);
+
+				info!("Accepted client {} from {}.", id, address);
+			}
+		};
+
+		let socket = match incoming
+		{
+			Incoming::Tcp(socket) => socket,
+			Incoming::Unix(socket) =>
+			{
+				let connection = client::Connection::Raw(Box::new(socket));
+				let address = client::Address::Local;
+				let admission = admissions.admit(address).map_err(Into::into);
+				accept(connection, address, admission);
+				continue;
+			}
+		};
+		let peer_address = match socket.peer_addr()
+		{
+			Ok(address) => address,
+			Err(error) =>
+			{
+				warn!("Failed to connect client: {:?}", error);
+				continue;
+			}
+		};
+
+		let websocket = match transport
+		{
+			Transport::Tcp => false,
+			Transport::WebSocket => true,
+			Transport::Unix => false,
+		};
+		let needs_handshake = tls.is_some() || websocket || proxy_protocol;
 
-		info!("Accepted client {}.", id);
+		// Connections are admitted before their handshake, so that
+		// handshakes in progress count towards the limits. Clients that are
+		// turned away still complete the handshake, so that we can tell them
+		// why before closing the connection.
+		let admission = if proxy_protocol
+		{
+			admissions.admit_unattributed()
+		}
+		else
+		{
+			admissions.admit(client::Address::Ip(peer_address))
+		};
+		let admission: Admission = match admission
+		{
+			Ok(ticket) if !proxy_protocol =>
+			{
+				check_ban(&bans, id, peer_address).map(|()| ticket)
+			}
+			Ok(ticket) => Ok(ticket),
+			Err(rejection) => Err(rejection.into()),
+		};
+
+		if !needs_handshake
+		{
+			let connection = client::Connection::Raw(Box::new(socket));
+			accept(connection, client::Address::Ip(peer_address), admission);
+			continue;
+		}
+
+		// The handshakes require roundtrips, so we do not want to
+		// block other incoming connections while we wait for them.
+		let tls = tls.clone();
+		tokio::spawn(async move {
+			let handshake = client::establish(
+				socket,
+				peer_address,
+				proxy_protocol,
+				tls,
+				websocket,
+			);
+			let timeout = Duration::from_secs(10);
+			match tokio::time::timeout(timeout, handshake).await
+			{
+				Ok(Ok((connection, address))) =>
+				{
+					if proxy_protocol
+					{
+						info!("Client {} is connecting from {}.", id, address);
+					}
+					accept(connection, client::Address::Ip(address), admission)
+				}
+				Ok(Err(error)) =>
+				{
+					warn!("Handshake with client {} failed: {}", id, error);
+				}
+				Err(_elapsed) =>
+				{
+					warn!("Handshake with client {} timed out.", id);
+				}
+			}
+		});
+	}
+}
+
+fn check_ban(
+	bans: &bans::BanList,
+	id: Keycode,
+	address: SocketAddr,
+) -> Result<(), RefusalReason>
+{
+	match bans.find_ip(address.ip())
+	{
+		Some(ban) =>
+		{
+			info!("Client {} from {} is banned: {:?}", id, address, ban);
+			metrics::REJECTED_CONNECTIONS.increment("ban");
+			Err(RefusalReason::IpBlocked)
+		}
+		None => Ok(()),
 	}
 }
 
@@ -324,9 +875,46 @@ async fn wait_for_closing(mut server_state: watch::Receiver<State>)
 	}
 }
 
+async fn wait_for_handoff(
+	binding: &portal::Binding,
+	server_state: watch::Receiver<State>,
+	mut handed_off: mpsc::Sender<()>,
+)
+{
+	let handler = match tokio::signal::unix::signal(SignalKind::user_defined2())
+	{
+		Ok(handler) => handler,
+		Err(error) =>
+		{
+			error!("Error running server: {}", error);
+			error!("{:#?}", error);
+			println!("Error running server: {}", error);
+			return;
+		}
+	};
+
+	let closing = wait_for_closing(server_state).boxed();
+	let mut signals = handler.take_until(closing);
+	while let Some(()) = signals.next().await
+	{
+		info!("Handing off to a new server process...");
+		match handoff::hand_off(binding.registration()).await
+		{
+			Ok(()) =>
+			{
+				info!("Handed off.");
+				let _ = handed_off.send(()).await;
+				break;
+			}
+			Err(error) => error!("Failed to hand off: {}", error),
+		}
+	}
+}
+
 async fn wait_for_close(
 	chat_canary: mpsc::Receiver<()>,
 	client_canary: mpsc::Receiver<()>,
+	handed_off: mpsc::Receiver<()>,
 	server_state: watch::Sender<State>,
 )
 {
@@ -344,8 +932,10 @@ async fn wait_for_close(
 
 	if let Some(handler) = handler
 	{
+		// Handing off to a new server process closes this one gracefully.
 		let chat_closed = wait_for_canary(chat_canary).boxed();
-		let mut signals = handler.take_until(chat_closed);
+		let mut signals = futures::stream::select(handler, handed_off)
+			.take_until(chat_closed);
 
 		let mut is_open = true;
 		while let Some(()) = signals.next().await